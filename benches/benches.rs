@@ -1,7 +1,9 @@
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use kvs::{AnyStream, Encoding, KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
 use rand::prelude::*;
-use sled;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -39,7 +41,7 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16,] {
+    for i in &[8, 12, 16] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let mut store = KvStore::open(temp_dir.path()).unwrap();
@@ -56,7 +58,7 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![8, 12, 16, ] {
+    for i in &[8, 12, 16] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
@@ -73,5 +75,31 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+fn encoding_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap());
+    thread::spawn(move || server.run("127.0.0.1:4100").unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut group = c.benchmark_group("encoding_bench");
+    group.throughput(Throughput::Elements(1 << 8));
+    for encoding in [Encoding::Json, Encoding::Binary] {
+        let stream = TcpStream::connect("127.0.0.1:4100").unwrap();
+        let mut client =
+            KvsClient::from_stream_with_encoding(AnyStream::Tcp(stream), encoding).unwrap();
+        group.bench_function(format!("{:?}", encoding).to_lowercase(), |b| {
+            b.iter(|| {
+                for i in 0..(1 << 8) {
+                    client
+                        .set(format!("key{}", i), "value".to_string())
+                        .unwrap();
+                    client.get(format!("key{}", i)).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, get_bench, encoding_bench);
 criterion_main!(benches);
@@ -0,0 +1,168 @@
+//! Access control lists on key prefixes and globs.
+//!
+//! The ACL file is TOML. Every rule allows some operations to a user (or to
+//! everyone with `"*"`) on the keys matching a glob, anything else is denied:
+//! ```toml
+//! [[rules]]
+//! user = "alice"
+//! ops = ["get", "set", "remove", "scan"]
+//! keys = "team-a/*"
+//!
+//! [[rules]]
+//! user = "*"
+//! ops = ["get"]
+//! keys = "public/*"
+//! ```
+//! Globs understand `*` (any string) and `?` (any character). For `publish`
+//! and `subscribe`, they match the names of the pub/sub channels.
+
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use crate::{transport::Request, Result};
+
+/// Identity of connections which didn't authenticate.
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Operation checked by the ACL.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    /// read one key
+    Get,
+    /// write one key
+    Set,
+    /// remove one key
+    Remove,
+    /// list the keys under a prefix
+    Scan,
+    /// administrative requests, which are not tied to a key
+    Admin,
+    /// publish on a channel, whose name the glob matches instead of a key
+    Publish,
+    /// subscribe to a channel, whose name the glob matches instead of a key
+    Subscribe,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    user: String,
+    ops: Vec<Op>,
+    #[serde(default = "match_all")]
+    keys: String,
+}
+
+fn match_all() -> String {
+    "*".to_owned()
+}
+
+#[derive(Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// Loaded ACL rules.
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Load the ACL file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file: AclFile = toml::from_str(&fs::read_to_string(path)?)?;
+        Ok(Self { rules: file.rules })
+    }
+
+    /// Whether `user` may run `op` on `key`.
+    pub fn allows(&self, user: &str, op: Op, key: &str) -> bool {
+        self.rules.iter().any(|rule| {
+            (rule.user == "*" || rule.user == user)
+                && rule.ops.contains(&op)
+                && glob_match(rule.keys.as_bytes(), key.as_bytes())
+        })
+    }
+
+    /// Whether `user` may run `op` on at least one key.
+    pub fn allows_any(&self, user: &str, op: Op) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| (rule.user == "*" || rule.user == user) && rule.ops.contains(&op))
+    }
+
+    /// The operation `request` runs, and whether `user` may run it on every
+    /// key it touches, or `None` for the requests checked on their own.
+    pub(crate) fn check(&self, user: &str, request: &Request) -> Option<(Op, bool)> {
+        let checked = match request {
+            Request::Get { key } => (Op::Get, self.allows(user, Op::Get, key)),
+            Request::Set { key, .. } => (Op::Set, self.allows(user, Op::Set, key)),
+            Request::Remove { key } => (Op::Remove, self.allows(user, Op::Remove, key)),
+            Request::Scan { .. } | Request::ScanPage { .. } => {
+                (Op::Scan, self.allows_any(user, Op::Scan))
+            }
+            Request::MGet { keys } => (
+                Op::Get,
+                keys.iter().all(|key| self.allows(user, Op::Get, key)),
+            ),
+            Request::MSet { pairs } => (
+                Op::Set,
+                pairs.iter().all(|(key, _)| self.allows(user, Op::Set, key)),
+            ),
+            Request::CompareAndSet { key, .. } => (
+                Op::Set,
+                self.allows(user, Op::Get, key) && self.allows(user, Op::Set, key),
+            ),
+            Request::ReplicationStatus
+            | Request::Raft(_)
+            | Request::ClusterStatus
+            | Request::AddMember { .. }
+            | Request::RemoveMember { .. }
+            | Request::ProxyStats
+            | Request::Backup { .. } => (Op::Admin, self.allows_any(user, Op::Admin)),
+            Request::Publish { channel, .. } => {
+                (Op::Publish, self.allows(user, Op::Publish, channel))
+            }
+            Request::Auth { .. }
+            | Request::Hello { .. }
+            | Request::Ping
+            | Request::Replicate { .. }
+            | Request::Watch { .. }
+            | Request::Subscribe { .. }
+            | Request::PSubscribe { .. }
+            | Request::Cdc { .. } => return None,
+        };
+        Some(checked)
+    }
+}
+
+/// Whether `text` matches the glob `pattern`.
+///
+/// It goes through both once, going back only to the last `*` on a mismatch,
+/// so that long keys and patterns with many stars are cheap.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` in `pattern`, and of the text it stops before
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // let the last `*` take one more byte
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,147 @@
+//! Authentication of client connections.
+//!
+//! Credentials are checked against a TOML file which only stores salted hashes:
+//! ```toml
+//! [users.alice]
+//! password = "sha256$10000$<salt>$<hash>"
+//!
+//! [[tokens]]
+//! name = "ci-bot"
+//! hash = "sha256$10000$<salt>$<hash>"
+//! ```
+//! The hashes are produced by `hash_secret` (or `kvs-server --hash-secret`).
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt, fmt::Write, fs, path::Path};
+use uuid::Uuid;
+
+use crate::Result;
+
+const HASH_ITERATIONS: u32 = 10_000;
+
+/// What a client presents to authenticate itself.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Opaque bearer token
+    Token(String),
+    /// User name and password
+    Password {
+        /// user name
+        user: String,
+        /// password in clear text, hashed by the server
+        password: String,
+    },
+}
+
+// NOTE: requests are logged with `{:?}`, never print the secrets.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(***)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {user:?} }}"),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct CredentialFile {
+    #[serde(default)]
+    users: HashMap<String, UserEntry>,
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+struct UserEntry {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct TokenEntry {
+    name: String,
+    hash: String,
+}
+
+/// Checks credentials against a hashed credential file.
+pub struct Authenticator {
+    users: HashMap<String, String>,
+    tokens: Vec<(String, String)>,
+}
+
+impl Authenticator {
+    /// Load the credential file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file: CredentialFile = toml::from_str(&fs::read_to_string(path)?)?;
+        Ok(Self {
+            users: file
+                .users
+                .into_iter()
+                .map(|(user, entry)| (user, entry.password))
+                .collect(),
+            tokens: file
+                .tokens
+                .into_iter()
+                .map(|entry| (entry.name, entry.hash))
+                .collect(),
+        })
+    }
+
+    /// Return the identity behind `credentials`, or `None` if they are wrong.
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<String> {
+        match credentials {
+            Credentials::Password { user, password } => self
+                .users
+                .get(user)
+                .filter(|hash| verify_secret(password, hash))
+                .map(|_| user.to_owned()),
+            Credentials::Token(token) => self
+                .tokens
+                .iter()
+                .find(|(_, hash)| verify_secret(token, hash))
+                .map(|(name, _)| name.to_owned()),
+        }
+    }
+}
+
+/// Hash `secret` with a random salt, in the format of the credential file.
+pub fn hash_secret(secret: &str) -> String {
+    let salt = Uuid::new_v4().simple().to_string();
+    let hash = iterate_hash(&salt, secret, HASH_ITERATIONS);
+    format!("sha256${HASH_ITERATIONS}${salt}${hash}")
+}
+
+fn verify_secret(secret: &str, hashed: &str) -> bool {
+    let parts: Vec<&str> = hashed.split('$').collect();
+    let ["sha256", iterations, salt, expected] = parts[..] else {
+        return false;
+    };
+    let Ok(iterations) = iterations.parse() else {
+        return false;
+    };
+    let actual = iterate_hash(salt, secret, iterations);
+    // compare in constant time
+    actual.len() == expected.len()
+        && actual
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn iterate_hash(salt: &str, secret: &str, iterations: u32) -> String {
+    let mut digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(secret)
+        .finalize();
+    for _ in 1..iterations {
+        digest = Sha256::new()
+            .chain_update(digest)
+            .chain_update(secret)
+            .finalize();
+    }
+    digest.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
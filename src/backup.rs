@@ -0,0 +1,197 @@
+//! Backups of a store.
+//!
+//! An engine freezes a `Snapshot` of its data while it is locked, which is
+//! cheap, and the snapshot is written out afterwards, while the engine takes
+//! writes again. A backup is a directory holding the data files of the
+//! engine, or a tarball of it when its path ends with `.tar`. Restoring one
+//! copies those files into the data directory of a stopped store.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{KvsError, Result};
+
+/// Frozen copy of the data of an engine, taken by `KvsEngine::snapshot`.
+pub trait Snapshot: Send {
+    /// Writes the data files of the snapshot into the directory `dir`.
+    fn write_to(self: Box<Self>, dir: &Path) -> Result<()>;
+}
+
+const BLOCK: usize = 512;
+
+/// Write `snapshot` to `path`, a new directory or a new `.tar` file.
+pub fn write_backup(snapshot: Box<dyn Snapshot>, path: &Path) -> Result<()> {
+    if !is_tarball(path) {
+        if fs::read_dir(path).map_or(false, |mut entries| entries.next().is_some()) {
+            return Err(not_empty(path));
+        }
+        return snapshot.write_to(path);
+    }
+    if path.exists() {
+        return Err(not_empty(path));
+    }
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".partial");
+    let staging = PathBuf::from(staging);
+    let result = snapshot
+        .write_to(&staging)
+        .and_then(|()| pack(&staging, path));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    if result.is_err() && path.exists() {
+        fs::remove_file(path)?;
+    }
+    result
+}
+
+/// Copy the data files of the backup at `backup` into `dir`, without
+/// replacing any file of `dir`.
+pub fn restore_backup(backup: &Path, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if is_tarball(backup) {
+        unpack(backup, dir)
+    } else {
+        copy_dir(backup, dir)
+    }
+}
+
+fn is_tarball(path: &Path) -> bool {
+    path.extension() == Some("tar".as_ref())
+}
+
+fn not_empty(path: &Path) -> anyhow::Error {
+    KvsError::StringError(format!(
+        "Backup destination {} is not empty",
+        path.display()
+    ))
+    .into()
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            create_new(&target)?;
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn create_new(path: &Path) -> Result<File> {
+    File::options()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                KvsError::StringError(format!("{} already exists", path.display())).into()
+            }
+            _ => e.into(),
+        })
+}
+
+/// Write the files under `dir` into the ustar archive `tar`.
+fn pack(dir: &Path, tar: &Path) -> Result<()> {
+    let mut out = io::BufWriter::new(create_new(tar)?);
+    pack_dir(dir, Path::new(""), &mut out)?;
+    out.write_all(&[0; 2 * BLOCK])?;
+    out.into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .sync_all()?;
+    Ok(())
+}
+
+fn pack_dir(dir: &Path, prefix: &Path, out: &mut impl Write) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            out.write_all(&header(&name, 0, b'5')?)?;
+            pack_dir(&entry.path(), &name, out)?;
+        } else {
+            let len = entry.metadata()?.len();
+            out.write_all(&header(&name, len, b'0')?)?;
+            io::copy(&mut File::open(entry.path())?, out)?;
+            out.write_all(&[0; BLOCK][..padding(len)])?;
+        }
+    }
+    Ok(())
+}
+
+fn header(name: &Path, len: u64, kind: u8) -> Result<[u8; BLOCK]> {
+    let name = name
+        .to_str()
+        .filter(|name| name.len() < 100)
+        .ok_or_else(|| KvsError::StringError(format!("Cannot archive {}", name.display())))?;
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let mode: &[u8] = if kind == b'5' {
+        b"0000755\0"
+    } else {
+        b"0000644\0"
+    };
+    header[100..108].copy_from_slice(mode);
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{len:011o}\0").as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = kind;
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    Ok(header)
+}
+
+/// Extract the ustar archive `tar` into `dir`.
+fn unpack(tar: &Path, dir: &Path) -> Result<()> {
+    let invalid = || KvsError::StringError(format!("Invalid backup archive {}", tar.display()));
+    let mut input = io::BufReader::new(File::open(tar)?);
+    let mut header = [0; BLOCK];
+    loop {
+        input.read_exact(&mut header)?;
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let bytes = &header[range];
+            let end = bytes
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(bytes.len());
+            std::str::from_utf8(&bytes[..end]).map_err(|_| invalid())
+        };
+        let name = PathBuf::from(field(0..100)?);
+        if !name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(invalid().into());
+        }
+        let len = u64::from_str_radix(field(124..136)?.trim(), 8).map_err(|_| invalid())?;
+        let target = dir.join(&name);
+        match header[156] {
+            b'5' => fs::create_dir_all(&target)?,
+            b'0' | 0 => {
+                let mut file = create_new(&target)?;
+                io::copy(&mut input.by_ref().take(len), &mut file)?;
+                input.read_exact(&mut [0; BLOCK][..padding(len)])?;
+            }
+            _ => return Err(invalid().into()),
+        }
+    }
+}
+
+/// Bytes after `len` bytes of data up to the end of their last block.
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
@@ -1,8 +1,16 @@
-use std::{ env::current_dir, net::SocketAddr};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result};
-use log::{info, LevelFilter};
+use kvs::{
+    export_pairs, import_pairs, Addr, Change, Credentials, DumpFormat, Encoding, KvsClient,
+    KvsClientBuilder, MapSource, Result, ShardedClient, TlsOptions, SCAN_PAGE_SIZE,
+};
+use log::{info, warn, LevelFilter};
+use serde_json::json;
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
@@ -14,6 +22,82 @@ use log::{info, LevelFilter};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// PEM CA certificates; connect over TLS and verify the server with them.
+    #[arg(long, global = true, value_name = "FILE")]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate for mutual TLS.
+    #[arg(long, global = true, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long, global = true, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name expected in the server certificate. [default: host of `--addr`]
+    #[arg(long, global = true, value_name = "NAME")]
+    tls_server_name: Option<String>,
+
+    /// User name to authenticate as.
+    #[arg(long, global = true, value_name = "USER", requires = "password_file")]
+    user: Option<String>,
+
+    /// File holding the password of `--user`.
+    #[arg(long, global = true, value_name = "FILE", requires = "user")]
+    password_file: Option<PathBuf>,
+
+    /// File holding a token to authenticate with.
+    #[arg(long, global = true, value_name = "FILE", conflicts_with = "user")]
+    token_file: Option<PathBuf>,
+
+    /// Encoding of the frames, `json` or `binary`.
+    #[arg(long, global = true, value_name = "ENCODING", default_value = "binary")]
+    encoding: Encoding,
+
+    /// Seconds to wait for the server to connect or answer. [default: no limit]
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+}
+
+impl Cli {
+    /// Connect to `addr`, over TLS when a CA is given, and authenticate.
+    fn connect(&self, addr: &Addr) -> Result<KvsClient> {
+        self.builder(addr)?.build()
+    }
+
+    /// Client settings of the global options, for the server at `addr`.
+    fn builder(&self, addr: &Addr) -> Result<KvsClientBuilder> {
+        let mut builder = KvsClient::builder(addr).encoding(self.encoding);
+        if let Some(ca) = &self.tls_ca {
+            builder = builder.tls(TlsOptions {
+                cert: self.tls_cert.clone(),
+                key: self.tls_key.clone(),
+                ca: Some(ca.clone()),
+                server_name: self.tls_server_name.clone(),
+            });
+        }
+        match (&self.user, &self.password_file, &self.token_file) {
+            (Some(user), Some(password_file), _) => {
+                builder = builder.credentials(Credentials::Password {
+                    user: user.to_owned(),
+                    password: read_secret(password_file)?,
+                })
+            }
+            (_, _, Some(token_file)) => {
+                builder = builder.credentials(Credentials::Token(read_secret(token_file)?))
+            }
+            _ => {}
+        }
+        if let Some(seconds) = self.timeout {
+            let timeout = Duration::from_secs(seconds);
+            builder = builder
+                .connect_timeout(timeout)
+                .read_timeout(timeout)
+                .write_timeout(timeout);
+        }
+        Ok(builder)
+    }
 }
 
 #[derive(Subcommand)]
@@ -28,24 +112,24 @@ enum Commands {
         v: String,
         /// Start the server and begin listening for incoming connections.
         #[arg(
-            long, 
+            long,
             value_name = "Server Address",
             default_value = DEFAULT_LISTENING_ADDRESS,
         )]
-        addr: SocketAddr,
+        addr: Addr,
     },
-    /// Get the string value of a given string key
+    /// Get the string value of one or more string keys
     Get {
-        /// key
-        #[arg(value_name = "KEY")]
-        k: String,
+        /// keys
+        #[arg(value_name = "KEY", required = true)]
+        k: Vec<String>,
         /// Start the server and begin listening for incoming connections.
         #[arg(
-            long, 
+            long,
             value_name = "Server Address",
             default_value = DEFAULT_LISTENING_ADDRESS,
         )]
-        addr: SocketAddr,
+        addr: Addr,
     },
     /// Remove a given key
     Rm {
@@ -54,11 +138,208 @@ enum Commands {
         k: String,
         /// Start the server and begin listening for incoming connections.
         #[arg(
-            long, 
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// List the key-value pairs under a key prefix
+    Scan {
+        /// key prefix
+        #[arg(value_name = "PREFIX", default_value = "")]
+        prefix: String,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Print the changes of the keys under a prefix as they happen
+    Watch {
+        /// key, or prefix of the keys
+        #[arg(value_name = "KEY_OR_PREFIX", default_value = "")]
+        prefix: String,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
             value_name = "Server Address",
             default_value = DEFAULT_LISTENING_ADDRESS,
         )]
-        addr: SocketAddr,
+        addr: Addr,
+    },
+    /// Print the changes logged by the server as JSON lines, then follow it
+    Cdc {
+        /// Start after this sequence number, the last one already seen.
+        #[arg(long, value_name = "SEQ", default_value_t = 0)]
+        from: u64,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Send a message to the subscribers of a channel
+    Publish {
+        /// channel
+        #[arg(value_name = "CHANNEL")]
+        channel: String,
+        /// message
+        #[arg(value_name = "MESSAGE")]
+        message: String,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Print the messages published on channels as they arrive
+    Subscribe {
+        /// channels, or globs of channel names with `--pattern`
+        #[arg(value_name = "CHANNEL", required = true)]
+        channels: Vec<String>,
+        /// Match the channel names against globs with `*` and `?`.
+        #[arg(long)]
+        pattern: bool,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Show or change the members of a cluster
+    Cluster {
+        #[command(subcommand)]
+        action: ClusterAction,
+    },
+    /// Move the keys of a sharded store to other servers, or create its shard
+    /// map when there is none
+    Reshard {
+        /// addresses of the servers, comma separated
+        #[arg(value_name = "NODES", value_delimiter = ',', num_args = 1.., required = true)]
+        nodes: Vec<String>,
+        /// shard map file
+        #[arg(long, value_name = "FILE", required_unless_present = "map_node")]
+        map: Option<PathBuf>,
+        /// server holding the shard map
+        #[arg(long, value_name = "Server Address", conflicts_with = "map")]
+        map_node: Option<Addr>,
+    },
+    /// Show the statistics of a proxy
+    ProxyStats {
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Show the replication state of a server
+    ReplicationStatus {
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Write the pairs of the server to the standard output
+    Export {
+        /// Only the keys starting with this prefix, repeat for several.
+        #[arg(long = "prefix", value_name = "PREFIX")]
+        prefixes: Vec<String>,
+        /// Dump format, `jsonl` or `csv`.
+        #[arg(long, value_name = "FORMAT", default_value = "jsonl")]
+        format: DumpFormat,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Write the pairs read from the standard input to the server
+    Import {
+        /// Only the keys starting with this prefix, repeat for several.
+        #[arg(long = "prefix", value_name = "PREFIX")]
+        prefixes: Vec<String>,
+        /// Dump format, `jsonl` or `csv`.
+        #[arg(long, value_name = "FORMAT", default_value = "jsonl")]
+        format: DumpFormat,
+        /// Pairs sent in one request.
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        batch_size: usize,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Make the server back up its data, without stopping it
+    Backup {
+        /// new directory, or new tarball ending with `.tar`, in the backup_dir of the server
+        #[arg(value_name = "PATH")]
+        path: String,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+}
+
+#[derive(Subcommand)]
+enum ClusterAction {
+    /// Show the state of a cluster node
+    Status {
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Add a node to the cluster
+    Add {
+        /// address of the node
+        #[arg(value_name = "NODE")]
+        node: String,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
+    },
+    /// Remove a node from the cluster
+    Remove {
+        /// address of the node
+        #[arg(value_name = "NODE")]
+        node: String,
+        /// Start the server and begin listening for incoming connections.
+        #[arg(
+            long,
+            value_name = "Server Address",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: Addr,
     },
 }
 
@@ -74,6 +355,12 @@ enum EngineEnum {
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 
+/// Read a secret from the first line of `path`.
+fn read_secret(path: &Path) -> Result<String> {
+    let content = fs::read_to_string(path)?;
+    Ok(content.lines().next().unwrap_or_default().to_owned())
+}
+
 fn main() -> Result<()> {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let cli = Cli::parse();
@@ -84,15 +371,191 @@ fn main() -> Result<()> {
     // let mut client = KvsClient::connect(cli.addr)?;
 
     match &cli.command {
-        Commands::Set { k, v , addr} => KvsClient::connect(addr)?.set(k.to_owned(), v.to_owned())?,
-        Commands::Get { k , addr} => {
-            if let Some(v) = KvsClient::connect(addr)?.get(k.to_owned())? {
-                println!("{}", v);
+        Commands::Set { k, v, addr } => cli.connect(addr)?.set(k.to_owned(), v.to_owned())?,
+        Commands::Get { k, addr } => {
+            let mut client = cli.connect(addr)?;
+            let values = match &k[..] {
+                [key] => vec![client.get(key.to_owned())?],
+                keys => client.mget(keys.to_vec())?,
+            };
+            for v in values {
+                if let Some(v) = v {
+                    println!("{}", v);
+                } else {
+                    println!("Key not found");
+                }
+            }
+        }
+        Commands::Rm { k, addr } => cli.connect(addr)?.remove(k.to_owned())?,
+        Commands::Scan { prefix, addr } => {
+            for (k, v) in cli.connect(addr)?.scan(prefix.to_owned())? {
+                println!("{}\t{}", k, v);
+            }
+        }
+        Commands::Watch { prefix, addr } => {
+            for event in cli.connect(addr)?.watch(prefix.to_owned())? {
+                let event = event?;
+                match event.change {
+                    Change::Set { key, value } => {
+                        println!("{}\tset\t{}\t{}", event.seq, key, value)
+                    }
+                    Change::Remove { key } => println!("{}\tremove\t{}", event.seq, key),
+                }
+            }
+        }
+        Commands::Cdc { from, addr } => {
+            for event in cli.connect(addr)?.cdc(*from)? {
+                let event = event?;
+                let line = match event.change {
+                    Change::Set { key, value } => {
+                        json!({ "seq": event.seq, "op": "set", "key": key, "value": value })
+                    }
+                    Change::Remove { key } => {
+                        json!({ "seq": event.seq, "op": "remove", "key": key })
+                    }
+                };
+                println!("{}", line);
+            }
+        }
+        Commands::Publish {
+            channel,
+            message,
+            addr,
+        } => {
+            let receivers = cli
+                .connect(addr)?
+                .publish(channel.to_owned(), message.to_owned())?;
+            println!("{}", receivers);
+        }
+        Commands::Subscribe {
+            channels,
+            pattern,
+            addr,
+        } => {
+            let client = cli.connect(addr)?;
+            let messages = if *pattern {
+                client.psubscribe(channels.to_vec())?
             } else {
-                println!("Key not found");
+                client.subscribe(channels.to_vec())?
+            };
+            for message in messages {
+                let message = message?;
+                if message.dropped > 0 {
+                    warn!("{} messages dropped", message.dropped);
+                }
+                println!("{}\t{}", message.channel, message.message);
             }
         }
-        Commands::Rm { k, addr } => KvsClient::connect(addr)?.remove(k.to_owned())?,
+        Commands::Cluster { action } => match action {
+            ClusterAction::Status { addr } => {
+                let status = cli.connect(addr)?.cluster_status()?;
+                println!("id\t{}", status.id);
+                println!("role\t{:?}", status.role);
+                println!("term\t{}", status.term);
+                if let Some(leader) = &status.leader {
+                    println!("leader\t{}", leader);
+                }
+                println!("members\t{}", status.members.join(","));
+                println!("last_index\t{}", status.last_index);
+                println!("commit\t{}", status.commit);
+                println!("applied\t{}", status.applied);
+                println!("snapshot_index\t{}", status.snapshot_index);
+            }
+            ClusterAction::Add { node, addr } => cli.connect(addr)?.add_member(node.to_owned())?,
+            ClusterAction::Remove { node, addr } => {
+                cli.connect(addr)?.remove_member(node.to_owned())?
+            }
+        },
+        Commands::Reshard {
+            nodes,
+            map,
+            map_node,
+        } => {
+            let (source, addr) = match (map, map_node) {
+                (_, Some(addr)) => (MapSource::Node(addr.clone()), addr.clone()),
+                (Some(path), None) => (
+                    MapSource::File(path.clone()),
+                    DEFAULT_LISTENING_ADDRESS.parse()?,
+                ),
+                (None, None) => unreachable!("clap requires a map"),
+            };
+            let mut client =
+                ShardedClient::open_or_create(source, nodes.clone(), Some(cli.builder(&addr)?))?;
+            if client.map().nodes() == &nodes[..] && client.map().previous().is_none() {
+                println!("version\t{}", client.map().version());
+            } else {
+                let moved = client.reshard(nodes.clone())?;
+                println!("version\t{}", client.map().version());
+                println!("moved\t{}", moved);
+            }
+        }
+        Commands::ProxyStats { addr } => {
+            let stats = cli.connect(addr)?.proxy_stats()?;
+            println!("requests\t{}", stats.requests);
+            println!("errors\t{}", stats.errors);
+            println!("connections\t{}", stats.connections);
+            for backend in &stats.backends {
+                println!(
+                    "backend\t{}\tshard={}\trole={}\thealthy={}\trequests={}\terrors={}\tconnections={}",
+                    backend.addr,
+                    backend.shard,
+                    if backend.replica { "replica" } else { "primary" },
+                    backend.healthy,
+                    backend.requests,
+                    backend.errors,
+                    backend.connections
+                );
+            }
+        }
+        Commands::ReplicationStatus { addr } => {
+            let status = cli.connect(addr)?.replication_status()?;
+            match &status.leader {
+                Some(leader) => {
+                    println!("role\treplica");
+                    println!("leader\t{}", leader);
+                    println!("connected\t{}", status.connected);
+                    println!("applied\t{}", status.applied);
+                    println!("lag\t{}", status.lag);
+                    if let Some(ms) = status.last_contact_ms {
+                        println!("last_contact_ms\t{}", ms);
+                    }
+                }
+                None => println!("role\tleader"),
+            }
+            println!("seq\t{}", status.seq);
+            println!("replicas\t{}", status.replicas);
+        }
+        Commands::Backup { path, addr } => cli.connect(addr)?.backup(path.to_owned())?,
+        Commands::Export {
+            prefixes,
+            format,
+            addr,
+        } => {
+            let mut client = cli.connect(addr)?;
+            let count = export_pairs(
+                prefixes,
+                |prefix, start_after| client.scan_page(prefix, start_after, SCAN_PAGE_SIZE),
+                *format,
+                io::stdout().lock(),
+            )?;
+            info!("Exported {} pairs", count);
+        }
+        Commands::Import {
+            prefixes,
+            format,
+            batch_size,
+            addr,
+        } => {
+            let mut client = cli.connect(addr)?;
+            let count = import_pairs(
+                io::stdin().lock(),
+                *format,
+                prefixes,
+                *batch_size,
+                |pairs| client.mset(pairs),
+            )?;
+            info!("Imported {} pairs", count);
+        }
     };
     Ok(())
 }
@@ -0,0 +1,127 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use kvs::{
+    Addr, Credentials, KvsClient, KvsProxy, PoolOptions, ProxyOptions, Result, Shard, TlsOptions,
+};
+use log::{info, LevelFilter};
+
+#[derive(Parser)]
+#[command(name = "kvs-proxy")]
+#[command(version = env!("CARGO_PKG_VERSION"))]
+#[command(
+    about = "Route the clients of a key-value store to its shards",
+    long_about = None
+)]
+struct Cli {
+    /// Listen for clients on this address, either `host:port` or
+    /// `unix:/path`. [default: 127.0.0.1:4100]
+    #[arg(long, value_name = "Proxy Address")]
+    addr: Option<Addr>,
+
+    /// Servers of one shard: its primary, then its replicas, comma separated.
+    /// Repeat for each shard.
+    #[arg(long = "shard", value_name = "PRIMARY[,REPLICA...]", required = true)]
+    shards: Vec<String>,
+
+    /// Send the reads to the replicas of the shards, when they are healthy.
+    #[arg(long)]
+    read_from_replicas: bool,
+
+    /// Milliseconds between two health checks of the servers.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    health_check_interval: u64,
+
+    /// Most connections open to each server.
+    #[arg(long, value_name = "N", default_value_t = 16)]
+    max_connections: usize,
+
+    /// Hashed credential file; clients must authenticate when it is given.
+    #[arg(long, value_name = "FILE")]
+    credentials_file: Option<PathBuf>,
+
+    /// ACL file; every operation is allowed without it.
+    #[arg(long, value_name = "FILE")]
+    acl_file: Option<PathBuf>,
+
+    /// File holding the token to authenticate to the servers with. It needs
+    /// `--credentials-file`, so that anonymous clients cannot use it.
+    #[arg(long, value_name = "FILE", requires = "credentials_file")]
+    token_file: Option<PathBuf>,
+
+    /// PEM certificate chain; serve over TLS when given with `--tls-key`.
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates; require client certificates signed by them (mTLS).
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+}
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4100";
+
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let cli = Cli::parse();
+
+    let addr: Addr = match cli.addr {
+        Some(addr) => addr,
+        None => DEFAULT_LISTENING_ADDRESS.parse()?,
+    };
+    let shards = cli
+        .shards
+        .iter()
+        .map(|servers| {
+            let mut addrs = servers
+                .split(',')
+                .map(str::parse)
+                .collect::<std::result::Result<Vec<Addr>, _>>()?;
+            let primary = addrs.remove(0);
+            Ok(Shard {
+                primary,
+                replicas: addrs,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let options = ProxyOptions {
+        read_from_replicas: cli.read_from_replicas,
+        health_check_interval: Duration::from_millis(cli.health_check_interval),
+        pool: PoolOptions {
+            max_connections: cli.max_connections,
+            ..PoolOptions::default()
+        },
+        credentials_file: cli.credentials_file,
+        acl_file: cli.acl_file,
+    };
+    let template = match &cli.token_file {
+        Some(path) => {
+            let token = fs::read_to_string(path)?;
+            Some(KvsClient::builder(&addr).credentials(Credentials::Token(token.trim().to_owned())))
+        }
+        None => None,
+    };
+
+    info!("kvs-proxy {}", env!("CARGO_PKG_VERSION"));
+    for (i, shard) in shards.iter().enumerate() {
+        info!("Shard {}: {} {:?}", i, shard.primary, shard.replicas);
+    }
+    let mut proxy = KvsProxy::with_template(shards, options, template)?;
+    if cli.tls_cert.is_some() {
+        info!(
+            "TLS enabled, client certificates required: {}",
+            cli.tls_ca.is_some()
+        );
+        let tls = TlsOptions {
+            cert: cli.tls_cert,
+            key: cli.tls_key,
+            ca: cli.tls_ca,
+            ..TlsOptions::default()
+        };
+        proxy = proxy.with_tls(tls.server_config()?);
+    }
+    proxy.run_addr(&addr)
+}
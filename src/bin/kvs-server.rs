@@ -1,8 +1,13 @@
-use std::{ env::current_dir, net::SocketAddr, process::exit};
+use std::{env::current_dir, fs, io, path::PathBuf, process::exit};
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use log::{info, error, LevelFilter};
+use clap::{Parser, ValueEnum};
+use kvs::{
+    hash_secret, Addr, ClusterConfig, Credentials, KvStore, KvsClient, KvsClientBuilder, KvsEngine,
+    KvsServer, Result, ServerConfig, ServerHandle, SledKvsEngine, TlsOptions,
+};
+use log::{error, info, warn, LevelFilter};
+#[cfg(unix)]
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
@@ -12,22 +17,70 @@ use log::{info, error, LevelFilter};
     long_about = None
 )]
 struct Cli {
-    /// Start the server and begin listening for incoming connections.
-    #[arg(
-        long, 
-        value_name = "Server Address",
-        default_value = DEFAULT_LISTENING_ADDRESS,
-    )]
-    addr: SocketAddr,
+    /// Start the server and begin listening for incoming connections,
+    /// either `host:port` or `unix:/path`. [default: 127.0.0.1:4000]
+    #[arg(long, value_name = "Server Address")]
+    addr: Option<Addr>,
+
+    /// Also serve Redis (RESP) clients on this address.
+    #[arg(long, value_name = "Address")]
+    resp_addr: Option<Addr>,
+
+    /// Also serve the HTTP/JSON gateway on this address.
+    #[arg(long, value_name = "Address")]
+    http_addr: Option<Addr>,
 
-    /// The engine that kvs used.
+    /// Also serve memcached clients (text protocol) on this address.
+    #[arg(long, value_name = "Address")]
+    memcache_addr: Option<Addr>,
+
+    /// Run as a read-only replica of the leader at this address.
+    #[arg(long, value_name = "Address")]
+    replica_of: Option<Addr>,
+
+    /// File holding the token to authenticate to the leader with.
+    #[arg(long, value_name = "FILE")]
+    replica_token_file: Option<PathBuf>,
+
+    /// Run as a node of a Raft cluster whose first members are at these
+    /// addresses, comma separated. A node not in the list waits to be added.
     #[arg(
         long,
-        value_name = "Engine",
-        value_enum,
-        default_value_t = EngineEnum::Kvs,
+        value_name = "Addresses",
+        value_delimiter = ',',
+        num_args = 1..,
+        conflicts_with = "replica_of"
     )]
-    engine: EngineEnum,
+    cluster: Option<Vec<String>>,
+
+    /// File holding the token to authenticate to the other cluster nodes with.
+    #[arg(long, value_name = "FILE")]
+    cluster_token_file: Option<PathBuf>,
+
+    /// The engine that kvs used. [default: kvs]
+    #[arg(long, value_name = "Engine", value_enum)]
+    engine: Option<EngineEnum>,
+
+    /// Configuration file, reloaded on SIGHUP on Unix.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// PEM certificate chain; serve over TLS when given with `--tls-key`.
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates; require client certificates signed by them (mTLS).
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// Read a password or token from stdin, print its hash for the
+    /// credential file and exit.
+    #[arg(long)]
+    hash_secret: bool,
 }
 
 #[derive(ValueEnum, Clone, Default, Debug, PartialEq, Eq)]
@@ -40,47 +93,249 @@ enum EngineEnum {
     Sled,
 }
 
-impl ToString for EngineEnum {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for EngineEnum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            EngineEnum::Kvs => "kvs".to_owned(), 
-            EngineEnum::Sled => "sled".to_owned(), 
+            EngineEnum::Kvs => write!(f, "kvs"),
+            EngineEnum::Sled => write!(f, "sled"),
         }
     }
 }
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 
+/// Addresses to listen on, one per protocol.
+struct Addrs {
+    native: Addr,
+    resp: Option<Addr>,
+    http: Option<Addr>,
+    memcache: Option<Addr>,
+    /// leader to replicate
+    leader: Option<Addr>,
+}
+
+/// Other servers this one works with.
+struct Peers {
+    /// leader to replicate, if a replica
+    leader: Option<KvsClientBuilder>,
+    /// settings of the cluster node, if one
+    cluster: Option<ClusterConfig>,
+}
+
 fn main() -> Result<()> {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
+    // Let everything through env_logger and filter with the global max level,
+    // so the level can be changed on reload.
+    env_logger::builder()
+        .filter_level(LevelFilter::Trace)
+        .init();
+    log::set_max_level(LevelFilter::Info);
     let cli = Cli::parse();
+    if cli.hash_secret {
+        let mut secret = String::new();
+        io::stdin().read_line(&mut secret)?;
+        println!("{}", hash_secret(secret.trim_end_matches(['\r', '\n'])));
+        return Ok(());
+    }
+
+    let config = match &cli.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    log::set_max_level(config.runtime.log_level);
+
+    let addr: Addr = match cli.addr {
+        Some(addr) => addr,
+        None => config
+            .addr
+            .as_deref()
+            .unwrap_or(DEFAULT_LISTENING_ADDRESS)
+            .parse()?,
+    };
+    let addrs = Addrs {
+        native: addr,
+        resp: match cli.resp_addr {
+            Some(addr) => Some(addr),
+            None => config.resp_addr.as_deref().map(str::parse).transpose()?,
+        },
+        http: match cli.http_addr {
+            Some(addr) => Some(addr),
+            None => config.http_addr.as_deref().map(str::parse).transpose()?,
+        },
+        memcache: match cli.memcache_addr {
+            Some(addr) => Some(addr),
+            None => config
+                .memcache_addr
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+        },
+        leader: match cli.replica_of {
+            Some(addr) => Some(addr),
+            None => config.replica_of.as_deref().map(str::parse).transpose()?,
+        },
+    };
+    let engine = match (&cli.engine, &config.engine) {
+        (Some(engine), _) => engine.clone(),
+        (None, Some(name)) => EngineEnum::from_str(name, true)
+            .map_err(|e| kvs::KvsError::StringError(format!("Invalid engine in config: {e}")))?,
+        (None, None) => EngineEnum::default(),
+    };
+    let data_dir = match &config.data_dir {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Listening on:  {}", cli.addr);
-    info!("Storage engine:  {:?}", cli.engine);
+    info!("Listening on:  {}", addrs.native);
+    info!("Storage engine:  {:?}", engine);
 
-    let full_path = current_dir()?.join(cli.engine.to_string());
-    let other_engine = if cli.engine == EngineEnum::Kvs {
+    let full_path = data_dir.join(engine.to_string());
+    let other_engine = if engine == EngineEnum::Kvs {
         EngineEnum::Sled
     } else {
         EngineEnum::Kvs
     };
-    let other_path = current_dir()?.join(other_engine.to_string());
+    let other_path = data_dir.join(other_engine.to_string());
     if !full_path.exists() && other_path.exists() {
         // error
         error!("Wrong engine!");
         exit(1);
     }
 
-    match cli.engine {
+    let token_file = |cli: &Option<PathBuf>, config: &Option<PathBuf>| {
+        cli.as_ref().or(config.as_ref()).map(read_token).transpose()
+    };
+    let mut peers = Peers {
+        leader: None,
+        cluster: None,
+    };
+    if let Some(leader) = &addrs.leader {
+        let mut builder = KvsClient::builder(leader);
+        if let Some(credentials) = token_file(&cli.replica_token_file, &config.replica_token_file)?
+        {
+            builder = builder.credentials(credentials);
+        }
+        peers.leader = Some(builder);
+    }
+    if let Some(members) = cli.cluster.as_ref().or(config.cluster.as_ref()) {
+        if peers.leader.is_some() {
+            error!("A cluster node cannot be a replica");
+            exit(1);
+        }
+        let mut cluster = ClusterConfig::new(
+            addrs.native.to_string(),
+            members.clone(),
+            data_dir.join("raft"),
+        );
+        cluster.credentials = token_file(&cli.cluster_token_file, &config.cluster_token_file)?;
+        peers.cluster = Some(cluster);
+    }
+
+    let tls = TlsOptions {
+        cert: cli.tls_cert.clone(),
+        key: cli.tls_key.clone(),
+        ca: cli.tls_ca.clone(),
+        server_name: None,
+    };
+    match engine {
         EngineEnum::Kvs => {
             info!("Start kvs server");
-            KvsServer::new(KvStore::open(full_path)?).run(cli.addr)?;
-        },
+            let server = KvsServer::with_config(KvStore::open(full_path)?, config.runtime.clone())?;
+            start(server, &addrs, &tls, peers, cli.config, config)?;
+        }
         EngineEnum::Sled => {
-            KvsServer::new(SledKvsEngine::new(sled::open(full_path)?)).run(cli.addr)?;
+            let server = KvsServer::with_config(
+                SledKvsEngine::new(sled::open(full_path)?),
+                config.runtime.clone(),
+            )?;
+            start(server, &addrs, &tls, peers, cli.config, config)?;
         }
     }
 
     Ok(())
 }
+
+fn start<E: KvsEngine + Send + 'static>(
+    mut server: KvsServer<E>,
+    addrs: &Addrs,
+    tls: &TlsOptions,
+    peers: Peers,
+    config_path: Option<PathBuf>,
+    config: ServerConfig,
+) -> Result<()> {
+    if tls.cert.is_some() {
+        info!(
+            "TLS enabled, client certificates required: {}",
+            tls.ca.is_some()
+        );
+        server = server.with_tls(tls.server_config()?);
+    }
+    if let Some(addr) = &addrs.resp {
+        server = server.with_resp(addr.bind()?);
+    }
+    if let Some(addr) = &addrs.http {
+        server = server.with_http(addr.bind()?);
+    }
+    if let Some(addr) = &addrs.memcache {
+        server = server.with_memcache(addr.bind()?);
+    }
+    if let Some(leader) = peers.leader {
+        info!("Replica of {}", leader.addr());
+        server = server.replica_of(leader);
+    }
+    if let Some(cluster) = peers.cluster {
+        info!("Cluster node {}", cluster.id);
+        server = server.cluster(cluster)?;
+    }
+    watch_config(server.handle(), config_path, config)?;
+    server.run_addr(&addrs.native)
+}
+
+/// Read the token in `path` to authenticate to other servers with.
+fn read_token(path: &PathBuf) -> Result<Credentials> {
+    let token = fs::read_to_string(path)?;
+    Ok(Credentials::Token(token.trim().to_owned()))
+}
+
+/// Reload the runtime settings from `path` whenever SIGHUP arrives.
+#[cfg(unix)]
+fn watch_config<E: KvsEngine + Send + 'static>(
+    handle: ServerHandle<E>,
+    path: Option<PathBuf>,
+    mut current: ServerConfig,
+) -> Result<()> {
+    let Some(path) = path else { return Ok(()) };
+    let mut signals = Signals::new([SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Got SIGHUP, reload configuration from {}", path.display());
+            let new = match ServerConfig::load(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Reload configuration failed, keep the old one: {e}");
+                    continue;
+                }
+            };
+            for setting in current.restart_only_changes(&new) {
+                warn!("Reject change of `{setting}`: it needs a restart");
+            }
+            if let Err(e) = handle.reload(new.runtime.clone()) {
+                error!("Reload configuration failed, keep the old one: {e}");
+                continue;
+            }
+            current.runtime = new.runtime;
+            info!("Configuration reloaded");
+        }
+    });
+    Ok(())
+}
+
+/// Without SIGHUP the configuration file is only read on startup.
+#[cfg(not(unix))]
+fn watch_config<E: KvsEngine + Send + 'static>(
+    _handle: ServerHandle<E>,
+    _path: Option<PathBuf>,
+    _current: ServerConfig,
+) -> Result<()> {
+    Ok(())
+}
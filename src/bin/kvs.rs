@@ -1,5 +1,9 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvStore, KvsEngine, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{
+    export_pairs, import_pairs, migrate, restore_backup, DumpFormat, KvStore, KvsEngine, KvsError,
+    Result, SledKvsEngine, SCAN_PAGE_SIZE,
+};
+use std::{fs, io, path::Path, path::PathBuf};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
@@ -36,12 +40,111 @@ enum Commands {
         #[arg(value_name = "KEY")]
         k: String,
     },
+    /// Back up the store to a new directory, or a new tarball ending with `.tar`
+    Backup {
+        /// backup path
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+    },
+    /// Restore an empty store from a backup
+    ///
+    /// The backup is checked by opening it before anything is changed. It
+    /// goes to the current directory, or with `--data-dir` to the store of
+    /// its engine in a kvs-server data directory, `DIR/<engine>`.
+    Restore {
+        /// backup path
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+        /// Data directory of kvs-server to restore the store into.
+        #[arg(long, value_name = "DIR")]
+        data_dir: Option<PathBuf>,
+    },
+    /// Write the pairs of the store to the standard output
+    Export {
+        /// Only the keys starting with this prefix, repeat for several.
+        #[arg(long = "prefix", value_name = "PREFIX")]
+        prefixes: Vec<String>,
+        /// Dump format, `jsonl` or `csv`.
+        #[arg(long, value_name = "FORMAT", default_value = "jsonl")]
+        format: DumpFormat,
+        /// Engine of the store in the current directory.
+        #[arg(long, value_enum, default_value_t)]
+        engine: EngineEnum,
+    },
+    /// Write the pairs read from the standard input to the store
+    Import {
+        /// Only the keys starting with this prefix, repeat for several.
+        #[arg(long = "prefix", value_name = "PREFIX")]
+        prefixes: Vec<String>,
+        /// Dump format, `jsonl` or `csv`.
+        #[arg(long, value_name = "FORMAT", default_value = "jsonl")]
+        format: DumpFormat,
+        /// Pairs written at once.
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        batch_size: usize,
+        /// Engine of the store in the current directory.
+        #[arg(long, value_enum, default_value_t)]
+        engine: EngineEnum,
+    },
+    /// Copy the store of a kvs-server data directory to another engine
+    ///
+    /// The store of an engine is the subdirectory of a data directory named
+    /// after it, `kvs` or `sled`, and kvs-server picks its engine by that
+    /// name, there is no other manifest. The new store is removed if the copy
+    /// fails or its checksum differs from the source.
+    Migrate {
+        /// Engine of the source store.
+        #[arg(long, value_enum)]
+        from: EngineEnum,
+        /// Engine of the new store.
+        #[arg(long, value_enum)]
+        to: EngineEnum,
+        /// Data directory holding the source store.
+        #[arg(long, value_name = "DIR")]
+        src: PathBuf,
+        /// Data directory to create the new store in, as `DIR/<engine>`.
+        #[arg(long, value_name = "DIR")]
+        dst: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+enum EngineEnum {
+    /// kvs
+    #[default]
+    Kvs,
+
+    /// sled
+    Sled,
+}
+
+impl std::fmt::Display for EngineEnum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineEnum::Kvs => write!(f, "kvs"),
+            EngineEnum::Sled => write!(f, "sled"),
+        }
+    }
 }
 
+/// Pairs written at once by `migrate`.
+const MIGRATION_BATCH: usize = 1000;
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let mut store = KvStore::open(kvs::DEFAULT_LOG_FILE)?;
+    match &cli.command {
+        Some(Commands::Restore { path, data_dir }) => return restore(path, data_dir.as_deref()),
+        Some(Commands::Migrate { from, to, src, dst }) => {
+            return migrate_data_dir(*from, *to, src, dst)
+        }
+        _ => {}
+    }
+    let engine = match &cli.command {
+        Some(Commands::Export { engine, .. } | Commands::Import { engine, .. }) => *engine,
+        _ => EngineEnum::Kvs,
+    };
+    let mut store = open(engine, Path::new(kvs::DEFAULT_LOG_FILE))?;
 
     match &cli.command {
         Some(Commands::Set { k, v }) => store.set(k.to_owned(), v.to_owned())?,
@@ -53,7 +156,153 @@ fn main() -> Result<()> {
             }
         }
         Some(Commands::Rm { k }) => store.remove(k.to_owned())?,
+        Some(Commands::Backup { path }) => store.backup_to(path)?,
+        Some(Commands::Export {
+            prefixes, format, ..
+        }) => {
+            export_pairs(
+                prefixes,
+                |prefix, start_after| store.scan_page(prefix, start_after, SCAN_PAGE_SIZE as usize),
+                *format,
+                io::stdout().lock(),
+            )?;
+        }
+        Some(Commands::Import {
+            prefixes,
+            format,
+            batch_size,
+            ..
+        }) => {
+            import_pairs(
+                io::stdin().lock(),
+                *format,
+                prefixes,
+                *batch_size,
+                |pairs| store.mset(pairs),
+            )?;
+        }
         _ => unreachable!(),
     };
     Ok(())
 }
+
+fn open(engine: EngineEnum, dir: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(match engine {
+        EngineEnum::Kvs => Box::new(KvStore::open(dir)?),
+        EngineEnum::Sled => Box::new(SledKvsEngine::new(sled::open(dir)?)),
+    })
+}
+
+/// Copy the `from` store of the data directory `src` to a new `to` store of
+/// the data directory `dst`, laid out as `kvs-server` expects: the store of
+/// an engine is the subdirectory named after it.
+fn migrate_data_dir(from: EngineEnum, to: EngineEnum, src: &Path, dst: &Path) -> Result<()> {
+    let src_store = src.join(from.to_string());
+    let dst_store = dst.join(to.to_string());
+    if src_store == dst_store {
+        return Err(KvsError::StringError(
+            "The source and the destination are the same".to_owned(),
+        )
+        .into());
+    }
+    if !src_store.is_dir() {
+        return Err(
+            KvsError::StringError(format!("No {} store in {}", from, src.display())).into(),
+        );
+    }
+    if fs::read_dir(&dst_store).map_or(false, |mut entries| entries.next().is_some()) {
+        return Err(KvsError::StringError(format!(
+            "{} already holds a {} store",
+            dst.display(),
+            to
+        ))
+        .into());
+    }
+    let mut source = open(from, &src_store)?;
+    let new_dst = !dst.exists();
+    fs::create_dir_all(&dst_store)?;
+    let result = open(to, &dst_store)
+        .and_then(|mut target| migrate(&mut *source, &mut *target, MIGRATION_BATCH));
+    let digest = match result {
+        Ok(digest) => digest,
+        Err(e) => {
+            // leave no half copied store for kvs-server to pick up
+            fs::remove_dir_all(if new_dst { dst } else { &dst_store })?;
+            return Err(e);
+        }
+    };
+    println!("pairs\t{}", digest.pairs);
+    println!("checksum\t{}", digest.checksum);
+    Ok(())
+}
+
+/// Restore the backup at `path` into the current directory, or into the
+/// store of its engine in the data directory `data_dir`, if the store holds
+/// nothing yet.
+///
+/// The backup is first unpacked next to the store and opened, so that a
+/// broken backup leaves the store as it was.
+fn restore(path: &Path, data_dir: Option<&Path>) -> Result<()> {
+    let parent = data_dir.unwrap_or(Path::new(kvs::DEFAULT_LOG_FILE));
+    let staging = parent.join(".restore.partial");
+    let result = restore_backup(path, &staging)
+        .and_then(|()| backup_engine(&staging))
+        .and_then(|engine| {
+            open(engine, &staging)?;
+            let dir = match data_dir {
+                Some(data_dir) => data_dir.join(engine.to_string()),
+                None => parent.to_owned(),
+            };
+            fs::create_dir_all(&dir)?;
+            clear_empty_store(engine, &dir)?;
+            for entry in fs::read_dir(&staging)? {
+                let entry = entry?;
+                fs::rename(entry.path(), dir.join(entry.file_name()))?;
+            }
+            Ok(())
+        });
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    result
+}
+
+/// Engine of the backup in `dir`, by its data files.
+fn backup_engine(dir: &Path) -> Result<EngineEnum> {
+    if dir.join("conf").is_file() {
+        return Ok(EngineEnum::Sled);
+    }
+    for entry in fs::read_dir(dir)? {
+        if entry?.path().extension() == Some("log".as_ref()) {
+            return Ok(EngineEnum::Kvs);
+        }
+    }
+    Err(KvsError::StringError("The backup holds no store".to_owned()).into())
+}
+
+/// Remove the empty log files which opening an empty kvs store leaves in
+/// `dir`, and fail if `dir` holds the data of an `engine` store.
+fn clear_empty_store(engine: EngineEnum, dir: &Path) -> Result<()> {
+    let not_empty = || KvsError::StringError("The store is not empty".to_owned()).into();
+    if engine == EngineEnum::Sled {
+        if dir.join("conf").exists() || dir.join("db").exists() {
+            return Err(not_empty());
+        }
+        return Ok(());
+    }
+    let mut empty_logs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().extension() != Some("log".as_ref()) {
+            continue;
+        }
+        if entry.metadata()?.len() > 0 {
+            return Err(not_empty());
+        }
+        empty_logs.push(entry.path());
+    }
+    for log in empty_logs {
+        fs::remove_file(log)?;
+    }
+    Ok(())
+}
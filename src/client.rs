@@ -3,60 +3,780 @@
 //! 2. Send serialized request to server.
 
 use crate::{
-    transport::{Request, ResponseGet, ResponseRemove, ResponseSet},
+    auth::Credentials,
+    codec::{Codec, Encoding, FRAMED_VERSION},
+    net::{Addr, AnyStream, Stream},
+    tls::TlsOptions,
+    transport::{
+        Capabilities, ClusterStatus, ErrorCode, Feature, Message, Page, Position, ProxyStats,
+        RaftMessage, RaftReply, ReplicationEvent, ReplicationStatus, Reply, Request, RequestFrame,
+        Response, WatchEvent, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
     KvsError, Result,
 };
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
+use log::debug;
 use std::{
-    io::{BufReader, BufWriter, Write},
+    collections::VecDeque,
+    io::{self, BufReader},
     net::{TcpStream, ToSocketAddrs},
+    thread,
 };
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
+pub use builder::{KvsClientBuilder, RetryPolicy};
+
+mod builder;
+
+/// Features this client can use.
+const FEATURES: &[Feature] = &[Feature::Batch, Feature::Scan, Feature::Auth];
+
+/// Most requests of a pipeline sent before reading their responses.
+const PIPELINE_WINDOW: usize = 128;
+/// Most bytes of a pipeline sent before reading the responses, small enough
+/// for the socket buffers so that neither side blocks on a write.
+const PIPELINE_WINDOW_BYTES: usize = 64 << 10;
+/// Most redirects followed for one request, waiting for an election included.
+const MAX_REDIRECTS: u32 = 10;
 
 /// Struct for client
-pub struct KvsClient {
-    sender: BufWriter<TcpStream>,
-    receiver: Deserializer<IoRead<BufReader<TcpStream>>>,
+pub struct KvsClient<S: Stream = AnyStream> {
+    stream: BufReader<S>,
+    next_id: u64,
+    codec: Codec,
+    capabilities: Capabilities,
+    /// set once the connection failed, it cannot be used any more
+    broken: bool,
+    /// opens a new connection once this one broke, `None` if it cannot
+    reconnect: Option<Box<Reconnect<S>>>,
+    /// where `reconnect` connects to, changed by the redirects of a cluster
+    addr: Option<Addr>,
+    retry: RetryPolicy,
 }
 
+type Reconnect<S> = dyn Fn(&Addr) -> Result<KvsClient<S>> + Send + Sync;
+
 impl KvsClient {
+    /// Configure a client of the server at `addr`, with timeouts, retries and
+    /// reconnection.
+    pub fn builder(addr: &Addr) -> KvsClientBuilder {
+        KvsClientBuilder::new(addr.clone())
+    }
+
     /// Establish the connection to server and return a `KvsClient` object.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_sender = TcpStream::connect(addr)?;
-        let tcp_receiver = tcp_sender.try_clone()?;
-        Ok(Self {
-            sender: BufWriter::new(tcp_sender),
-            receiver: Deserializer::from_reader(BufReader::new(tcp_receiver)),
-        })
+        Self::connect_with(
+            || Ok(AnyStream::Tcp(TcpStream::connect(&addr)?)),
+            Encoding::Binary,
+        )
+    }
+
+    /// Establish the connection to a server listening on a Unix domain socket.
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<Self> {
+        Self::connect_with(
+            || Ok(AnyStream::Unix(UnixStream::connect(&path)?)),
+            Encoding::Binary,
+        )
+    }
+
+    /// Establish the connection to a server on either a TCP or a Unix address.
+    pub fn connect_addr(addr: &Addr) -> Result<Self> {
+        Self::connect_with(|| Ok(addr.connect()?), Encoding::Binary)
+    }
+
+    /// Establish the connection to a server and authenticate with `credentials`.
+    pub fn connect_with_credentials(addr: &Addr, credentials: Credentials) -> Result<Self> {
+        let mut client = Self::connect_addr(addr)?;
+        client.authenticate(credentials)?;
+        Ok(client)
+    }
+
+    /// Establish a TLS connection to a server.
+    ///
+    /// The server certificate is checked against `tls.ca`, and the client
+    /// certificate in `tls.cert`/`tls.key` is presented for mutual TLS.
+    pub fn connect_tls(addr: &Addr, tls: &TlsOptions) -> Result<Self> {
+        Self::connect_with(|| tls.connect(addr), Encoding::Binary)
+    }
+
+    /// Run the handshake on a connection opened by `connect`. A server which
+    /// only speaks version 1 refuses the handshake, so speak version 1 on a
+    /// second connection.
+    fn connect_with(connect: impl Fn() -> Result<AnyStream>, encoding: Encoding) -> Result<Self> {
+        match Self::from_stream_with_encoding(connect()?, encoding) {
+            Err(e) if matches!(e.downcast_ref(), Some(KvsError::UnsupportedVersion(1))) => {
+                debug!("The server has no handshake, fall back to protocol version 1");
+                Ok(Self::without_handshake(connect()?))
+            }
+            result => result,
+        }
+    }
+}
+
+impl<S: Stream> KvsClient<S> {
+    /// Create a client on an already connected stream, and run the handshake.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsupportedVersion(1)` if the server only speaks
+    /// version 1 and refuses the handshake; the `connect` functions open
+    /// another stream and speak version 1 on it then. A server hanging up on
+    /// the handshake is a connection error.
+    pub fn from_stream(stream: S) -> Result<Self> {
+        Self::from_stream_with_encoding(stream, Encoding::Binary)
+    }
+
+    /// Create a client on an already connected stream, and run the handshake
+    /// asking for frames in `encoding`.
+    pub fn from_stream_with_encoding(stream: S, encoding: Encoding) -> Result<Self> {
+        let mut client = Self::without_handshake(stream);
+        client.capabilities = client.hello(encoding)?;
+        if client.capabilities.version >= FRAMED_VERSION {
+            client.codec.switch_to(client.capabilities.encoding);
+        }
+        Ok(client)
+    }
+
+    /// Create a client speaking version 1, which has no handshake, on an
+    /// already connected stream.
+    fn without_handshake(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            next_id: 0,
+            codec: Codec::default(),
+            capabilities: Capabilities {
+                version: MIN_PROTOCOL_VERSION,
+                // a version 1 server knows `scan` and `auth`, not the later requests
+                features: vec![Feature::Scan, Feature::Auth],
+                server: None,
+                encoding: Encoding::Json,
+            },
+            broken: false,
+            reconnect: None,
+            addr: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// What this client and the server agreed on in the handshake.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    fn hello(&mut self, encoding: Encoding) -> Result<Capabilities> {
+        let request = Request::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: FEATURES.to_vec(),
+            encodings: vec![encoding],
+        };
+        match self.call(request)? {
+            Reply::Welcome(capabilities) => {
+                if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&capabilities.version) {
+                    return Err(KvsError::UnsupportedVersion(capabilities.version).into());
+                }
+                Ok(capabilities)
+            }
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Fail unless `feature` was agreed on in the handshake.
+    fn require(&self, feature: Feature) -> Result<()> {
+        if self.capabilities.supports(feature) {
+            Ok(())
+        } else {
+            Err(KvsError::UnsupportedFeature(feature).into())
+        }
+    }
+
+    /// Whether the connection failed, in which case it cannot be used any more.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Send one request and wait for its response.
+    fn call(&mut self, request: Request) -> Result<Reply> {
+        let mut redirects = 0;
+        loop {
+            let response = self.with_retries(request.is_idempotent(), |client| {
+                client.round_trip(&request)
+            })?;
+            match response.result {
+                Err(ErrorCode::NotLeader { leader }) if self.can_redirect(redirects) => {
+                    self.redirect(leader, &mut redirects)?;
+                }
+                result => return result.map_err(|code| KvsError::from(code).into()),
+            }
+        }
     }
+
+    /// Whether a request refused by a cluster node which isn't the leader may
+    /// be sent again, after `redirects` redirects.
+    fn can_redirect(&self, redirects: u32) -> bool {
+        self.reconnect.is_some() && redirects < MAX_REDIRECTS
+    }
+
+    /// Connect to `leader` on the next request, or wait for the election of
+    /// a leader if there is none.
+    fn redirect(&mut self, leader: Option<String>, redirects: &mut u32) -> Result<()> {
+        match leader {
+            Some(leader) => {
+                debug!("Redirected to the cluster leader at {leader}");
+                self.addr = Some(leader.parse()?);
+                self.broken = true;
+            }
+            None => {
+                let backoff = self.retry.initial_backoff * 2u32.pow(*redirects);
+                thread::sleep(backoff.min(self.retry.max_backoff));
+            }
+        }
+        *redirects += 1;
+        Ok(())
+    }
+
+    /// Run `exchange`, reconnecting first if the connection broke, and run it
+    /// again as the retry policy allows if the connection breaks on the way.
+    fn with_retries<T>(
+        &mut self,
+        idempotent: bool,
+        mut exchange: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let mut backoff = self.retry.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match self.reconnect_if_broken().and_then(|()| exchange(self)) {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    self.broken = true;
+                    if self.reconnect.is_none()
+                        || retries >= self.retry.max_retries
+                        || !(idempotent || self.retry.retry_non_idempotent)
+                    {
+                        return Err(e);
+                    }
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.retry.max_backoff);
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Replace a broken connection by a new one, if this client knows how.
+    fn reconnect_if_broken(&mut self) -> Result<()> {
+        let (true, Some(reconnect), Some(addr)) = (self.broken, &self.reconnect, &self.addr) else {
+            return Ok(());
+        };
+        let fresh = reconnect(addr)?;
+        self.stream = fresh.stream;
+        self.next_id = 0;
+        self.codec = fresh.codec;
+        self.capabilities = fresh.capabilities;
+        self.broken = false;
+        Ok(())
+    }
+
+    fn round_trip(&mut self, request: &Request) -> Result<Response> {
+        self.next_id += 1;
+        let frame = RequestFrame {
+            version: self.capabilities.version,
+            id: self.next_id,
+            request,
+        };
+        self.codec.send(self.stream.get_mut(), &frame)?;
+        let response = self.recv()?;
+        // an error with id 0 is the server refusing the connection
+        if response.id != frame.id && !(response.id == 0 && response.result.is_err()) {
+            return Err(KvsError::UnexpectedResponse.into());
+        }
+        Ok(response)
+    }
+
+    /// Send `requests` in windows, and return their results in the same order.
+    ///
+    /// A cluster node which isn't the leader refuses every request, so that
+    /// they are all sent again to the leader.
+    fn call_many(&mut self, requests: Vec<Request>) -> Result<Vec<Result<Reply>>> {
+        let idempotent = requests.iter().all(Request::is_idempotent);
+        let mut redirects = 0;
+        loop {
+            let results =
+                self.with_retries(idempotent, |client| client.round_trip_many(&requests))?;
+            let leader = results.iter().find_map(|result| match result {
+                Err(e) => match e.downcast_ref() {
+                    Some(KvsError::NotLeader(leader)) => Some(leader.clone()),
+                    _ => None,
+                },
+                Ok(_) => None,
+            });
+            match leader {
+                Some(leader) if self.can_redirect(redirects) => {
+                    self.redirect(leader, &mut redirects)?
+                }
+                _ => return Ok(results),
+            }
+        }
+    }
+
+    fn round_trip_many(&mut self, requests: &[Request]) -> Result<Vec<Result<Reply>>> {
+        let mut results = Vec::with_capacity(requests.len());
+        let mut requests = requests.iter().peekable();
+        while requests.peek().is_some() {
+            let first_id = self.next_id + 1;
+            let mut buf = vec![];
+            for request in requests.by_ref().take(PIPELINE_WINDOW) {
+                self.next_id += 1;
+                let frame = RequestFrame {
+                    version: self.capabilities.version,
+                    id: self.next_id,
+                    request,
+                };
+                self.codec.encode(&mut buf, &frame)?;
+                if buf.len() >= PIPELINE_WINDOW_BYTES {
+                    break;
+                }
+            }
+            let stream = self.stream.get_mut();
+            stream.write_all(&buf)?;
+            stream.flush()?;
+
+            // match the responses to the requests by id
+            let mut window: Vec<Option<Result<Reply>>> =
+                (first_id..=self.next_id).map(|_| None).collect();
+            for _ in 0..window.len() {
+                let response = self.recv()?;
+                let slot = response
+                    .id
+                    .checked_sub(first_id)
+                    .and_then(|i| window.get_mut(i as usize))
+                    .filter(|slot| slot.is_none())
+                    .ok_or(KvsError::UnexpectedResponse)?;
+                *slot = Some(response.result.map_err(|code| KvsError::from(code).into()));
+            }
+            results.extend(window.into_iter().flatten());
+        }
+        Ok(results)
+    }
+
+    fn recv(&mut self) -> Result<Response> {
+        Ok(self
+            .codec
+            .recv(&mut self.stream)?
+            .ok_or_else(|| KvsError::IO(io::ErrorKind::UnexpectedEof.into()))?)
+    }
+
+    /// Queue requests to send them together, which saves a round trip per
+    /// request. Useful to load many keys.
+    pub fn pipeline(&mut self) -> Pipeline<'_, S> {
+        Pipeline {
+            client: self,
+            requests: vec![],
+        }
+    }
+
+    /// Authenticate this connection, and return the identity given by the server.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<String> {
+        self.require(Feature::Auth)?;
+        match self.call(Request::Auth { credentials })? {
+            Reply::Identity(identity) => Ok(identity),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Check that the connection works.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.call(Request::Ping)? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
     /// request `get`
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.sender, &Request::Get { key })?;
-        self.sender.flush()?;
-        let response = ResponseGet::deserialize(&mut self.receiver)?;
-        match response {
-            ResponseGet::Ok(value) => Ok(value),
-            ResponseGet::Err(e) => Err(KvsError::StringError(e).into()),
+        match self.call(Request::Get { key })? {
+            Reply::Value(value) => Ok(value),
+            _ => Err(KvsError::UnexpectedResponse.into()),
         }
     }
     /// request `set`
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.sender, &Request::Set { key, value })?;
-        self.sender.flush()?;
-        let response = ResponseSet::deserialize(&mut self.receiver)?;
-        match response {
-            ResponseSet::Ok(()) => Ok(()),
-            ResponseSet::Err(e) => Err(KvsError::StringError(e).into()),
+        match self.call(Request::Set { key, value })? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
         }
     }
     /// request `remove`
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.sender, &Request::Remove { key })?;
-        self.sender.flush()?;
-        let response = ResponseRemove::deserialize(&mut self.receiver)?;
-        match response {
-            ResponseRemove::Ok(()) => Ok(()),
-            ResponseRemove::Err(e) => Err(KvsError::StringError(e).into()),
+        match self.call(Request::Remove { key })? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+    /// request `mget`, the values come in the order of `keys`, with `None`
+    /// for the missing keys
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.require(Feature::Batch)?;
+        match self.call(Request::MGet { keys })? {
+            Reply::Values(values) => Ok(values),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+    /// request `mset`
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.require(Feature::Batch)?;
+        match self.call(Request::MSet { pairs })? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
         }
     }
+    /// request `compare_and_set`: set `key` to `value` only if its current
+    /// value is `expected` (`None` for a missing key), return whether it was set
+    pub fn compare_and_set(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        match self.call(Request::CompareAndSet {
+            key,
+            expected,
+            value,
+        })? {
+            Reply::Swapped(swapped) => Ok(swapped),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+    /// request `scan`
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.require(Feature::Scan)?;
+        match self.call(Request::Scan { prefix })? {
+            Reply::Pairs(pairs) => Ok(pairs),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+    /// request `scan_page`: at most `limit` pairs under `prefix` after
+    /// `start_after`, the last key of the previous page
+    pub fn scan_page(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        limit: u32,
+    ) -> Result<Page> {
+        self.require(Feature::Scan)?;
+        match self.call(Request::ScanPage {
+            prefix,
+            start_after,
+            limit,
+        })? {
+            Reply::Page(page) => Ok(page),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// request `replication_status`
+    pub fn replication_status(&mut self) -> Result<ReplicationStatus> {
+        match self.call(Request::ReplicationStatus)? {
+            Reply::ReplicationStatus(status) => Ok(status),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// request `cluster_status`
+    pub fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        match self.call(Request::ClusterStatus)? {
+            Reply::ClusterStatus(status) => Ok(status),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// request `proxy_stats`
+    pub fn proxy_stats(&mut self) -> Result<ProxyStats> {
+        match self.call(Request::ProxyStats)? {
+            Reply::ProxyStats(stats) => Ok(stats),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Make the server write a backup of its data to `path`, a new directory
+    /// or a new tarball if it ends with `.tar`, in its `backup_dir`. It is
+    /// consistent unless the engine of the server cannot freeze its data,
+    /// like sled.
+    pub fn backup(&mut self, path: String) -> Result<()> {
+        match self.call(Request::Backup { path })? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Add the node at `addr` to the cluster, once the change is committed.
+    pub fn add_member(&mut self, addr: String) -> Result<()> {
+        match self.call(Request::AddMember { addr })? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Remove the node at `addr` from the cluster, once the change is committed.
+    pub fn remove_member(&mut self, addr: String) -> Result<()> {
+        match self.call(Request::RemoveMember { addr })? {
+            Reply::Done => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Send a message to another node of the cluster.
+    pub(crate) fn raft(&mut self, message: RaftMessage) -> Result<RaftReply> {
+        match self.call(Request::Raft(message))? {
+            Reply::Raft(reply) => Ok(reply),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Follow the server as a replica: get a snapshot of its data, unless it
+    /// still has the changes after `from`, and then its changes as they happen.
+    ///
+    /// The connection carries nothing else afterwards.
+    pub fn replicate(mut self, from: Option<Position>) -> Result<Replication<S>> {
+        self.next_id += 1;
+        let frame = RequestFrame {
+            version: self.capabilities.version,
+            id: self.next_id,
+            request: Request::Replicate { from },
+        };
+        self.codec.send(self.stream.get_mut(), &frame)?;
+        Ok(Replication { client: self })
+    }
+
+    /// Send `message` to the subscribers of `channel`, and return how many
+    /// got it.
+    pub fn publish(&mut self, channel: String, message: String) -> Result<usize> {
+        match self.call(Request::Publish { channel, message })? {
+            Reply::Receivers(receivers) => Ok(receivers),
+            _ => Err(KvsError::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Subscribe to `channels`: get the messages published on them from now on.
+    ///
+    /// The connection carries nothing else afterwards.
+    pub fn subscribe(self, channels: Vec<String>) -> Result<Subscription<S>> {
+        self.start_subscription(Request::Subscribe { channels })
+    }
+
+    /// Subscribe to the channels matching `patterns`, globs with `*` and `?`.
+    ///
+    /// The connection carries nothing else afterwards.
+    pub fn psubscribe(self, patterns: Vec<String>) -> Result<Subscription<S>> {
+        self.start_subscription(Request::PSubscribe { patterns })
+    }
+
+    fn start_subscription(mut self, request: Request) -> Result<Subscription<S>> {
+        self.next_id += 1;
+        let frame = RequestFrame {
+            version: self.capabilities.version,
+            id: self.next_id,
+            request,
+        };
+        self.codec.send(self.stream.get_mut(), &frame)?;
+        Ok(Subscription {
+            client: self,
+            messages: VecDeque::new(),
+        })
+    }
+
+    /// Watch the keys starting with `key_or_prefix`: get their changes as they
+    /// happen, from now on.
+    ///
+    /// The connection carries nothing else afterwards.
+    pub fn watch(mut self, key_or_prefix: String) -> Result<Watch<S>> {
+        self.next_id += 1;
+        let frame = RequestFrame {
+            version: self.capabilities.version,
+            id: self.next_id,
+            request: Request::Watch { key_or_prefix },
+        };
+        self.codec.send(self.stream.get_mut(), &frame)?;
+        Ok(Watch {
+            client: self,
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Read every change logged by the engine of the server after sequence
+    /// number `from`, then the new ones as they happen. A consumer resumes
+    /// after a disconnect from the sequence number of the last change it saw.
+    ///
+    /// The connection carries nothing else afterwards.
+    pub fn cdc(mut self, from: u64) -> Result<Cdc<S>> {
+        self.next_id += 1;
+        let frame = RequestFrame {
+            version: self.capabilities.version,
+            id: self.next_id,
+            request: Request::Cdc { from },
+        };
+        self.codec.send(self.stream.get_mut(), &frame)?;
+        Ok(Cdc {
+            client: self,
+            events: VecDeque::new(),
+        })
+    }
+}
+
+/// Replication stream of a server, started by `KvsClient::replicate`.
+pub struct Replication<S: Stream = AnyStream> {
+    client: KvsClient<S>,
+}
+
+impl<S: Stream> Iterator for Replication<S> {
+    type Item = Result<ReplicationEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let response = match self.client.recv() {
+            Ok(response) => response,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(match response.result {
+            Ok(Reply::Replication(event)) if response.id == self.client.next_id => Ok(event),
+            Ok(_) => Err(KvsError::UnexpectedResponse.into()),
+            Err(code) => Err(KvsError::from(code).into()),
+        })
+    }
+}
+
+/// Changes of watched keys, started by `KvsClient::watch`.
+pub struct Watch<S: Stream = AnyStream> {
+    client: KvsClient<S>,
+    /// received but not returned yet
+    events: VecDeque<WatchEvent>,
+}
+
+impl<S: Stream> Iterator for Watch<S> {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.events.is_empty() {
+            let response = match self.client.recv() {
+                Ok(response) => response,
+                Err(e) => return Some(Err(e)),
+            };
+            match response.result {
+                Ok(Reply::Watch(events)) if response.id == self.client.next_id => {
+                    self.events.extend(events)
+                }
+                Ok(_) => return Some(Err(KvsError::UnexpectedResponse.into())),
+                Err(code) => return Some(Err(KvsError::from(code).into())),
+            }
+        }
+        self.events.pop_front().map(Ok)
+    }
+}
+
+/// Messages of subscribed channels, started by `KvsClient::subscribe`.
+pub struct Subscription<S: Stream = AnyStream> {
+    client: KvsClient<S>,
+    /// received but not returned yet
+    messages: VecDeque<Message>,
+}
+
+impl<S: Stream> Iterator for Subscription<S> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.messages.is_empty() {
+            let response = match self.client.recv() {
+                Ok(response) => response,
+                Err(e) => return Some(Err(e)),
+            };
+            match response.result {
+                Ok(Reply::Messages(messages)) if response.id == self.client.next_id => {
+                    self.messages.extend(messages)
+                }
+                Ok(_) => return Some(Err(KvsError::UnexpectedResponse.into())),
+                Err(code) => return Some(Err(KvsError::from(code).into())),
+            }
+        }
+        self.messages.pop_front().map(Ok)
+    }
+}
+
+/// Changes logged by the engine of a server, started by `KvsClient::cdc`.
+pub struct Cdc<S: Stream = AnyStream> {
+    client: KvsClient<S>,
+    /// received but not returned yet
+    events: VecDeque<WatchEvent>,
+}
+
+impl<S: Stream> Iterator for Cdc<S> {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.events.is_empty() {
+            let response = match self.client.recv() {
+                Ok(response) => response,
+                Err(e) => return Some(Err(e)),
+            };
+            match response.result {
+                Ok(Reply::Cdc(events)) if response.id == self.client.next_id => {
+                    self.events.extend(events)
+                }
+                Ok(_) => return Some(Err(KvsError::UnexpectedResponse.into())),
+                Err(code) => return Some(Err(KvsError::from(code).into())),
+            }
+        }
+        self.events.pop_front().map(Ok)
+    }
+}
+
+/// Requests queued on a `KvsClient`, sent together by `execute`.
+pub struct Pipeline<'a, S: Stream = AnyStream> {
+    client: &'a mut KvsClient<S>,
+    requests: Vec<Request>,
+}
+
+impl<S: Stream> Pipeline<'_, S> {
+    /// queue a `get`
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+    /// queue a `set`
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+    /// queue a `remove`
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Number of queued requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send the queued requests, and return their results in the same order:
+    /// the value for a `get`, `None` for a `set` or a `remove`.
+    ///
+    /// The outer error is a failure of the connection, after which the results
+    /// of the requests are unknown.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        Ok(self
+            .client
+            .call_many(self.requests)?
+            .into_iter()
+            .map(|result| match result? {
+                Reply::Value(value) => Ok(value),
+                Reply::Done => Ok(None),
+                _ => Err(KvsError::UnexpectedResponse.into()),
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,156 @@
+//! Clients with timeouts, retries and reconnection.
+
+use std::time::Duration;
+
+use super::KvsClient;
+use crate::{auth::Credentials, codec::Encoding, net::Addr, tls::TlsOptions, Result};
+
+/// When a client sends a request again after its connection broke.
+///
+/// Only idempotent requests are sent again, such as `get` and `set`, unless
+/// `retry_non_idempotent` is set: a `remove` which reached the server before
+/// the connection broke would fail the second time.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// retries after the first attempt, 0 to never retry
+    pub max_retries: u32,
+    /// wait before the first retry, doubled at each retry
+    pub initial_backoff: Duration,
+    /// longest wait before a retry
+    pub max_backoff: Duration,
+    /// whether to also retry requests which aren't idempotent
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+/// Builder of a `KvsClient` which reconnects on its own.
+///
+/// The client opens a new connection, and authenticates it again, before the
+/// first request after its connection broke. It also follows the redirects
+/// of a cluster node to the leader.
+#[derive(Clone, Debug)]
+pub struct KvsClientBuilder {
+    addr: Addr,
+    tls: Option<TlsOptions>,
+    credentials: Option<Credentials>,
+    encoding: Encoding,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+
+impl KvsClientBuilder {
+    /// Start the configuration of a client of the server at `addr`.
+    pub fn new(addr: Addr) -> Self {
+        Self {
+            addr,
+            tls: None,
+            credentials: None,
+            encoding: Encoding::Binary,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Address of the server.
+    pub fn addr(&self) -> &Addr {
+        &self.addr
+    }
+
+    /// Whether the clients authenticate to the server.
+    pub(crate) fn has_credentials(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    /// The same settings for the server at `addr`.
+    pub fn with_addr(&self, addr: &Addr) -> Self {
+        Self {
+            addr: addr.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Connect over TLS.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticate every connection with `credentials`.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Ask for frames in `encoding`.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Give up connecting after `timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up waiting for a response after `timeout`.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up sending a request after `timeout`.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Retry the requests failed by a broken connection under `policy`.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Connect to the server.
+    pub fn build(self) -> Result<KvsClient> {
+        let mut client = self.connect(&self.addr)?;
+        client.retry = self.retry.clone();
+        client.addr = Some(self.addr.clone());
+        client.reconnect = Some(Box::new(move |addr| self.connect(addr)));
+        Ok(client)
+    }
+
+    /// Open and authenticate one connection to `addr`.
+    fn connect(&self, addr: &Addr) -> Result<KvsClient> {
+        let connect = || {
+            let stream = match self.connect_timeout {
+                Some(timeout) => addr.connect_timeout(timeout)?,
+                None => addr.connect()?,
+            };
+            stream.set_timeouts(self.read_timeout, self.write_timeout)?;
+            match &self.tls {
+                Some(tls) => tls.handshake(addr, stream),
+                None => Ok(stream),
+            }
+        };
+        let mut client = KvsClient::connect_with(connect, self.encoding)?;
+        if let Some(credentials) = &self.credentials {
+            client.authenticate(credentials.clone())?;
+        }
+        Ok(client)
+    }
+}
@@ -0,0 +1,148 @@
+//! Server configuration loaded from the `kvs-server --config` file.
+//!
+//! The file is TOML. Settings are split into two groups:
+//! 1. Restart-only settings (`addr`, `resp_addr`, `http_addr`, `memcache_addr`,
+//!    `engine`, `data_dir`, `replica_of`, `replica_token_file`, `cluster`,
+//!    `cluster_token_file`) are read once at startup.
+//! 2. Runtime settings (`RuntimeConfig`) can be reloaded on SIGHUP.
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path, path::PathBuf};
+
+use crate::Result;
+
+/// The whole content of a server configuration file.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Listening address. Needs a restart to change.
+    pub addr: Option<String>,
+    /// Listening address of the Redis protocol, off if `None`. Needs a restart to change.
+    pub resp_addr: Option<String>,
+    /// Listening address of the HTTP gateway, off if `None`. Needs a restart to change.
+    pub http_addr: Option<String>,
+    /// Listening address of the memcached protocol, off if `None`. Needs a restart to change.
+    pub memcache_addr: Option<String>,
+    /// Storage engine name. Needs a restart to change.
+    pub engine: Option<String>,
+    /// Directory holding the engine data. Needs a restart to change.
+    pub data_dir: Option<PathBuf>,
+    /// Address of the leader to replicate, `None` to be a leader. Needs a restart to change.
+    pub replica_of: Option<String>,
+    /// File holding the token a replica authenticates to its leader with. Needs a restart to change.
+    pub replica_token_file: Option<PathBuf>,
+    /// Addresses of the first members of the cluster, `None` to run alone. Needs a restart to change.
+    pub cluster: Option<Vec<String>>,
+    /// File holding the token a cluster node authenticates to the others with. Needs a restart to change.
+    pub cluster_token_file: Option<PathBuf>,
+    /// Settings which can be changed while the server is running.
+    #[serde(flatten)]
+    pub runtime: RuntimeConfig,
+}
+
+/// Settings which can safely change while the server is running.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// Maximum level of the server log.
+    pub log_level: LevelFilter,
+    /// Maximum number of concurrent client connections, unlimited if `None`.
+    /// A client over the limit gets a "Too many connections" error.
+    pub max_connections: Option<usize>,
+    /// Maximum number of requests per second on one connection, unlimited if `None`.
+    /// The messages between cluster nodes are not limited.
+    pub rate_limit: Option<u32>,
+    /// Requests running longer than this (in milliseconds) are logged as slow.
+    pub slow_log_threshold_ms: Option<u64>,
+    /// Uncompacted bytes that trigger a compaction, engine default if `None`.
+    pub compaction_threshold: Option<u64>,
+    /// Last changes kept by compactions for CDC consumers, engine default if `None`.
+    pub cdc_retention: Option<u64>,
+    /// Hashed credential file, clients must authenticate when it is set.
+    pub credentials_file: Option<PathBuf>,
+    /// ACL file, every operation is allowed if `None`.
+    pub acl_file: Option<PathBuf>,
+    /// Directory the `Backup` request writes in, backups are refused if `None`.
+    pub backup_dir: Option<PathBuf>,
+    /// Most pub/sub messages held for one subscriber which reads them slower
+    /// than they are published.
+    pub pubsub_buffer: usize,
+    /// What happens to a message for a subscriber whose buffer is full.
+    pub pubsub_drop: DropPolicy,
+}
+
+/// How a full pub/sub subscriber buffer makes room.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// drop the oldest message of the buffer
+    #[default]
+    DropOldest,
+    /// drop the new message
+    DropNewest,
+    /// end the subscription
+    Disconnect,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            log_level: LevelFilter::Info,
+            max_connections: None,
+            rate_limit: None,
+            slow_log_threshold_ms: None,
+            compaction_threshold: None,
+            cdc_retention: None,
+            credentials_file: None,
+            acl_file: None,
+            backup_dir: None,
+            pubsub_buffer: 1024,
+            pubsub_drop: DropPolicy::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read and parse the configuration file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Names of the restart-only settings which differ between `self` and `new`.
+    pub fn restart_only_changes(&self, new: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.addr != new.addr {
+            changed.push("addr");
+        }
+        if self.resp_addr != new.resp_addr {
+            changed.push("resp_addr");
+        }
+        if self.http_addr != new.http_addr {
+            changed.push("http_addr");
+        }
+        if self.memcache_addr != new.memcache_addr {
+            changed.push("memcache_addr");
+        }
+        if self.engine != new.engine {
+            changed.push("engine");
+        }
+        if self.data_dir != new.data_dir {
+            changed.push("data_dir");
+        }
+        if self.replica_of != new.replica_of {
+            changed.push("replica_of");
+        }
+        if self.replica_token_file != new.replica_token_file {
+            changed.push("replica_token_file");
+        }
+        if self.cluster != new.cluster {
+            changed.push("cluster");
+        }
+        if self.cluster_token_file != new.cluster_token_file {
+            changed.push("cluster_token_file");
+        }
+        changed
+    }
+}
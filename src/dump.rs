@@ -0,0 +1,361 @@
+//! Logical dumps of the pairs of a store.
+//!
+//! A dump holds key-value pairs, ordered by key, one per line in JSON Lines
+//! (`{"key":"k","value":"v"}`) or one per record in CSV, after a `key,value`
+//! header, with the quoting of RFC 4180. It does not depend on the engine, so
+//! importing the dump of one engine into another migrates the data, which
+//! `migrate` does without a dump in between.
+//!
+//! The pairs are read one page at a time, so that neither the whole store nor
+//! a reply bigger than a frame is ever held.
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{transport::Page, KvsEngine, KvsError, Result};
+
+/// Format of a dump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// one JSON object per line
+    #[default]
+    Jsonl,
+    /// comma separated values, with a header
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(DumpFormat::Jsonl),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(KvsError::StringError(format!("Unknown dump format: {s}"))),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "key,value";
+
+/// Most pairs read at once while going through a store.
+pub const SCAN_PAGE_SIZE: u32 = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Passes the pages of the pairs under `prefix` to `f`, in order.
+/// `scan_page(prefix, start_after)` reads the page after `start_after`.
+pub fn for_each_page(
+    prefix: &str,
+    mut scan_page: impl FnMut(String, Option<String>) -> Result<Page>,
+    mut f: impl FnMut(Vec<(String, String)>) -> Result<()>,
+) -> Result<()> {
+    let mut start_after = None;
+    loop {
+        let page = scan_page(prefix.to_owned(), start_after)?;
+        f(page.pairs)?;
+        match page.next {
+            Some(next) => start_after = Some(next),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Writes the pairs whose key starts with one of `prefixes`, all of them if
+/// there are none, to `out` in `format`, and returns how many there were.
+/// `scan_page(prefix, start_after)` reads a page of the pairs under a prefix,
+/// which is written out before the next one is read.
+pub fn export_pairs<W: Write>(
+    prefixes: &[String],
+    mut scan_page: impl FnMut(String, Option<String>) -> Result<Page>,
+    format: DumpFormat,
+    mut out: W,
+) -> Result<u64> {
+    if format == DumpFormat::Csv {
+        writeln!(out, "{CSV_HEADER}")?;
+    }
+    let mut count = 0;
+    for prefix in covering_prefixes(prefixes) {
+        for_each_page(&prefix, &mut scan_page, |pairs| {
+            for (key, value) in pairs {
+                match format {
+                    DumpFormat::Jsonl => {
+                        serde_json::to_writer(&mut out, &Pair { key, value })?;
+                        writeln!(out)?;
+                    }
+                    DumpFormat::Csv => writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?,
+                }
+                count += 1;
+            }
+            Ok(())
+        })?;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Reads the pairs of `input` in `format` whose key starts with one of
+/// `prefixes`, all of them if there are none, passes them to `write` in batches of `batch_size`, and
+/// returns how many there were.
+pub fn import_pairs<R: BufRead>(
+    input: R,
+    format: DumpFormat,
+    prefixes: &[String],
+    batch_size: usize,
+    mut write: impl FnMut(Vec<(String, String)>) -> Result<()>,
+) -> Result<u64> {
+    let mut reader = Reader {
+        input,
+        format,
+        line: 0,
+    };
+    if format == DumpFormat::Csv
+        && reader.next_csv()?.map(|fields| fields.join(",")).as_deref() != Some(CSV_HEADER)
+    {
+        return Err(
+            KvsError::StringError(format!("CSV dump without a {CSV_HEADER} header")).into(),
+        );
+    }
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Some((key, value)) = reader.next_pair()? {
+        if !prefixes.is_empty()
+            && !prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+        {
+            continue;
+        }
+        batch.push((key, value));
+        if batch.len() >= batch_size.max(1) {
+            count += batch.len() as u64;
+            write(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len() as u64;
+        write(batch)?;
+    }
+    Ok(count)
+}
+
+/// Number and checksum of the pairs of a store, equal for two stores holding
+/// the same pairs whatever their engines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairsDigest {
+    /// number of pairs
+    pub pairs: u64,
+    /// hex SHA-256 of the pairs, ordered by key
+    pub checksum: String,
+}
+
+impl PairsDigest {
+    /// Digest of the pairs of `engine`, read one page at a time.
+    pub fn of<E: KvsEngine + ?Sized>(engine: &mut E) -> Result<Self> {
+        let mut digest = DigestBuilder::default();
+        for_each_page(
+            "",
+            |prefix, start_after| engine.scan_page(prefix, start_after, SCAN_PAGE_SIZE as usize),
+            |pairs| {
+                digest.add(&pairs);
+                Ok(())
+            },
+        )?;
+        Ok(digest.finish())
+    }
+}
+
+/// `PairsDigest` of the pairs added so far, in order.
+#[derive(Default)]
+struct DigestBuilder {
+    hasher: Sha256,
+    pairs: u64,
+}
+
+impl DigestBuilder {
+    fn add(&mut self, pairs: &[(String, String)]) {
+        for (key, value) in pairs {
+            for field in [key, value] {
+                self.hasher.update((field.len() as u64).to_be_bytes());
+                self.hasher.update(field.as_bytes());
+            }
+        }
+        self.pairs += pairs.len() as u64;
+    }
+
+    fn finish(self) -> PairsDigest {
+        let checksum = self
+            .hasher
+            .finalize()
+            .iter()
+            .fold(String::new(), |mut hex, b| {
+                let _ = write!(hex, "{b:02x}");
+                hex
+            });
+        PairsDigest {
+            pairs: self.pairs,
+            checksum,
+        }
+    }
+}
+
+/// Copies every pair of `from` to `to`, one page of `batch_size` pairs at a
+/// time, then checks that `to` holds the same pairs, by number and checksum.
+///
+/// # Errors
+///
+/// It fails if the digests of the two engines differ, when `to` held other
+/// pairs already or lost some.
+pub fn migrate<F, T>(from: &mut F, to: &mut T, batch_size: usize) -> Result<PairsDigest>
+where
+    F: KvsEngine + ?Sized,
+    T: KvsEngine + ?Sized,
+{
+    let mut expected = DigestBuilder::default();
+    for_each_page(
+        "",
+        |prefix, start_after| from.scan_page(prefix, start_after, batch_size.max(1)),
+        |pairs| {
+            expected.add(&pairs);
+            to.mset(pairs)
+        },
+    )?;
+    let expected = expected.finish();
+    let copied = PairsDigest::of(to)?;
+    if copied != expected {
+        return Err(KvsError::StringError(format!(
+            "Migration check failed: {} pairs with checksum {} copied as {} pairs with checksum {}",
+            expected.pairs, expected.checksum, copied.pairs, copied.checksum
+        ))
+        .into());
+    }
+    Ok(copied)
+}
+
+/// `prefixes` without those under another one, in order, or the prefix of
+/// every key if there are none.
+fn covering_prefixes(prefixes: &[String]) -> Vec<String> {
+    if prefixes.is_empty() {
+        return vec![String::new()];
+    }
+    let mut covering: Vec<String> = prefixes
+        .iter()
+        .filter(|prefix| {
+            !prefixes
+                .iter()
+                .any(|other| other != *prefix && prefix.starts_with(other.as_str()))
+        })
+        .cloned()
+        .collect();
+    covering.sort();
+    covering.dedup();
+    covering
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+struct Reader<R> {
+    input: R,
+    format: DumpFormat,
+    /// number of the last line read, for the errors
+    line: u64,
+}
+
+impl<R: BufRead> Reader<R> {
+    fn invalid(&self, reason: &str) -> anyhow::Error {
+        KvsError::StringError(format!("Invalid dump at line {}: {reason}", self.line)).into()
+    }
+
+    fn read_line(&mut self, line: &mut String) -> Result<bool> {
+        if self.input.read_line(line)? == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        Ok(true)
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(String, String)>> {
+        match self.format {
+            DumpFormat::Jsonl => {
+                let mut line = String::new();
+                while self.read_line(&mut line)? {
+                    if !line.trim().is_empty() {
+                        let pair: Pair = serde_json::from_str(&line)
+                            .map_err(|e| self.invalid(&e.to_string()))?;
+                        return Ok(Some((pair.key, pair.value)));
+                    }
+                    line.clear();
+                }
+                Ok(None)
+            }
+            DumpFormat::Csv => match self.next_csv()? {
+                None => Ok(None),
+                Some(fields) => match <[String; 2]>::try_from(fields) {
+                    Ok([key, value]) => Ok(Some((key, value))),
+                    Err(_) => Err(self.invalid("expected a key and a value")),
+                },
+            },
+        }
+    }
+
+    /// Fields of the next CSV record, which spans several lines when a quoted
+    /// field holds a line break.
+    fn next_csv(&mut self) -> Result<Option<Vec<String>>> {
+        let mut line = String::new();
+        loop {
+            if !self.read_line(&mut line)? {
+                return Ok(None);
+            }
+            if !line.trim_end_matches(['\r', '\n']).is_empty() {
+                break;
+            }
+            line.clear();
+        }
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars().collect::<Vec<_>>().into_iter().peekable();
+        loop {
+            let Some(c) = chars.next() else {
+                if !quoted {
+                    fields.push(field);
+                    return Ok(Some(fields));
+                }
+                // the quoted field goes on on the next line
+                let mut next = String::new();
+                if !self.read_line(&mut next)? {
+                    return Err(self.invalid("unterminated quoted field"));
+                }
+                chars = next.chars().collect::<Vec<_>>().into_iter().peekable();
+                continue;
+            };
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                ('"', true) => quoted = false,
+                ('"', false) if field.is_empty() => quoted = true,
+                (',', false) => fields.push(std::mem::take(&mut field)),
+                ('\r' | '\n', false) => {}
+                (c, _) => field.push(c),
+            }
+        }
+    }
+}
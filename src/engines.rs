@@ -1,4 +1,8 @@
-use crate::Result;
+use std::path::Path;
+
+use crate::backup::{write_backup, Snapshot};
+use crate::transport::{Change, Page};
+use crate::{KvsError, Result};
 
 /// Trait for a key-value storage
 pub trait KvsEngine {
@@ -18,6 +22,105 @@ pub trait KvsEngine {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Gets the values of several keys, in the order of `keys`.
+    ///
+    /// A missing key gives `None` at its position.
+    fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Sets several key-value pairs.
+    ///
+    /// Engines which can write them in one batch should override it.
+    fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Sets `key` to `value` only if its current value is `expected`, `None`
+    /// meaning that the key doesn't exist. Returns whether the value was set.
+    fn compare_and_set(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        if self.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Lists the key-value pairs whose key starts with `prefix`, ordered by key.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the engine cannot list its keys.
+    fn scan(&mut self, _prefix: String) -> Result<Vec<(String, String)>> {
+        Err(KvsError::InvalidRequest("The engine cannot list its keys".to_owned()).into())
+    }
+
+    /// Lists at most `limit` key-value pairs whose key starts with `prefix`
+    /// and comes after `start_after`, ordered by key, to go through many keys
+    /// without holding them all.
+    ///
+    /// Engines which can start a scan in the middle should override it, the
+    /// default one runs the whole `scan` for every page.
+    fn scan_page(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> Result<Page> {
+        let pairs = self
+            .scan(prefix)?
+            .into_iter()
+            .filter(|(key, _)| start_after.as_ref().map_or(true, |after| key > after))
+            .take(limit)
+            .collect();
+        Ok(Page::new(pairs, limit))
+    }
+
+    /// Sets the number of uncompacted bytes which triggers a compaction.
+    ///
+    /// Engines without their own compaction ignore it.
+    fn set_compaction_threshold(&mut self, _threshold: u64) {}
+
+    /// Reads at most `limit` changes with a sequence number above `seq`,
+    /// oldest first. Every write is numbered, from 1, and the numbers survive
+    /// a restart.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Compacted` if compaction dropped some of these
+    /// changes, and an error if the engine keeps no change log.
+    fn changes(&mut self, _seq: u64, _limit: usize) -> Result<Vec<(u64, Change)>> {
+        Err(KvsError::InvalidRequest("The engine keeps no change log".to_owned()).into())
+    }
+
+    /// Sets the number of the last changes a compaction keeps for `changes`.
+    ///
+    /// Engines without a change log ignore it.
+    fn set_cdc_retention(&mut self, _changes: u64) {}
+
+    /// Freezes a consistent copy of the data, cheap enough to take while
+    /// writers wait for the engine. It is written out afterwards.
+    ///
+    /// An engine which cannot freeze its data says so, and its backups may
+    /// hold some of the writes made while they are written out.
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>> {
+        Err(KvsError::InvalidRequest("The engine cannot take snapshots".to_owned()).into())
+    }
+
+    /// Writes a backup of the data, as frozen by `snapshot`, to `path`, a new
+    /// directory, or a new tarball if it ends with `.tar`.
+    fn backup_to(&mut self, path: &Path) -> Result<()> {
+        write_backup(self.snapshot()?, path)
+    }
 }
 
 mod kvs;
@@ -1,14 +1,19 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
+use std::ffi::OsString;
 use std::fs::{create_dir, read_dir, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::{collections::HashMap, fs::File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::{collections::BTreeMap, fs::File};
 use uuid::Uuid;
 
+use crate::backup::Snapshot;
+use crate::transport::{Change, Page};
 use crate::{KvsEngine, KvsError, Result};
 
 const MAX_LOG_UNCOMPACTED_BYTES: u64 = 1024 * 1024;
+const CDC_RETENTION: u64 = 10_000;
 
 /// The KvStore store the key-value database.
 ///
@@ -17,9 +22,11 @@ const MAX_LOG_UNCOMPACTED_BYTES: u64 = 1024 * 1024;
 /// Example:
 /// ```rust
 /// # use crate::kvs::{KvStore, Result, KvsEngine};
+/// # use tempfile::TempDir;
 ///
 /// # fn main() -> Result<()> {
-/// let mut store = KvStore::open(kvs::DEFAULT_LOG_FILE)?;
+/// let temp_dir = TempDir::new()?;
+/// let mut store = KvStore::open(temp_dir.path())?;
 /// store.set("key1".to_owned(), "value1".to_owned());
 /// let value = store.get("key1".to_owned())?;
 /// assert_eq!(value, Some("value1".to_owned()));
@@ -30,9 +37,26 @@ pub struct KvStore {
     // log writer
     logger: Logger,
     /// Key -> the index of latest serialized Cmd
-    index: HashMap<String, CmdIdx>,
+    index: BTreeMap<String, CmdIdx>,
     /// uncompacted size
     uncompacted: u64,
+    /// uncompacted size which triggers a compaction
+    compaction_threshold: u64,
+    /// sequence number of the next Cmd
+    next_seq: u64,
+    /// every Cmd of the log, in log order which is sequence order
+    records: Vec<Record>,
+    /// the log holds every Cmd from this sequence number on
+    retained_from: u64,
+    /// number of the last Cmds a compaction keeps, live or not
+    cdc_retention: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    seq: u64,
+    start: usize,
+    len: usize,
 }
 
 struct CmdIdx {
@@ -47,10 +71,22 @@ impl CmdIdx {
 }
 
 /// The Command struct will represent an entry in the log
+///
+/// `seq` is 0 in the logs written before it existed, it is then given by the
+/// order of the log.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Cmd {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
     Empty,
 }
 impl Cmd {
@@ -58,7 +94,29 @@ impl Cmd {
         match &self {
             Cmd::Empty => unreachable!(),
             Cmd::Set { key, .. } => key,
-            Cmd::Remove { key } => key,
+            Cmd::Remove { key, .. } => key,
+        }
+    }
+
+    fn seq(&self) -> u64 {
+        match self {
+            Cmd::Empty => unreachable!(),
+            Cmd::Set { seq, .. } | Cmd::Remove { seq, .. } => *seq,
+        }
+    }
+
+    fn set_seq(&mut self, new: u64) {
+        match self {
+            Cmd::Empty => unreachable!(),
+            Cmd::Set { seq, .. } | Cmd::Remove { seq, .. } => *seq = new,
+        }
+    }
+
+    fn into_change(self) -> Change {
+        match self {
+            Cmd::Empty => unreachable!(),
+            Cmd::Set { key, value, .. } => Change::Set { key, value },
+            Cmd::Remove { key, .. } => Change::Remove { key },
         }
     }
 }
@@ -69,15 +127,13 @@ impl KvsEngine for KvStore {
         let cmd = Cmd::Set {
             key: k.to_owned(),
             value: v.clone(),
+            seq: self.next_seq,
         };
-        let pos = self.logger.pos;
-        serde_json::to_writer(&mut self.logger, &cmd)?;
-        self.logger.flush()?;
-        let len = self.logger.pos - pos;
+        let (pos, len) = self.append(&cmd)?;
         self.index.insert(k.clone(), CmdIdx::new(pos, len, cmd));
         self.uncompacted += len as u64;
 
-        if self.uncompacted > MAX_LOG_UNCOMPACTED_BYTES {
+        if self.uncompacted > self.compaction_threshold {
             self.compact()?;
         }
         Ok(())
@@ -86,12 +142,9 @@ impl KvsEngine for KvStore {
     /// Get the value of key `k`
     fn get(&mut self, k: String) -> Result<Option<String>> {
         match self.index.get(&k) {
-            Some(cmd_idx) => {
+            Some(&CmdIdx { start, len, .. }) => {
                 // read from log
-                self.logger
-                    .seek(SeekFrom::Start(cmd_idx.start.try_into()?))?;
-                let reader = self.logger.reader.get_mut().take(cmd_idx.len.try_into()?);
-                if let Cmd::Set { value, .. } = serde_json::from_reader(reader)? {
+                if let Cmd::Set { value, .. } = self.read(start, len)? {
                     Ok(Some(value))
                 } else {
                     Ok(None)
@@ -104,23 +157,108 @@ impl KvsEngine for KvStore {
 
     /// Remove the key `k`
     fn remove(&mut self, k: String) -> Result<()> {
-        // Check whether key is exist.
-        self.index.get(&k).ok_or(KvsError::KeyNotFound)?;
+        // Check whether key is exist, a removed key keeps its `Remove` in the index.
+        match self.index.get(&k) {
+            Some(CmdIdx {
+                cmd: Cmd::Set { .. },
+                ..
+            }) => {}
+            _ => return Err(KvsError::KeyNotFound.into()),
+        }
 
         // Construct a remove command
-        let cmd = Cmd::Remove { key: k.to_owned() };
-        let pos = self.logger.pos;
-        serde_json::to_writer(&mut self.logger, &cmd)?;
-        self.logger.flush()?;
-        let len = self.logger.pos - pos;
+        let cmd = Cmd::Remove {
+            key: k.to_owned(),
+            seq: self.next_seq,
+        };
+        let (pos, len) = self.append(&cmd)?;
         self.index.insert(k, CmdIdx::new(pos, len, cmd));
         self.uncompacted += len as u64;
 
-        if self.uncompacted > MAX_LOG_UNCOMPACTED_BYTES {
+        if self.uncompacted > self.compaction_threshold {
             self.compact()?;
         }
         Ok(())
     }
+
+    /// List the key-value pairs under `prefix` from the cached commands
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        Ok(self.pairs_under(&prefix, None).collect())
+    }
+
+    /// List a page of the key-value pairs under `prefix` from the cached commands
+    fn scan_page(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> Result<Page> {
+        let pairs = self
+            .pairs_under(&prefix, start_after.as_deref())
+            .take(limit)
+            .collect();
+        Ok(Page::new(pairs, limit))
+    }
+
+    fn set_compaction_threshold(&mut self, threshold: u64) {
+        self.compaction_threshold = threshold;
+    }
+
+    /// Read the changes after `seq` from the log
+    fn changes(&mut self, seq: u64, limit: usize) -> Result<Vec<(u64, Change)>> {
+        if seq + 1 < self.retained_from {
+            return Err(KvsError::Compacted(self.retained_from).into());
+        }
+        let first = self.records.partition_point(|record| record.seq <= seq);
+        let records = self.records[first..self.records.len().min(first + limit)].to_vec();
+        records
+            .into_iter()
+            .map(|record| {
+                Ok((
+                    record.seq,
+                    self.read(record.start, record.len)?.into_change(),
+                ))
+            })
+            .collect()
+    }
+
+    fn set_cdc_retention(&mut self, changes: u64) {
+        self.cdc_retention = changes;
+    }
+
+    /// Freeze the log as it is now: the log is only appended to, and a
+    /// compaction writes a new one, so its current bytes never change.
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>> {
+        self.logger.flush()?;
+        Ok(Box::new(LogSnapshot {
+            file: File::open(&self.logger.filename)?,
+            len: self.logger.pos as u64,
+            name: self
+                .logger
+                .filename
+                .file_name()
+                .ok_or(KvsError::UnexpectedCommandType)?
+                .to_owned(),
+        }))
+    }
+}
+
+/// The first `len` bytes of a log, still readable after a compaction
+/// removed the log.
+struct LogSnapshot {
+    file: File,
+    len: u64,
+    name: OsString,
+}
+
+impl Snapshot for LogSnapshot {
+    fn write_to(self: Box<Self>, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let mut out = File::create(dir.join(&self.name))?;
+        io::copy(&mut self.file.take(self.len), &mut out)?;
+        out.sync_all()?;
+        Ok(())
+    }
 }
 
 impl KvStore {
@@ -129,8 +267,13 @@ impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let mut ret = KvStore {
             logger: Logger::new(path)?,
-            index: HashMap::new(),
+            index: BTreeMap::new(),
             uncompacted: 0,
+            compaction_threshold: MAX_LOG_UNCOMPACTED_BYTES,
+            next_seq: 1,
+            records: vec![],
+            retained_from: 1,
+            cdc_retention: CDC_RETENTION,
         };
 
         // reconstruct the index
@@ -143,7 +286,16 @@ impl KvStore {
             let mut cmd_idx = CmdIdx::new(read_pos, len, Cmd::Empty);
             match cmd? {
                 Cmd::Empty => return Err(KvsError::UnexpectedCommandType.into()),
-                valid_cmd => {
+                mut valid_cmd => {
+                    if valid_cmd.seq() == 0 {
+                        valid_cmd.set_seq(ret.next_seq);
+                    }
+                    ret.next_seq = valid_cmd.seq() + 1;
+                    ret.records.push(Record {
+                        seq: valid_cmd.seq(),
+                        start: read_pos,
+                        len,
+                    });
                     cmd_idx.cmd = valid_cmd.clone();
                     ret.index.insert(valid_cmd.get_key().clone(), cmd_idx);
                 }
@@ -153,12 +305,70 @@ impl KvStore {
 
         // NOTE: we will ONLY append the log!!
         ret.logger.pos = read_pos;
+        ret.retained_from = retained_from(&ret.records, ret.next_seq);
 
         Ok(ret)
     }
 
+    /// Live pairs under `prefix` after `start_after`, ordered by key.
+    fn pairs_under<'a>(
+        &'a self,
+        prefix: &'a str,
+        start_after: Option<&str>,
+    ) -> impl Iterator<Item = (String, String)> + 'a {
+        let start = match start_after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_owned()),
+            _ => Bound::Included(prefix.to_owned()),
+        };
+        self.index
+            .range((start, Bound::Unbounded))
+            .take_while(move |(k, _)| k.starts_with(prefix))
+            .filter_map(|(k, cmd_idx)| match &cmd_idx.cmd {
+                Cmd::Set { value, .. } => Some((k.to_owned(), value.to_owned())),
+                _ => None,
+            })
+    }
+
+    /// Append `cmd` to the log and return its position and length
+    fn append(&mut self, cmd: &Cmd) -> Result<(usize, usize)> {
+        let pos = self.logger.pos;
+        serde_json::to_writer(&mut self.logger, cmd)?;
+        self.logger.flush()?;
+        let len = self.logger.pos - pos;
+        self.records.push(Record {
+            seq: cmd.seq(),
+            start: pos,
+            len,
+        });
+        self.next_seq = cmd.seq() + 1;
+        Ok((pos, len))
+    }
+
+    /// Read the Cmd of `len` bytes at `start` of the log
+    fn read(&mut self, start: usize, len: usize) -> Result<Cmd> {
+        self.logger.seek(SeekFrom::Start(start.try_into()?))?;
+        let reader = self.logger.reader.get_mut().take(len.try_into()?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
     fn compact(&mut self) -> Result<()> {
-        // dump self.index into new file and reset the writer
+        // keep the live Cmds, and the last `cdc_retention` ones for the CDC
+        // consumers, in sequence order
+        let retained = self.next_seq.saturating_sub(self.cdc_retention);
+        let mut cmds: Vec<Cmd> = self
+            .index
+            .values()
+            .filter(|cmdidx| cmdidx.cmd.seq() < retained)
+            .map(|cmdidx| cmdidx.cmd.clone())
+            .collect();
+        cmds.sort_by_key(Cmd::seq);
+        let first = self.records.partition_point(|record| record.seq < retained);
+        let tail = self.records[first..].to_vec();
+        for record in tail {
+            cmds.push(self.read(record.start, record.len)?);
+        }
+
+        // dump them into new file and reset the writer
         let old_log = self.logger.filename.clone();
         let new_log = old_log
             .parent()
@@ -167,23 +377,21 @@ impl KvStore {
         self.logger.writer = BufWriter::new(get_file_handler(&new_log)?);
         self.logger.pos = 0;
 
-        let mut new_index: HashMap<String, CmdIdx> = HashMap::new();
-        let mut pos = 0;
-        for (k, cmdidx) in self.index.iter() {
-            let cmd = &cmdidx.cmd;
-            serde_json::to_writer(&mut self.logger, &cmd)?;
-            self.logger.flush()?;
-            let len = self.logger.pos - pos;
-            new_index.insert(k.to_string(), CmdIdx::new(pos, len, cmd.clone()));
-            pos += len;
+        let mut new_index: BTreeMap<String, CmdIdx> = BTreeMap::new();
+        let next_seq = self.next_seq;
+        self.records.clear();
+        for cmd in cmds {
+            let (pos, len) = self.append(&cmd)?;
+            new_index.insert(cmd.get_key().to_string(), CmdIdx::new(pos, len, cmd));
         }
+        self.next_seq = next_seq;
         // reset the reader to this new file
         self.logger.reader = BufReader::new(get_file_handler(&new_log)?);
         self.index = new_index;
 
         self.logger.filename = new_log;
-        self.logger.pos = pos;
         self.uncompacted = 0;
+        self.retained_from = retained_from(&self.records, self.next_seq);
 
         // remember to remove the file
         std::fs::remove_file(&old_log)?;
@@ -191,6 +399,18 @@ impl KvStore {
     }
 }
 
+/// First sequence number from which `records` holds every Cmd before `next_seq`
+fn retained_from(records: &[Record], next_seq: u64) -> u64 {
+    let mut first = next_seq;
+    for record in records.iter().rev() {
+        if record.seq + 1 != first {
+            break;
+        }
+        first = record.seq;
+    }
+    first
+}
+
 fn get_file_handler(path: impl Into<PathBuf>) -> Result<File> {
     Ok(OpenOptions::new()
         .read(true)
@@ -1,6 +1,12 @@
 //! Sled storage
-use sled::{Db, Tree};
+use std::ops::Bound;
+use std::path::Path;
 
+use sled::{Batch, Db, Tree};
+
+use crate::backup::Snapshot;
+use crate::dump::SCAN_PAGE_SIZE;
+use crate::transport::Page;
 use crate::{KvsEngine, KvsError, Result};
 
 /// sled bridge
@@ -36,4 +42,86 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        let tree: &Tree = &self.0;
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        tree.scan_prefix(prefix)
+            .map(|pair| {
+                let (k, v) = pair?;
+                Ok((
+                    String::from_utf8(k.to_vec())?,
+                    String::from_utf8(v.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn scan_page(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> Result<Page> {
+        let tree: &Tree = &self.0;
+        let start = match start_after {
+            Some(after) if after >= prefix => Bound::Excluded(after.into_bytes()),
+            _ => Bound::Included(prefix.clone().into_bytes()),
+        };
+        let pairs = tree
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .take_while(|pair| {
+                pair.as_ref()
+                    .map_or(true, |(k, _)| k.starts_with(prefix.as_bytes()))
+            })
+            .take(limit)
+            .map(|pair| {
+                let (k, v) = pair?;
+                Ok((
+                    String::from_utf8(k.to_vec())?,
+                    String::from_utf8(v.to_vec())?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Page::new(pairs, limit))
+    }
+
+    /// Share the database, the pairs are copied once the engine is released.
+    ///
+    /// sled has no snapshot of its own, and its iterator doesn't give a point
+    /// in time view: the writes made while the backup is written out may be
+    /// in it or not, each pair being either its old or its new value.
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>> {
+        Ok(Box::new(SledSnapshot(self.0.clone())))
+    }
+}
+
+/// Live sled database, copied into a new one.
+struct SledSnapshot(Db);
+
+impl Snapshot for SledSnapshot {
+    fn write_to(self: Box<Self>, dir: &Path) -> Result<()> {
+        let db = sled::open(dir)?;
+        let mut pairs = self.0.iter().peekable();
+        while pairs.peek().is_some() {
+            let mut batch = Batch::default();
+            for pair in pairs.by_ref().take(SCAN_PAGE_SIZE as usize) {
+                let (key, value) = pair?;
+                batch.insert(key, value);
+            }
+            db.apply_batch(batch)?;
+        }
+        db.flush()?;
+        Ok(())
+    }
 }
@@ -1,6 +1,8 @@
 use std::io;
 use thiserror::Error;
 
+use crate::transport::Feature;
+
 /// Error type for kvs.
 #[derive(Error, Debug)]
 pub enum KvsError {
@@ -19,6 +21,50 @@ pub enum KvsError {
     /// Unexpected command error
     #[error("Unexpected command type")]
     UnexpectedCommandType,
+    /// Address which is neither `host:port` nor `unix:/path`
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+    /// TLS setup or handshake error
+    #[error("TLS error: {0}")]
+    Tls(String),
+    /// Request sent before the connection authenticated
+    #[error("Authentication required")]
+    AuthRequired,
+    /// Wrong credentials
+    #[error("Authentication failed")]
+    AuthFailed,
+    /// Operation not allowed by the ACL
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    /// The other side speaks another protocol version
+    #[error("Unsupported protocol version, the server speaks version {0}")]
+    UnsupportedVersion(u32),
+    /// Feature which was not agreed on in the handshake
+    #[error("Unsupported feature: {0:?}")]
+    UnsupportedFeature(Feature),
+    /// Request the server cannot handle
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// Failure on the server side, such as an I/O error of its engine
+    #[error("Server error: {0}")]
+    Server(String),
+    /// Response which doesn't match the request
+    #[error("Unexpected response")]
+    UnexpectedResponse,
+    /// Write sent to a read-only replica
+    #[error("Read-only replica, write to the leader at {0}")]
+    ReadOnly(String),
+    /// No connection of a pool became available in time
+    #[error("Timed out waiting for a pooled connection")]
+    PoolTimeout,
+    /// Request sent to a cluster node which isn't the leader, with the
+    /// address of the leader if there is one
+    #[error("Not the cluster leader{}", .0.as_ref().map(|leader| format!(", the leader is {leader}")).unwrap_or_default())]
+    NotLeader(Option<String>),
+    /// Changes asked for which compaction dropped, with the sequence number
+    /// of the first change kept
+    #[error("Changes before {0} were compacted")]
+    Compacted(u64),
 }
 
 /// Result type for kvs
@@ -1,16 +1,46 @@
 #![deny(missing_docs)]
 //! A simple Key-Value database
 
-pub use client::KvsClient;
+pub use acl::{Acl, Op};
+pub use auth::{hash_secret, Authenticator, Credentials};
+pub use backup::{restore_backup, write_backup, Snapshot};
+pub use client::{
+    Cdc, KvsClient, KvsClientBuilder, Pipeline, Replication, RetryPolicy, Subscription, Watch,
+};
+pub use codec::Encoding;
+pub use config::{DropPolicy, RuntimeConfig, ServerConfig};
+pub use dump::{
+    export_pairs, for_each_page, import_pairs, migrate, DumpFormat, PairsDigest, SCAN_PAGE_SIZE,
+};
 pub use engines::{KvStore, KvsEngine, SledKvsEngine};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use net::{Addr, AnyListener, AnyStream, Listener, Stream};
+pub use pool::{KvsClientPool, PoolOptions, PooledClient};
+pub use proxy::{KvsProxy, ProxyOptions, Shard};
+pub use server::{ClusterConfig, KvsServer, ServerHandle};
+pub use shard::{MapSource, ShardMap, ShardedClient};
+pub use tls::{TlsOptions, TlsStream};
+pub use transport::{
+    BackendStats, Capabilities, Change, ClusterStatus, Feature, Message, Page, Position,
+    ProxyStats, RaftRole, ReplicationEvent, ReplicationStatus, ServerInfo, WatchEvent,
+};
 
 /// default log file path
 pub static DEFAULT_LOG_FILE: &str = "./";
 
+mod acl;
+mod auth;
+mod backup;
 mod client;
+mod codec;
+mod config;
+mod dump;
 mod engines;
 mod error;
+mod net;
+mod pool;
+mod proxy;
 mod server;
+mod shard;
+mod tls;
 mod transport;
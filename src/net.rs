@@ -0,0 +1,306 @@
+//! Stream transports which `KvsServer` and `KvsClient` can run on.
+//!
+//! An address is either a TCP socket address (`127.0.0.1:4000`) or a Unix
+//! domain socket path with the `unix:` prefix (`unix:/tmp/kvs.sock`). Unix
+//! domain sockets are only available on Unix platforms.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+};
+
+use crate::{tls::TlsStream, KvsError};
+
+/// A bidirectional byte stream between a client and the server.
+pub trait Stream: Read + Write + Send + 'static {
+    /// Description of the other end, for logging.
+    fn peer(&self) -> String;
+
+    /// Identity the other end proved while connecting, if any.
+    fn identity(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn peer(&self) -> String {
+        match self.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown tcp peer".to_owned(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn peer(&self) -> String {
+        match self
+            .peer_addr()
+            .ok()
+            .and_then(|a| a.as_pathname().map(|p| p.to_owned()))
+        {
+            Some(path) => format!("unix:{}", path.display()),
+            None => "unix socket peer".to_owned(),
+        }
+    }
+}
+
+/// Something which accepts incoming `Stream`s.
+pub trait Listener: Send + 'static {
+    /// Stream type of the accepted connections.
+    type Stream: Stream;
+
+    /// Block until a new connection comes in.
+    fn accept(&self) -> io::Result<Self::Stream>;
+
+    /// Description of the listening address, for logging.
+    fn local(&self) -> String;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn local(&self) -> String {
+        match self.local_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown tcp address".to_owned(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn local(&self) -> String {
+        match self
+            .local_addr()
+            .ok()
+            .and_then(|a| a.as_pathname().map(|p| p.to_owned()))
+        {
+            Some(path) => format!("unix:{}", path.display()),
+            None => "unnamed unix socket".to_owned(),
+        }
+    }
+}
+
+/// Address of a server, parsed from `host:port` or `unix:/path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Addr {
+    /// TCP socket address
+    Tcp(SocketAddr),
+    /// Unix domain socket path
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Addr {
+    /// Connect to this address.
+    pub fn connect(&self) -> io::Result<AnyStream> {
+        Ok(match self {
+            Addr::Tcp(addr) => AnyStream::Tcp(TcpStream::connect(addr)?),
+            #[cfg(unix)]
+            Addr::Unix(path) => AnyStream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    /// Connect to this address, giving up after `timeout`.
+    ///
+    /// Connecting to a Unix domain socket doesn't wait, so it has no timeout.
+    pub fn connect_timeout(&self, timeout: Duration) -> io::Result<AnyStream> {
+        Ok(match self {
+            Addr::Tcp(addr) => AnyStream::Tcp(TcpStream::connect_timeout(addr, timeout)?),
+            #[cfg(unix)]
+            Addr::Unix(path) => AnyStream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    /// Listen on this address.
+    ///
+    /// A stale socket file left by a previous server is removed first. Any
+    /// other file, or a socket which a server still listens on, is an
+    /// `AddrInUse` error.
+    pub fn bind(&self) -> io::Result<AnyListener> {
+        Ok(match self {
+            Addr::Tcp(addr) => AnyListener::Tcp(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                if fs::symlink_metadata(path).is_ok() {
+                    let stale = fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket())
+                        && UnixStream::connect(path)
+                            .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+                    if !stale {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("Address in use: {}", path.display()),
+                        ));
+                    }
+                    fs::remove_file(path)?;
+                }
+                AnyListener::Unix(UnixListener::bind(path)?)
+            }
+        })
+    }
+}
+
+impl FromStr for Addr {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return match path {
+                #[cfg(unix)]
+                path if !path.is_empty() => Ok(Addr::Unix(PathBuf::from(path))),
+                _ => Err(KvsError::InvalidAddress(s.to_owned())),
+            };
+        }
+        s.to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .map(Addr::Tcp)
+            .ok_or_else(|| KvsError::InvalidAddress(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Addr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        Addr::Tcp(addr)
+    }
+}
+
+/// Either a TCP or a Unix domain socket stream.
+pub enum AnyStream {
+    /// TCP stream
+    Tcp(TcpStream),
+    /// Unix domain socket stream
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// TLS over one of the above
+    Tls(Box<TlsStream<AnyStream>>),
+}
+
+impl AnyStream {
+    /// Set the read and write timeouts of the socket, `None` to wait forever.
+    pub fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        match self {
+            AnyStream::Tcp(s) => {
+                s.set_read_timeout(read)?;
+                s.set_write_timeout(write)
+            }
+            #[cfg(unix)]
+            AnyStream::Unix(s) => {
+                s.set_read_timeout(read)?;
+                s.set_write_timeout(write)
+            }
+            AnyStream::Tls(s) => s.get_ref().set_timeouts(read, write),
+        }
+    }
+}
+
+impl Read for AnyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            AnyStream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            AnyStream::Unix(s) => s.read(buf),
+            AnyStream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for AnyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AnyStream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            AnyStream::Unix(s) => s.write(buf),
+            AnyStream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AnyStream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            AnyStream::Unix(s) => s.flush(),
+            AnyStream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+impl Stream for AnyStream {
+    fn peer(&self) -> String {
+        match self {
+            AnyStream::Tcp(s) => s.peer(),
+            #[cfg(unix)]
+            AnyStream::Unix(s) => s.peer(),
+            AnyStream::Tls(s) => s.peer(),
+        }
+    }
+
+    fn identity(&self) -> Option<String> {
+        match self {
+            AnyStream::Tls(s) => s.identity(),
+            _ => None,
+        }
+    }
+}
+
+/// Either a TCP or a Unix domain socket listener.
+pub enum AnyListener {
+    /// TCP listener
+    Tcp(TcpListener),
+    /// Unix domain socket listener
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener for AnyListener {
+    type Stream = AnyStream;
+
+    fn accept(&self) -> io::Result<AnyStream> {
+        Ok(match self {
+            AnyListener::Tcp(l) => AnyStream::Tcp(Listener::accept(l)?),
+            #[cfg(unix)]
+            AnyListener::Unix(l) => AnyStream::Unix(Listener::accept(l)?),
+        })
+    }
+
+    fn local(&self) -> String {
+        match self {
+            AnyListener::Tcp(l) => l.local(),
+            #[cfg(unix)]
+            AnyListener::Unix(l) => l.local(),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Pool of client connections shared by several threads.
+//!
+//! A thread checks a connection out with `KvsClientPool::get`, and the
+//! connection goes back to the pool when the `PooledClient` is dropped, unless
+//! it broke in the meantime.
+
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{client::KvsClient, net::Addr, KvsError, Result};
+
+/// Settings of a `KvsClientPool`.
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    /// connections opened up front and kept open when idle
+    pub min_connections: usize,
+    /// most connections open at once, checked out or idle
+    pub max_connections: usize,
+    /// idle connections above `min_connections` are closed after this long,
+    /// never if `None`
+    pub idle_timeout: Option<Duration>,
+    /// longest wait for a connection when all of them are checked out
+    pub checkout_timeout: Duration,
+    /// whether to ping an idle connection before handing it out
+    pub health_check: bool,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            min_connections: 0,
+            max_connections: 16,
+            idle_timeout: Some(Duration::from_secs(300)),
+            checkout_timeout: Duration::from_secs(30),
+            health_check: true,
+        }
+    }
+}
+
+type Connect = dyn Fn() -> Result<KvsClient> + Send + Sync;
+
+/// Pool of connections to one server.
+///
+/// Cloning the pool is cheap, and the clones share the connections.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connect: Box<Connect>,
+    options: PoolOptions,
+    state: Mutex<State>,
+    /// signaled when a connection is returned or closed
+    released: Condvar,
+}
+
+struct State {
+    /// most recently returned last
+    idle: VecDeque<Idle>,
+    /// connections open, checked out or idle
+    open: usize,
+}
+
+struct Idle {
+    client: KvsClient,
+    since: Instant,
+}
+
+impl KvsClientPool {
+    /// Create a pool of connections to `addr`.
+    pub fn new(addr: &Addr, options: PoolOptions) -> Result<Self> {
+        let addr = addr.clone();
+        Self::with_connect(options, move || KvsClient::connect_addr(&addr))
+    }
+
+    /// Create a pool which opens its connections with `connect`, for instance
+    /// to use TLS or to authenticate them.
+    pub fn with_connect<F>(options: PoolOptions, connect: F) -> Result<Self>
+    where
+        F: Fn() -> Result<KvsClient> + Send + Sync + 'static,
+    {
+        let mut idle = VecDeque::with_capacity(options.min_connections);
+        for _ in 0..options.min_connections {
+            idle.push_back(Idle {
+                client: connect()?,
+                since: Instant::now(),
+            });
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                connect: Box::new(connect),
+                state: Mutex::new(State {
+                    open: idle.len(),
+                    idle,
+                }),
+                options,
+                released: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Check a connection out, opening one if none is idle and the pool isn't
+    /// full, or waiting for one to be returned otherwise.
+    pub fn get(&self) -> Result<PooledClient> {
+        let inner = &*self.inner;
+        let deadline = Instant::now() + inner.options.checkout_timeout;
+        let mut state = inner.state.lock().unwrap();
+        loop {
+            inner.close_expired(&mut state);
+            if let Some(Idle { mut client, .. }) = state.idle.pop_back() {
+                if !inner.options.health_check {
+                    return Ok(self.pooled(client));
+                }
+                drop(state);
+                if client.ping().is_ok() {
+                    return Ok(self.pooled(client));
+                }
+                // try the next one
+                state = inner.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+
+            if state.open < inner.options.max_connections {
+                state.open += 1;
+                drop(state);
+                return match (inner.connect)() {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        inner.close();
+                        Err(e)
+                    }
+                };
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(KvsError::PoolTimeout.into());
+            }
+            state = inner.released.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Number of open connections, checked out or idle.
+    pub fn connections(&self) -> usize {
+        self.inner.state.lock().unwrap().open
+    }
+
+    /// Number of idle connections.
+    pub fn idle_connections(&self) -> usize {
+        self.inner.state.lock().unwrap().idle.len()
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            pool: self.inner.clone(),
+        }
+    }
+}
+
+impl Inner {
+    /// Close the idle connections above `min_connections` which timed out.
+    fn close_expired(&self, state: &mut State) {
+        let Some(timeout) = self.options.idle_timeout else {
+            return;
+        };
+        while state.open > self.options.min_connections
+            && state
+                .idle
+                .front()
+                .is_some_and(|idle| idle.since.elapsed() >= timeout)
+        {
+            state.idle.pop_front();
+            state.open -= 1;
+        }
+    }
+
+    /// Account for a checked out connection which was closed.
+    fn close(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// Connection checked out of a `KvsClientPool`, returned to it on drop.
+pub struct PooledClient {
+    /// `None` once dropped
+    client: Option<KvsClient>,
+    pool: Arc<Inner>,
+}
+
+impl PooledClient {
+    /// Close the connection instead of returning it to the pool.
+    pub fn discard(mut self) {
+        self.client.take();
+        self.pool.close();
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        if client.is_broken() {
+            self.pool.close();
+            return;
+        }
+        let mut state = self.pool.state.lock().unwrap();
+        state.idle.push_back(Idle {
+            client,
+            since: Instant::now(),
+        });
+        drop(state);
+        self.pool.released.notify_one();
+    }
+}
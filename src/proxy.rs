@@ -0,0 +1,624 @@
+//! Proxy speaking the native protocol in front of sharded servers.
+//!
+//! The clients of a `KvsProxy` see a single server. The proxy sends each key
+//! to the shard owning it, by consistent hashing as in `ShardMap`, through a
+//! pool of connections per server. A shard is a primary, which takes the
+//! writes, and its read-only replicas, which may take the reads.
+//!
+//! The proxy authenticates its clients and checks their ACL itself, like a
+//! server, since the servers only see the proxy. Credentials for the servers
+//! are refused unless the clients must authenticate, so that anonymous
+//! clients don't use them.
+
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    net::{TcpListener, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    acl::{Acl, Op, ANONYMOUS},
+    auth::{Authenticator, Credentials},
+    client::KvsClientBuilder,
+    codec::{Codec, Encoding, FRAMED_VERSION},
+    net::{Addr, Listener, Stream},
+    pool::{KvsClientPool, PoolOptions},
+    shard::ShardMap,
+    tls::TlsStream,
+    transport::{
+        BackendStats, Capabilities, ErrorCode, Feature, Page, ProxyStats, Reply, Request, Response,
+        ServerInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    KvsClient, KvsError, Result,
+};
+
+/// Servers holding the same keys.
+#[derive(Clone, Debug)]
+pub struct Shard {
+    /// server taking the writes
+    pub primary: Addr,
+    /// replicas of `primary`
+    pub replicas: Vec<Addr>,
+}
+
+/// Settings of a `KvsProxy`.
+#[derive(Clone, Debug)]
+pub struct ProxyOptions {
+    /// whether to send the reads to the replicas, when a shard has healthy
+    /// ones, at the cost of reading values they didn't replicate yet
+    pub read_from_replicas: bool,
+    /// time between two health checks of every server
+    pub health_check_interval: Duration,
+    /// settings of the pool of each server
+    pub pool: PoolOptions,
+    /// hashed credential file, clients must authenticate when it is set
+    pub credentials_file: Option<PathBuf>,
+    /// ACL file, every operation is allowed if `None`
+    pub acl_file: Option<PathBuf>,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            read_from_replicas: false,
+            health_check_interval: Duration::from_secs(1),
+            pool: PoolOptions::default(),
+            credentials_file: None,
+            acl_file: None,
+        }
+    }
+}
+
+/// Proxy forwarding the requests of its clients to the shards.
+pub struct KvsProxy {
+    shared: Arc<Shared>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+struct Shared {
+    options: ProxyOptions,
+    /// `None` if clients don't need to authenticate
+    auth: Option<Authenticator>,
+    /// `None` if every operation is allowed
+    acl: Option<Acl>,
+    /// primaries by consistent hashing of the keys
+    map: ShardMap,
+    /// index of each shard in `shards`, by primary address
+    indexes: HashMap<String, usize>,
+    shards: Vec<ShardBackends>,
+    info: ServerInfo,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    connections: AtomicUsize,
+}
+
+struct ShardBackends {
+    primary: Backend,
+    replicas: Vec<Backend>,
+    /// replica to read from next
+    next_replica: AtomicUsize,
+}
+
+/// Server behind the proxy.
+struct Backend {
+    addr: Addr,
+    pool: KvsClientPool,
+    /// until the first health check, assumed healthy
+    healthy: AtomicBool,
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Backend {
+    fn new(addr: &Addr, template: &Option<KvsClientBuilder>, options: PoolOptions) -> Result<Self> {
+        let builder = match template {
+            Some(template) => template.with_addr(addr),
+            None => KvsClient::builder(addr),
+        };
+        Ok(Self {
+            addr: addr.clone(),
+            pool: KvsClientPool::with_connect(options, move || builder.clone().build())?,
+            healthy: AtomicBool::new(true),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        })
+    }
+
+    /// Run `call` on a pooled connection to this server.
+    fn call<T>(&self, call: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let result = self.pool.get().and_then(|mut client| call(&mut client));
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn check_health(&self) {
+        let healthy = self.pool.get().and_then(|mut client| client.ping()).is_ok();
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                info!("Backend {} is up", self.addr);
+            } else {
+                warn!("Backend {} is down", self.addr);
+            }
+        }
+    }
+
+    fn stats(&self, shard: usize, replica: bool) -> BackendStats {
+        BackendStats {
+            addr: self.addr.to_string(),
+            shard,
+            replica,
+            healthy: self.healthy.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            connections: self.pool.connections(),
+        }
+    }
+}
+
+impl ShardBackends {
+    /// Server to read from: a healthy replica in turn if allowed, the primary
+    /// otherwise.
+    fn reader(&self, from_replicas: bool) -> &Backend {
+        if from_replicas && !self.replicas.is_empty() {
+            let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+            let healthy = (0..self.replicas.len())
+                .map(|i| &self.replicas[(start + i) % self.replicas.len()])
+                .find(|replica| replica.healthy.load(Ordering::Relaxed));
+            if let Some(replica) = healthy {
+                return replica;
+            }
+        }
+        &self.primary
+    }
+
+    /// Run the read `call` on a replica if allowed, and on the primary if the
+    /// replica fails.
+    fn read<T>(
+        &self,
+        from_replicas: bool,
+        call: impl Fn(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        let backend = self.reader(from_replicas);
+        match backend.call(&call) {
+            Err(e) if !std::ptr::eq(backend, &self.primary) => {
+                debug!(
+                    "Read from replica {} failed, use the primary: {e}",
+                    backend.addr
+                );
+                self.primary.call(call)
+            }
+            result => result,
+        }
+    }
+}
+
+impl KvsProxy {
+    /// Proxy of `shards`, connecting to them without authentication.
+    pub fn new(shards: Vec<Shard>, options: ProxyOptions) -> Result<Self> {
+        Self::with_template(shards, options, None)
+    }
+
+    /// Like `new`, with the connections to the servers configured as
+    /// `template`, whose address is ignored.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if `template` has credentials while the clients
+    /// don't need to authenticate.
+    pub fn with_template(
+        shards: Vec<Shard>,
+        options: ProxyOptions,
+        template: Option<KvsClientBuilder>,
+    ) -> Result<Self> {
+        if shards.is_empty() {
+            return Err(KvsError::StringError("A proxy needs a shard".to_owned()).into());
+        }
+        let has_credentials = template
+            .as_ref()
+            .is_some_and(KvsClientBuilder::has_credentials);
+        if has_credentials && options.credentials_file.is_none() {
+            return Err(KvsError::StringError(
+                "A proxy with credentials for the servers must authenticate its clients".to_owned(),
+            )
+            .into());
+        }
+        let auth = options
+            .credentials_file
+            .as_ref()
+            .map(Authenticator::load)
+            .transpose()?;
+        let acl = options.acl_file.as_ref().map(Acl::load).transpose()?;
+        let primaries: Vec<String> = shards.iter().map(|s| s.primary.to_string()).collect();
+        let indexes = primaries.iter().cloned().zip(0..).collect();
+        let backends = shards
+            .iter()
+            .map(|shard| {
+                Ok(ShardBackends {
+                    primary: Backend::new(&shard.primary, &template, options.pool.clone())?,
+                    replicas: shard
+                        .replicas
+                        .iter()
+                        .map(|addr| Backend::new(addr, &template, options.pool.clone()))
+                        .collect::<Result<_>>()?,
+                    next_replica: AtomicUsize::new(0),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                options,
+                auth,
+                acl,
+                map: ShardMap::new(primaries),
+                indexes,
+                shards: backends,
+                info: ServerInfo {
+                    name: "kvs-proxy".to_owned(),
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    id: Uuid::new_v4().to_string(),
+                },
+                requests: AtomicU64::new(0),
+                errors: AtomicU64::new(0),
+                connections: AtomicUsize::new(0),
+            }),
+            tls: None,
+        })
+    }
+
+    /// Serve every client over TLS with `config`.
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Statistics of the proxy.
+    pub fn stats(&self) -> ProxyStats {
+        self.shared.stats()
+    }
+
+    /// Run the proxy
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.run_with(TcpListener::bind(addr)?)
+    }
+
+    /// Run the proxy on a TCP or Unix domain socket address
+    pub fn run_addr(self, addr: &Addr) -> Result<()> {
+        self.run_with(addr.bind()?)
+    }
+
+    /// Run the proxy on the connections accepted from `listener`
+    pub fn run_with<L: Listener>(self, listener: L) -> Result<()> {
+        let shared = self.shared.clone();
+        thread::spawn(move || loop {
+            for shard in &shared.shards {
+                shard.primary.check_health();
+                shard.replicas.iter().for_each(Backend::check_health);
+            }
+            thread::sleep(shared.options.health_check_interval);
+        });
+
+        info!("Start proxy and listen on: {}", listener.local());
+        loop {
+            match listener.accept() {
+                Ok(stream) => {
+                    let shared = self.shared.clone();
+                    shared.connections.fetch_add(1, Ordering::SeqCst);
+                    let tls = self.tls.clone();
+                    thread::spawn(move || {
+                        let result = match tls {
+                            Some(tls) => TlsStream::accept(tls, stream)
+                                .and_then(|stream| serve(&shared, stream)),
+                            None => serve(&shared, stream),
+                        };
+                        if let Err(e) = result {
+                            error!("Serving client error: {e}");
+                        }
+                        shared.connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => error!("Connection Failed. {e}"),
+            }
+        }
+    }
+}
+
+/// Answer the requests of one client.
+fn serve<S: Stream>(shared: &Shared, stream: S) -> Result<()> {
+    let peer_addr = stream.peer();
+    // who the client proved to be, by TLS or by an `Auth` request
+    let mut identity = stream.identity();
+    if let Some(identity) = &identity {
+        info!("{} identified as {}", peer_addr, identity);
+    }
+    let mut stream = BufReader::new(stream);
+    let mut codec = Codec::default();
+    // protocol version of the connection, `None` until the first request
+    let mut version = None;
+
+    while let Some(frame) = codec.recv_request(&mut stream)? {
+        debug!("Request from {}: {:?}", peer_addr, frame.request);
+        let result = match frame.request {
+            Ok(Request::Hello {
+                min_version,
+                max_version,
+                features,
+                encodings,
+            }) => shared.hello(&mut version, min_version, max_version, features, encodings),
+            Ok(Request::Auth { credentials }) => {
+                shared.authenticate(&mut identity, &peer_addr, &credentials)
+            }
+            Ok(request) => {
+                if frame.version == *version.get_or_insert(MIN_PROTOCOL_VERSION) {
+                    shared.handle(identity.as_deref(), &peer_addr, request)
+                } else {
+                    Err(ErrorCode::UnsupportedVersion {
+                        supported: PROTOCOL_VERSION,
+                    })
+                }
+            }
+            Err(e) => Err(ErrorCode::InvalidRequest(e)),
+        };
+        let switch_to = match &result {
+            Ok(Reply::Welcome(capabilities)) if capabilities.version >= FRAMED_VERSION => {
+                Some(capabilities.encoding)
+            }
+            _ => None,
+        };
+        codec.send(
+            stream.get_mut(),
+            &Response {
+                id: frame.id,
+                result,
+            },
+        )?;
+        if let Some(encoding) = switch_to {
+            codec.switch_to(encoding);
+        }
+    }
+    Ok(())
+}
+
+impl Shared {
+    /// Agree on the protocol version and the features of the connection.
+    fn hello(
+        &self,
+        version: &mut Option<u32>,
+        min_version: u32,
+        max_version: u32,
+        features: Vec<Feature>,
+        encodings: Vec<Encoding>,
+    ) -> std::result::Result<Reply, ErrorCode> {
+        if version.is_some() {
+            return Err(ErrorCode::InvalidRequest(
+                "Hello must be the first request".to_owned(),
+            ));
+        }
+        let agreed = max_version.min(PROTOCOL_VERSION);
+        if agreed < min_version || agreed < MIN_PROTOCOL_VERSION {
+            return Err(ErrorCode::UnsupportedVersion {
+                supported: PROTOCOL_VERSION,
+            });
+        }
+        *version = Some(agreed);
+        let offered = [Feature::Batch, Feature::Scan, Feature::Auth];
+        Ok(Reply::Welcome(Capabilities {
+            version: agreed,
+            features: features
+                .into_iter()
+                .filter(|feature| offered.contains(feature))
+                .collect(),
+            server: Some(self.info.clone()),
+            encoding: encodings.first().copied().unwrap_or_default(),
+        }))
+    }
+
+    fn authenticate(
+        &self,
+        identity: &mut Option<String>,
+        peer_addr: &str,
+        credentials: &Credentials,
+    ) -> std::result::Result<Reply, ErrorCode> {
+        let Some(auth) = &self.auth else {
+            return Err(ErrorCode::InvalidRequest(
+                "Authentication is not enabled".to_owned(),
+            ));
+        };
+        match auth.authenticate(credentials) {
+            Some(name) => {
+                info!("{} authenticated as {}", peer_addr, name);
+                *identity = Some(name.clone());
+                Ok(Reply::Identity(name))
+            }
+            None => {
+                warn!("{} failed to authenticate: {:?}", peer_addr, credentials);
+                Err(ErrorCode::AuthFailed)
+            }
+        }
+    }
+
+    /// Check one request of the client `identity` and forward it, counting it.
+    fn handle(
+        &self,
+        identity: Option<&str>,
+        peer_addr: &str,
+        request: Request,
+    ) -> std::result::Result<Reply, ErrorCode> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let result = self
+            .authorize(identity, peer_addr, &request)
+            .and_then(|user| self.forward(user, request).map_err(|e| ErrorCode::from(&e)));
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Check that the client `identity` may run `request`, and return the
+    /// user whose ACL applies.
+    fn authorize<'a>(
+        &self,
+        identity: Option<&'a str>,
+        peer_addr: &str,
+        request: &Request,
+    ) -> std::result::Result<&'a str, ErrorCode> {
+        let user = identity.unwrap_or(ANONYMOUS);
+        // answered before authentication, to check the connection
+        if matches!(request, Request::Ping) {
+            return Ok(user);
+        }
+        if identity.is_none() && self.auth.is_some() {
+            return Err(ErrorCode::AuthRequired);
+        }
+        if let Some((op, false)) = self.acl.as_ref().and_then(|acl| acl.check(user, request)) {
+            warn!("Deny {:?} to {} from {}", op, user, peer_addr);
+            return Err(ErrorCode::PermissionDenied(format!(
+                "{user} may not {op:?}"
+            )));
+        }
+        Ok(user)
+    }
+
+    /// Whether `user` may see `key` in a scan.
+    fn scannable(&self, user: &str, key: &str) -> bool {
+        self.acl
+            .as_ref()
+            .map_or(true, |acl| acl.allows(user, Op::Scan, key))
+    }
+
+    fn forward(&self, user: &str, request: Request) -> Result<Reply> {
+        let from_replicas = self.options.read_from_replicas;
+        match request {
+            Request::Ping => Ok(Reply::Done),
+            Request::ProxyStats => Ok(Reply::ProxyStats(self.stats())),
+            Request::Get { key } => self
+                .shard(&key)
+                .read(from_replicas, |client| client.get(key.clone()))
+                .map(Reply::Value),
+            Request::Scan { prefix } => {
+                let mut pairs = vec![];
+                for shard in &self.shards {
+                    pairs.extend(shard.read(from_replicas, |client| client.scan(prefix.clone()))?);
+                }
+                // only list the keys this user may scan
+                pairs.retain(|(key, _)| self.scannable(user, key));
+                pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+                Ok(Reply::Pairs(pairs))
+            }
+            Request::ScanPage {
+                prefix,
+                start_after,
+                limit,
+            } => {
+                let mut pages = vec![];
+                for shard in &self.shards {
+                    pages.push(shard.read(from_replicas, |client| {
+                        client.scan_page(prefix.clone(), start_after.clone(), limit)
+                    })?);
+                }
+                let mut page = Page::merge(pages, limit as usize);
+                page.pairs.retain(|(key, _)| self.scannable(user, key));
+                Ok(Reply::Page(page))
+            }
+            Request::MGet { keys } => {
+                let mut values = vec![None; keys.len()];
+                for (shard, positions) in self.group(keys.iter()) {
+                    let shard_keys: Vec<String> =
+                        positions.iter().map(|i| keys[*i].clone()).collect();
+                    let shard_values = self.shards[shard]
+                        .read(from_replicas, |client| client.mget(shard_keys.clone()))?;
+                    for (i, value) in positions.into_iter().zip(shard_values) {
+                        values[i] = value;
+                    }
+                }
+                Ok(Reply::Values(values))
+            }
+            Request::MSet { pairs } => {
+                for (shard, positions) in self.group(pairs.iter().map(|(key, _)| key)) {
+                    let shard_pairs = positions.iter().map(|i| pairs[*i].clone()).collect();
+                    self.shards[shard]
+                        .primary
+                        .call(|client| client.mset(shard_pairs))?;
+                }
+                Ok(Reply::Done)
+            }
+            Request::Set { key, value } => self
+                .shard(&key)
+                .primary
+                .call(|client| client.set(key.clone(), value))
+                .map(|()| Reply::Done),
+            Request::Remove { key } => self
+                .shard(&key)
+                .primary
+                .call(|client| client.remove(key.clone()))
+                .map(|()| Reply::Done),
+            Request::CompareAndSet {
+                key,
+                expected,
+                value,
+            } => self
+                .shard(&key)
+                .primary
+                .call(|client| client.compare_and_set(key.clone(), expected, value))
+                .map(Reply::Swapped),
+            Request::Auth { .. } | Request::Hello { .. } => unreachable!(),
+            Request::Replicate { .. }
+            | Request::Watch { .. }
+            | Request::Publish { .. }
+            | Request::Subscribe { .. }
+            | Request::PSubscribe { .. }
+            | Request::Cdc { .. }
+            | Request::Backup { .. }
+            | Request::ReplicationStatus
+            | Request::Raft(_)
+            | Request::ClusterStatus
+            | Request::AddMember { .. }
+            | Request::RemoveMember { .. } => {
+                Err(KvsError::InvalidRequest("Not supported by the proxy".to_owned()).into())
+            }
+        }
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        self.indexes[self.map.owner(key)]
+    }
+
+    fn shard(&self, key: &str) -> &ShardBackends {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// Positions of `keys`, by the shard owning them.
+    fn group<'a>(&self, keys: impl Iterator<Item = &'a String>) -> HashMap<usize, Vec<usize>> {
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, key) in keys.enumerate() {
+            groups.entry(self.shard_index(key)).or_default().push(i);
+        }
+        groups
+    }
+
+    fn stats(&self) -> ProxyStats {
+        let mut backends = vec![];
+        for (i, shard) in self.shards.iter().enumerate() {
+            backends.push(shard.primary.stats(i, false));
+            backends.extend(shard.replicas.iter().map(|replica| replica.stats(i, true)));
+        }
+        ProxyStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::SeqCst),
+            backends,
+        }
+    }
+}
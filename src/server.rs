@@ -1,84 +1,908 @@
 //! Use `KvsEngine` object to perform the server functionality
 
-use serde_json::Deserializer;
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    fs,
+    io::BufReader,
+    net::{TcpListener, ToSocketAddrs},
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use log::{error, info};
+use log::{debug, error, info, warn};
+use uuid::Uuid;
 
-use crate::transport::{Request, ResponseGet, ResponseRemove, ResponseSet};
-use crate::KvsEngine;
-use crate::Result;
+use crate::acl::{Acl, Op, ANONYMOUS};
+use crate::auth::{Authenticator, Credentials};
+use crate::backup::write_backup;
+use crate::client::KvsClientBuilder;
+use crate::codec::{Codec, Encoding, FRAMED_VERSION};
+use crate::config::RuntimeConfig;
+use crate::net::{Addr, AnyListener, Listener, Stream};
+use crate::tls::TlsStream;
+use crate::transport::{
+    Capabilities, ErrorCode, Feature, Reply, Request, Response, ServerInfo, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::{KvsEngine, KvsError, Result};
+
+mod cdc;
+mod http;
+mod memcache;
+mod pubsub;
+mod raft;
+mod replication;
+mod resp;
+mod watch;
+
+use pubsub::Broker;
+pub use raft::ClusterConfig;
+use raft::Raft;
+use replication::{ChangeLog, Follower};
 
 /// Struct for server object
 pub struct KvsServer<E: KvsEngine> {
-    engine: E,
+    shared: Arc<Shared<E>>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Listeners of the other protocols, served beside the native one
+    listeners: Vec<(Protocol, AnyListener)>,
+    /// leader to replicate, `None` unless this server is a replica
+    leader: Option<KvsClientBuilder>,
+}
+
+/// Protocol spoken by the clients of a listener.
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    Native,
+    Resp,
+    Http,
+    Memcache,
+}
+
+/// State shared by every connection of a server.
+struct Shared<E: KvsEngine> {
+    engine: Mutex<E>,
+    config: RwLock<RuntimeConfig>,
+    /// `None` if clients don't need to authenticate
+    auth: RwLock<Option<Authenticator>>,
+    /// `None` if every operation is allowed
+    acl: RwLock<Option<Acl>>,
+    connections: AtomicUsize,
+    info: ServerInfo,
+    /// recent writes, for the replicas
+    changes: ChangeLog,
+    /// replicas streaming from this server
+    replicas: AtomicUsize,
+    /// set if this server is a replica
+    follower: OnceLock<Follower>,
+    /// set if this server is a cluster node
+    raft: OnceLock<Raft>,
+    /// pub/sub subscribers
+    pubsub: Broker,
+    /// held by the memcached commands which depend on the current item
+    memcache_writes: Mutex<()>,
+}
+
+impl<E: KvsEngine> Shared<E> {
+    /// Features this server offers in the handshake.
+    ///
+    /// `Auth` is offered even while authentication is disabled, since a
+    /// reload may enable it while the connection is open.
+    fn features(&self) -> Vec<Feature> {
+        vec![Feature::Batch, Feature::Scan, Feature::Auth]
+    }
+
+    /// Send `message` to the subscribers of `channel`, and return how many
+    /// got it.
+    fn publish(&self, channel: &str, message: &str) -> usize {
+        let (buffer, drop) = {
+            let config = self.config.read().unwrap();
+            (config.pubsub_buffer, config.pubsub_drop)
+        };
+        self.pubsub.publish(channel, message, buffer, drop)
+    }
+
+    /// Run a write of the server itself the way the writes of the clients
+    /// run: rejected by a replica and through the log on a cluster node.
+    fn write(&self, request: &Request) -> std::result::Result<Reply, ErrorCode> {
+        if let Some(follower) = self.follower.get() {
+            return Err(ErrorCode::ReadOnly {
+                leader: follower.leader.clone(),
+            });
+        }
+        if let Some(raft) = self.raft.get() {
+            return raft.write(self, request);
+        }
+        let mut engine = self.engine.lock().unwrap();
+        let reply = raft::write(&mut *engine, request).map_err(|e| ErrorCode::from(&e))?;
+        self.changes.record(request, &reply);
+        Ok(reply)
+    }
+}
+
+/// Handle to change the runtime settings of a running `KvsServer`.
+pub struct ServerHandle<E: KvsEngine> {
+    shared: Arc<Shared<E>>,
+}
+
+impl<E: KvsEngine> Clone for ServerHandle<E> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
 }
 
-impl<E: KvsEngine> KvsServer<E> {
+impl<E: KvsEngine> ServerHandle<E> {
+    /// Apply `config` to the server. New settings take effect on the next request.
+    ///
+    /// Nothing changes if a file referred by `config` cannot be loaded.
+    pub fn reload(&self, config: RuntimeConfig) -> Result<()> {
+        let auth = config
+            .credentials_file
+            .as_ref()
+            .map(Authenticator::load)
+            .transpose()?;
+        let acl = config.acl_file.as_ref().map(Acl::load).transpose()?;
+
+        log::set_max_level(config.log_level);
+        {
+            let mut engine = self.shared.engine.lock().unwrap();
+            if let Some(threshold) = config.compaction_threshold {
+                engine.set_compaction_threshold(threshold);
+            }
+            if let Some(changes) = config.cdc_retention {
+                engine.set_cdc_retention(changes);
+            }
+        }
+        *self.shared.auth.write().unwrap() = auth;
+        *self.shared.acl.write().unwrap() = acl;
+        *self.shared.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// The runtime settings currently in use.
+    pub fn config(&self) -> RuntimeConfig {
+        self.shared.config.read().unwrap().clone()
+    }
+}
+
+impl<E: KvsEngine + Send + 'static> KvsServer<E> {
     /// Create a server object with `KvsEngine`
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        Self {
+            shared: Arc::new(Shared {
+                engine: Mutex::new(engine),
+                config: RwLock::new(RuntimeConfig::default()),
+                auth: RwLock::new(None),
+                acl: RwLock::new(None),
+                connections: AtomicUsize::new(0),
+                info: ServerInfo {
+                    name: "kvs-server".to_owned(),
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    id: Uuid::new_v4().to_string(),
+                },
+                changes: ChangeLog::new(),
+                replicas: AtomicUsize::new(0),
+                follower: OnceLock::new(),
+                raft: OnceLock::new(),
+                pubsub: Broker::new(),
+                memcache_writes: Mutex::new(()),
+            }),
+            tls: None,
+            listeners: vec![],
+            leader: None,
+        }
+    }
+
+    /// Create a server object with `KvsEngine` and the given runtime settings
+    pub fn with_config(engine: E, config: RuntimeConfig) -> Result<Self> {
+        let server = Self::new(engine);
+        server.handle().reload(config)?;
+        Ok(server)
+    }
+
+    /// Serve every connection over TLS with `config`.
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Also serve Redis clients (RESP2 and RESP3) on `listener`.
+    pub fn with_resp(mut self, listener: AnyListener) -> Self {
+        self.listeners.push((Protocol::Resp, listener));
+        self
+    }
+
+    /// Also serve the HTTP/JSON gateway on `listener`.
+    pub fn with_http(mut self, listener: AnyListener) -> Self {
+        self.listeners.push((Protocol::Http, listener));
+        self
+    }
+
+    /// Also serve memcached clients (text protocol) on `listener`.
+    pub fn with_memcache(mut self, listener: AnyListener) -> Self {
+        self.listeners.push((Protocol::Memcache, listener));
+        self
+    }
+
+    /// Replicate the server `leader` connects to: copy its data, then apply
+    /// its changes, and reject writes.
+    pub fn replica_of(mut self, leader: KvsClientBuilder) -> Self {
+        let _ = self
+            .shared
+            .follower
+            .set(Follower::new(leader.addr().to_string()));
+        self.leader = Some(leader);
+        self
+    }
+
+    /// Run as a node of a Raft cluster: the writes go through the replicated
+    /// log, and only the leader answers the clients.
+    ///
+    /// The state of the node is loaded from `config.dir`.
+    pub fn cluster(self, config: ClusterConfig) -> Result<Self> {
+        let _ = self.shared.raft.set(Raft::open(config)?);
+        Ok(self)
+    }
+
+    /// Get a handle to reload the settings once the server is running.
+    pub fn handle(&self) -> ServerHandle<E> {
+        ServerHandle {
+            shared: self.shared.clone(),
+        }
     }
 
     /// Run this server object
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(&addr)?;
-        info!(
-            "Start server and listen on: {}",
-            addr.to_socket_addrs().unwrap().next().unwrap()
-        );
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.run_with(TcpListener::bind(addr)?)
+    }
+
+    /// Run this server object on a TCP or Unix domain socket address
+    pub fn run_addr(self, addr: &Addr) -> Result<()> {
+        self.run_with(addr.bind()?)
+    }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.serve(stream) {
+    /// Run this server object on the connections accepted from `listener`
+    pub fn run_with<L: Listener>(self, listener: L) -> Result<()> {
+        // a replica gets the removals of expired items from its leader
+        let sweep = self.leader.is_none()
+            && self
+                .listeners
+                .iter()
+                .any(|(protocol, _)| matches!(protocol, Protocol::Memcache));
+        if let Some(leader) = self.leader {
+            let shared = self.shared.clone();
+            thread::spawn(move || replication::follow(&shared, leader));
+        }
+        if self.shared.raft.get().is_some() {
+            let shared = self.shared.clone();
+            thread::spawn(move || raft::run(shared));
+        }
+        if sweep {
+            let shared = self.shared.clone();
+            thread::spawn(move || memcache::sweep(&shared));
+        }
+        for (protocol, listener) in self.listeners {
+            let shared = self.shared.clone();
+            let tls = self.tls.clone();
+            thread::spawn(move || accept(shared, tls, protocol, listener));
+        }
+        accept(self.shared, self.tls, Protocol::Native, listener)
+    }
+}
+
+/// Serve the connections accepted from `listener`, each on its own thread.
+fn accept<E: KvsEngine + Send + 'static, L: Listener>(
+    shared: Arc<Shared<E>>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    protocol: Protocol,
+    listener: L,
+) -> Result<()> {
+    match protocol {
+        Protocol::Native => info!("Start server and listen on: {}", listener.local()),
+        _ => info!("Listen for {:?} clients on: {}", protocol, listener.local()),
+    }
+
+    loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let shared = shared.clone();
+                let max_connections = shared.config.read().unwrap().max_connections;
+                let active = shared.connections.load(Ordering::SeqCst);
+                if max_connections.is_some_and(|max| active >= max) {
+                    warn!("Too many connections ({active}), reject {}", stream.peer());
+                    let tls = tls.clone();
+                    thread::spawn(move || {
+                        let code = ErrorCode::Internal("Too many connections".to_owned());
+                        let result = match tls {
+                            Some(tls) => TlsStream::accept(tls, stream)
+                                .and_then(|stream| reject(protocol, stream, code)),
+                            None => reject(protocol, stream, code),
+                        };
+                        if let Err(e) = result {
+                            debug!("Rejecting client error: {e}");
+                        }
+                    });
+                    continue;
+                }
+                shared.connections.fetch_add(1, Ordering::SeqCst);
+
+                let tls = tls.clone();
+                thread::spawn(move || {
+                    let result = match tls {
+                        Some(tls) => TlsStream::accept(tls, stream)
+                            .and_then(|stream| serve_protocol(protocol, &shared, stream)),
+                        None => serve_protocol(protocol, &shared, stream),
+                    };
+                    if let Err(e) = result {
                         error!("Serving client error: {e}");
                     }
+                    shared.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => error!("Connection Failed. {e}"),
+        }
+    }
+}
+
+fn serve_protocol<E: KvsEngine, S: Stream>(
+    protocol: Protocol,
+    shared: &Shared<E>,
+    stream: S,
+) -> Result<()> {
+    match protocol {
+        Protocol::Native => serve(shared, stream),
+        Protocol::Resp => resp::serve(shared, stream),
+        Protocol::Http => http::serve(shared, stream),
+        Protocol::Memcache => memcache::serve(shared, stream),
+    }
+}
+
+/// Tell a client why its connection is closed right away.
+fn reject<S: Stream>(protocol: Protocol, mut stream: S, code: ErrorCode) -> Result<()> {
+    match protocol {
+        // id 0 answers no request, but the connection
+        Protocol::Native => Codec::default().send(
+            &mut stream,
+            &Response {
+                id: 0,
+                result: Err(code),
+            },
+        ),
+        Protocol::Resp => resp::reject(stream, code),
+        Protocol::Http => http::reject(stream, code),
+        Protocol::Memcache => memcache::reject(stream, code),
+    }
+}
+
+/// Most bytes of responses held back while pipelined requests are read.
+const MAX_HELD_RESPONSES: usize = 64 << 10;
+
+/// Private server functionality
+fn serve<E: KvsEngine, S: Stream>(shared: &Shared<E>, stream: S) -> Result<()> {
+    let mut session = Session::new(shared, &stream);
+    let mut stream = BufReader::new(stream);
+    let mut codec = Codec::default();
+    let mut responses = vec![];
+
+    // Get/Parse the request
+    while let Some(frame) = codec.recv_request(&mut stream)? {
+        info!("Got request from {}", session.peer_addr);
+        debug!("Request from {}: {:?}", session.peer_addr, frame.request);
+
+        // Execute the command and Send the response
+        let result = match frame.request {
+            Ok(Request::Hello {
+                min_version,
+                max_version,
+                features,
+                encodings,
+            }) => session.hello(min_version, max_version, features, encodings),
+            Ok(Request::Replicate { from }) => {
+                match session
+                    .check_version(frame.version)
+                    .and_then(|()| session.authorize_admin())
+                {
+                    Ok(()) => {
+                        info!("Start replication to {}", session.peer_addr);
+                        let stream = stream.get_mut();
+                        stream.write_all(&responses)?;
+                        return replication::stream(shared, stream, &codec, frame.id, from);
+                    }
+                    Err(code) => Err(code),
                 }
-                Err(e) => error!("Connection Failed. {e}"),
             }
+            Ok(Request::Watch { key_or_prefix }) => {
+                match session
+                    .check_version(frame.version)
+                    .and_then(|()| session.authorize_stream(Op::Get, &[&key_or_prefix]))
+                {
+                    Ok(user) => {
+                        info!("{} watches {:?}", session.peer_addr, key_or_prefix);
+                        let stream = stream.get_mut();
+                        stream.write_all(&responses)?;
+                        return watch::stream(
+                            shared,
+                            stream,
+                            &codec,
+                            frame.id,
+                            &key_or_prefix,
+                            &user,
+                        );
+                    }
+                    Err(code) => Err(code),
+                }
+            }
+            Ok(Request::Cdc { from }) => {
+                match session
+                    .check_version(frame.version)
+                    .and_then(|()| session.authorize_stream(Op::Get, &[]))
+                {
+                    Ok(user) => {
+                        info!("{} reads the changes after {}", session.peer_addr, from);
+                        let stream = stream.get_mut();
+                        stream.write_all(&responses)?;
+                        return cdc::stream(shared, stream, &codec, frame.id, from, &user);
+                    }
+                    Err(code) => Err(code),
+                }
+            }
+            Ok(request @ (Request::Subscribe { .. } | Request::PSubscribe { .. })) => {
+                let (channels, patterns) = match request {
+                    Request::Subscribe { channels } => (channels, vec![]),
+                    Request::PSubscribe { patterns } => (vec![], patterns),
+                    _ => unreachable!(),
+                };
+                let names: Vec<&str> = channels
+                    .iter()
+                    .chain(&patterns)
+                    .map(String::as_str)
+                    .collect();
+                match session
+                    .check_version(frame.version)
+                    .and_then(|()| session.authorize_stream(Op::Subscribe, &names))
+                {
+                    Ok(_) => {
+                        info!("{} subscribes to {:?}", session.peer_addr, names);
+                        let stream = stream.get_mut();
+                        stream.write_all(&responses)?;
+                        return pubsub::stream(
+                            shared, stream, &codec, frame.id, channels, patterns,
+                        );
+                    }
+                    Err(code) => Err(code),
+                }
+            }
+            Ok(request) => session
+                .check_version(frame.version)
+                .and_then(|()| session.handle(&request)),
+            Err(e) => Err(ErrorCode::InvalidRequest(e)),
+        };
+        let switch_to = match &result {
+            Ok(Reply::Welcome(capabilities)) if capabilities.version >= FRAMED_VERSION => {
+                Some(capabilities.encoding)
+            }
+            _ => None,
+        };
+        codec.encode(
+            &mut responses,
+            &Response {
+                id: frame.id,
+                result,
+            },
+        )?;
+
+        // Hold the responses of pipelined requests while more are already
+        // buffered, and write them together.
+        let pipelined = codec.is_framed() && !stream.buffer().is_empty();
+        if !pipelined || responses.len() >= MAX_HELD_RESPONSES {
+            let stream = stream.get_mut();
+            stream.write_all(&responses)?;
+            stream.flush()?;
+            responses.clear();
+        }
+        if let Some(encoding) = switch_to {
+            codec.switch_to(encoding);
+        }
+    }
+    Ok(())
+}
+
+/// Per-connection state of the server.
+struct Session<'a, E: KvsEngine> {
+    shared: &'a Shared<E>,
+    peer_addr: String,
+    /// Who the peer proved to be, by TLS or by an `Auth` request
+    identity: Option<String>,
+    /// Protocol version of the connection, `None` until the first request
+    version: Option<u32>,
+    limiter: RateLimiter,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    fn new<S: Stream>(shared: &'a Shared<E>, stream: &S) -> Self {
+        let peer_addr = stream.peer();
+        let identity = stream.identity();
+        if let Some(identity) = &identity {
+            info!("{} identified as {}", peer_addr, identity);
+        }
+        Self {
+            shared,
+            peer_addr,
+            identity,
+            version: None,
+            limiter: RateLimiter::new(),
         }
-        Ok(())
     }
 
-    /// Private server functionality
-    fn serve(&mut self, tcp: TcpStream) -> Result<()> {
-        // Get/Parse the request
-        let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
-        let mut writer = BufWriter::new(&tcp);
-        let serde_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    /// Agree on the protocol version and the features of the connection.
+    fn hello(
+        &mut self,
+        min_version: u32,
+        max_version: u32,
+        features: Vec<Feature>,
+        encodings: Vec<Encoding>,
+    ) -> std::result::Result<Reply, ErrorCode> {
+        if self.version.is_some() {
+            return Err(ErrorCode::InvalidRequest(
+                "Hello must be the first request".to_owned(),
+            ));
+        }
+        let version = max_version.min(PROTOCOL_VERSION);
+        if version < min_version || version < MIN_PROTOCOL_VERSION {
+            warn!(
+                "{} speaks protocol versions {}..={}",
+                self.peer_addr, min_version, max_version
+            );
+            return Err(ErrorCode::UnsupportedVersion {
+                supported: PROTOCOL_VERSION,
+            });
+        }
+        self.version = Some(version);
 
-        macro_rules! send_response {
-            ($response:expr) => {{
-                let response = $response;
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-            }};
+        let offered = self.shared.features();
+        let features: Vec<Feature> = features
+            .into_iter()
+            .filter(|feature| offered.contains(feature))
+            .collect();
+        // every encoding is supported, take the favorite of the client
+        let encoding = encodings.first().copied().unwrap_or_default();
+        info!(
+            "{} speaks protocol version {} in {:?} with {:?}",
+            self.peer_addr, version, encoding, features
+        );
+        Ok(Reply::Welcome(Capabilities {
+            version,
+            features,
+            server: Some(self.shared.info.clone()),
+            encoding,
+        }))
+    }
+
+    /// Check `version` against the version of the connection. A connection
+    /// without a handshake speaks version 1.
+    fn check_version(&mut self, version: u32) -> std::result::Result<(), ErrorCode> {
+        if version == *self.version.get_or_insert(MIN_PROTOCOL_VERSION) {
+            Ok(())
+        } else {
+            Err(ErrorCode::UnsupportedVersion {
+                supported: PROTOCOL_VERSION,
+            })
         }
+    }
 
-        for request in serde_reader {
-            // Execute the command and Send the response
-            let request = request?;
-            info!("Got request from {}", peer_addr);
+    /// Check and run one request.
+    fn handle(&mut self, request: &Request) -> std::result::Result<Reply, ErrorCode> {
+        let RuntimeConfig {
+            rate_limit,
+            slow_log_threshold_ms,
+            ..
+        } = *self.shared.config.read().unwrap();
+        // the messages between cluster nodes keep the cluster alive
+        if !matches!(request, Request::Raft(_)) {
+            self.limiter.acquire(rate_limit);
+        }
+
+        match request {
+            Request::Auth { credentials } => return self.authenticate(credentials),
+            // answered before authentication, to check the connection
+            Request::Ping => return Ok(Reply::Done),
+            Request::Replicate { .. } => {
+                return Err(ErrorCode::InvalidRequest(
+                    "Replication needs a native connection".to_owned(),
+                ))
+            }
+            Request::Watch { .. } => {
+                return Err(ErrorCode::InvalidRequest(
+                    "Watching needs a native connection".to_owned(),
+                ))
+            }
+            Request::Cdc { .. } => {
+                return Err(ErrorCode::InvalidRequest(
+                    "Change data capture needs a native connection".to_owned(),
+                ))
+            }
+            Request::Subscribe { .. } | Request::PSubscribe { .. } => {
+                return Err(ErrorCode::InvalidRequest(
+                    "Subscribing needs a native or RESP connection".to_owned(),
+                ))
+            }
+            _ => {}
+        }
+        if self.needs_auth() {
+            return Err(ErrorCode::AuthRequired);
+        }
+
+        let start = Instant::now();
+        let result = self.execute(request);
+        let elapsed = start.elapsed();
+        if slow_log_threshold_ms.is_some_and(|ms| elapsed >= Duration::from_millis(ms)) {
+            warn!(
+                "Slow request from {}: {:?} took {}ms",
+                self.peer_addr,
+                request,
+                elapsed.as_millis()
+            );
+        }
+        result
+    }
+
+    /// Whether the connection must authenticate before any other request.
+    fn needs_auth(&self) -> bool {
+        self.identity.is_none() && self.shared.auth.read().unwrap().is_some()
+    }
+
+    /// Check that the connection may run administrative requests.
+    fn authorize_admin(&self) -> std::result::Result<(), ErrorCode> {
+        if self.needs_auth() {
+            return Err(ErrorCode::AuthRequired);
+        }
+        let user = self.identity.as_deref().unwrap_or(ANONYMOUS);
+        match &*self.shared.acl.read().unwrap() {
+            Some(acl) if !acl.allows_any(user, Op::Admin) => {
+                warn!("Deny {:?} to {} from {}", Op::Admin, user, self.peer_addr);
+                Err(ErrorCode::PermissionDenied(format!("{user} may not Admin")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the connection may run `op` on every one of `names`
+    /// before turning into a stream, and return the user whose ACL filters
+    /// the stream.
+    fn authorize_stream(&self, op: Op, names: &[&str]) -> std::result::Result<String, ErrorCode> {
+        if self.needs_auth() {
+            return Err(ErrorCode::AuthRequired);
+        }
+        let user = self.identity.as_deref().unwrap_or(ANONYMOUS);
+        match &*self.shared.acl.read().unwrap() {
+            Some(acl) if !names.iter().all(|name| acl.allows(user, op, name)) => {
+                warn!("Deny {:?} to {} from {}", op, user, self.peer_addr);
+                Err(ErrorCode::PermissionDenied(format!(
+                    "{user} may not {op:?}"
+                )))
+            }
+            _ => Ok(user.to_owned()),
+        }
+    }
+
+    fn authenticate(&mut self, credentials: &Credentials) -> std::result::Result<Reply, ErrorCode> {
+        let result = match &*self.shared.auth.read().unwrap() {
+            Some(auth) => auth.authenticate(credentials).ok_or(ErrorCode::AuthFailed),
+            None => Err(ErrorCode::InvalidRequest(
+                "Authentication is not enabled".to_owned(),
+            )),
+        };
+        match result {
+            Ok(name) => {
+                info!("{} authenticated as {}", self.peer_addr, name);
+                self.identity = Some(name.clone());
+                Ok(Reply::Identity(name))
+            }
+            Err(e) => {
+                warn!(
+                    "{} failed to authenticate: {:?}",
+                    self.peer_addr, credentials
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a backup to `path` within the backup directory. It is refused
+    /// unless clients authenticate, on top of the ACL asking for `Admin`.
+    fn backup(&self, path: &str) -> std::result::Result<Reply, ErrorCode> {
+        if self.shared.auth.read().unwrap().is_none() {
+            return Err(ErrorCode::PermissionDenied(
+                "Backups need authentication to be enabled".to_owned(),
+            ));
+        }
+        let Some(dir) = self.shared.config.read().unwrap().backup_dir.clone() else {
+            return Err(ErrorCode::InvalidRequest(
+                "No backup_dir is configured".to_owned(),
+            ));
+        };
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(ErrorCode::InvalidRequest(format!(
+                "Backup path {path:?} must be relative to backup_dir, without `..`"
+            )));
+        }
+        let path = dir.join(relative);
+
+        // only freezing the data holds the engine, not writing it out
+        let snapshot = self.shared.engine.lock().unwrap().snapshot();
+        info!("{} backs up to {}", self.peer_addr, path.display());
+        snapshot
+            .and_then(|snapshot| {
+                fs::create_dir_all(&dir)?;
+                write_backup(snapshot, &path)
+            })
+            .map(|()| Reply::Done)
+            .map_err(|e| ErrorCode::from(&e))
+    }
+
+    /// Run an authenticated request on the engine, within the ACL.
+    fn execute(&self, request: &Request) -> std::result::Result<Reply, ErrorCode> {
+        let user = self.identity.as_deref().unwrap_or(ANONYMOUS);
+        let acl = self.shared.acl.read().unwrap();
+        if let Some((op, false)) = acl.as_ref().and_then(|acl| acl.check(user, request)) {
+            warn!("Deny {:?} to {} from {}", op, user, self.peer_addr);
+            return Err(ErrorCode::PermissionDenied(format!(
+                "{user} may not {op:?}"
+            )));
+        }
+
+        if let Some(follower) = self.shared.follower.get() {
+            if matches!(
+                request,
+                Request::Set { .. }
+                    | Request::Remove { .. }
+                    | Request::MSet { .. }
+                    | Request::CompareAndSet { .. }
+            ) {
+                return Err(ErrorCode::ReadOnly {
+                    leader: follower.leader.clone(),
+                });
+            }
+        }
+
+        if let Some(raft) = self.shared.raft.get() {
             match request {
-                Request::Get { key } => send_response!(match self.engine.get(key) {
-                    Ok(value) => ResponseGet::Ok(value),
-                    Err(e) => ResponseGet::Err(format!("{e}")),
-                }),
-                Request::Set { key, value } => send_response!(match self.engine.set(key, value) {
-                    Ok(()) => ResponseSet::Ok(()),
-                    Err(e) => ResponseSet::Err(format!("{e}")),
-                }),
-                Request::Remove { key } => send_response!(match self.engine.remove(key) {
-                    Ok(()) => ResponseRemove::Ok(()),
-                    Err(e) => ResponseRemove::Err(format!("{e}")),
+                Request::Set { .. }
+                | Request::Remove { .. }
+                | Request::MSet { .. }
+                | Request::CompareAndSet { .. } => return raft.write(self.shared, request),
+                Request::Get { .. }
+                | Request::Scan { .. }
+                | Request::ScanPage { .. }
+                | Request::MGet { .. } => raft.read_barrier()?,
+                Request::Raft(message) => {
+                    return raft
+                        .handle(self.shared, message.clone())
+                        .map(Reply::Raft)
+                        .map_err(|e| ErrorCode::from(&e))
+                }
+                Request::ClusterStatus => return Ok(Reply::ClusterStatus(raft.status())),
+                Request::AddMember { addr } => return raft.change_members(self.shared, addr, true),
+                Request::RemoveMember { addr } => {
+                    return raft.change_members(self.shared, addr, false)
+                }
+                _ => {}
+            }
+        }
+
+        if let Request::Publish { channel, message } = request {
+            return Ok(Reply::Receivers(self.shared.publish(channel, message)));
+        }
+        if let Request::Backup { path } = request {
+            return self.backup(path);
+        }
+
+        let mut engine = self.shared.engine.lock().unwrap();
+        let result = match request {
+            Request::Get { key } => engine.get(key.to_owned()).map(Reply::Value),
+            Request::Set { key, value } => engine
+                .set(key.to_owned(), value.to_owned())
+                .map(|()| Reply::Done),
+            Request::Remove { key } => engine.remove(key.to_owned()).map(|()| Reply::Done),
+            // only list the keys this user may scan, without memcached metadata
+            Request::Scan { prefix } => engine.scan(prefix.to_owned()).map(|pairs| {
+                Reply::Pairs(
+                    pairs
+                        .into_iter()
+                        .filter(|(key, _)| scannable(acl.as_ref(), user, key))
+                        .collect(),
+                )
+            }),
+            Request::ScanPage {
+                prefix,
+                start_after,
+                limit,
+            } => engine
+                .scan_page(prefix.to_owned(), start_after.to_owned(), *limit as usize)
+                .map(|mut page| {
+                    page.pairs
+                        .retain(|(key, _)| scannable(acl.as_ref(), user, key));
+                    Reply::Page(page)
                 }),
+            Request::MGet { keys } => engine.mget(keys.to_owned()).map(Reply::Values),
+            Request::MSet { pairs } => engine.mset(pairs.to_owned()).map(|()| Reply::Done),
+            Request::CompareAndSet {
+                key,
+                expected,
+                value,
+            } => engine
+                .compare_and_set(key.to_owned(), expected.to_owned(), value.to_owned())
+                .map(Reply::Swapped),
+            Request::ReplicationStatus => {
+                Ok(Reply::ReplicationStatus(self.shared.replication_status()))
+            }
+            Request::Raft(_)
+            | Request::ClusterStatus
+            | Request::AddMember { .. }
+            | Request::RemoveMember { .. } => {
+                Err(KvsError::InvalidRequest("Not a cluster node".to_owned()).into())
             }
+            Request::ProxyStats => Err(KvsError::InvalidRequest("Not a proxy".to_owned()).into()),
+            Request::Auth { .. }
+            | Request::Hello { .. }
+            | Request::Ping
+            | Request::Replicate { .. }
+            | Request::Watch { .. }
+            | Request::Publish { .. }
+            | Request::Subscribe { .. }
+            | Request::PSubscribe { .. }
+            | Request::Cdc { .. }
+            | Request::Backup { .. } => unreachable!(),
+        };
+        if let Ok(reply) = &result {
+            self.shared.changes.record(request, reply);
         }
-        Ok(())
+        result.map_err(|e| ErrorCode::from(&e))
+    }
+}
+
+/// Whether `user` may see `key` in a scan, memcached metadata being hidden.
+fn scannable(acl: Option<&Acl>, user: &str, key: &str) -> bool {
+    !key.ends_with(memcache::META_SUFFIX) && acl.map_or(true, |acl| acl.allows(user, Op::Scan, key))
+}
+
+/// Throttle the requests of one connection to a number of requests per second.
+struct RateLimiter {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Block until one more request is allowed by `limit`.
+    fn acquire(&mut self, limit: Option<u32>) {
+        let Some(limit) = limit else { return };
+        let window = Duration::from_secs(1);
+        if self.window_start.elapsed() >= window {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        if self.count >= limit {
+            thread::sleep(window.saturating_sub(self.window_start.elapsed()));
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
     }
 }
@@ -0,0 +1,82 @@
+//! Change data capture.
+//!
+//! A `Cdc` request turns the connection into a stream of the changes logged
+//! by the engine, numbered by the engine so that a consumer which reconnects,
+//! even to a restarted server, resumes after the last change it saw. The
+//! `ChangeLog` of the server only signals that there are new changes.
+
+use std::{io::Write, time::Instant};
+
+use super::{memcache, replication::HEARTBEAT_INTERVAL, Shared};
+use crate::acl::Op;
+use crate::codec::Codec;
+use crate::transport::{Change, ErrorCode, Reply, Response, WatchEvent};
+use crate::{KvsEngine, Result};
+
+/// Most changes read from the engine at once.
+const BATCH: usize = 1000;
+
+/// Send the changes after `from` of the keys which `user` may get, and a
+/// heartbeat when there are none for a while, until the connection fails or
+/// the engine cannot give the changes.
+pub fn stream<E: KvsEngine, W: Write>(
+    shared: &Shared<E>,
+    stream: &mut W,
+    codec: &Codec,
+    id: u64,
+    from: u64,
+    user: &str,
+) -> Result<()> {
+    let mut seq = from;
+    let mut last_sent = Instant::now();
+    loop {
+        let head = shared.changes.seq();
+        let changes = match shared.engine.lock().unwrap().changes(seq, BATCH) {
+            Ok(changes) => changes,
+            Err(e) => {
+                let result = Err(ErrorCode::from(&e));
+                return codec.send(stream, &Response { id, result });
+            }
+        };
+        if changes.is_empty() {
+            if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                last_sent = Instant::now();
+                send(stream, codec, id, vec![])?;
+            }
+            // wait for a write
+            shared.changes.after(head, HEARTBEAT_INTERVAL);
+            continue;
+        }
+        seq = changes.last().map_or(seq, |(seq, _)| *seq);
+        let acl = shared.acl.read().unwrap();
+        let events = changes
+            .into_iter()
+            .filter(|(_, change)| {
+                let key = match change {
+                    Change::Set { key, .. } | Change::Remove { key } => key,
+                };
+                !key.ends_with(memcache::META_SUFFIX)
+                    && acl
+                        .as_ref()
+                        .map_or(true, |acl| acl.allows(user, Op::Get, key))
+            })
+            .map(|(seq, change)| WatchEvent { seq, change })
+            .collect::<Vec<_>>();
+        drop(acl);
+        if events.is_empty() && last_sent.elapsed() < HEARTBEAT_INTERVAL {
+            continue;
+        }
+        last_sent = Instant::now();
+        send(stream, codec, id, events)?;
+    }
+}
+
+fn send<W: Write>(stream: &mut W, codec: &Codec, id: u64, events: Vec<WatchEvent>) -> Result<()> {
+    codec.send(
+        stream,
+        &Response {
+            id,
+            result: Ok(Reply::Cdc(events)),
+        },
+    )
+}
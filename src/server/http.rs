@@ -0,0 +1,485 @@
+//! HTTP/JSON gateway.
+//!
+//! Routes:
+//! - `GET /v1/keys/{key}`: the value as text, with its `ETag`
+//! - `PUT /v1/keys/{key}`: set the value to the request body, only if the
+//!   `If-Match` or `If-None-Match` condition holds when one is given
+//! - `DELETE /v1/keys/{key}`
+//! - `GET /v1/keys?prefix=...`: the pairs under a prefix, as a JSON array
+//! - `GET /v1/admin/health`, `GET /v1/admin/info` and `GET /v1/admin/config`
+//!
+//! Requests authenticate with `Authorization: Bearer <token>` or
+//! `Authorization: Basic ...`. Errors come as `{"error": "..."}` with the
+//! matching status code, such as 404 for a missing key.
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    sync::atomic::Ordering,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use log::{debug, info};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::{resp::read_line, Session, Shared};
+use crate::auth::Credentials;
+use crate::net::Stream;
+use crate::transport::{ErrorCode, Reply, Request};
+use crate::{KvsEngine, KvsError, Result};
+
+/// Most headers accepted in one request.
+const MAX_HEADERS: usize = 100;
+/// Largest body accepted in one request.
+const MAX_BODY: usize = 64 << 20;
+
+/// Serve one HTTP client.
+pub fn serve<E: KvsEngine, S: Stream>(shared: &Shared<E>, stream: S) -> Result<()> {
+    let mut session = Session::new(shared, &stream);
+    // identity given by TLS, every request has to present its own credentials
+    let identity = session.identity.clone();
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let request = match read_request(&mut stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                if let Some(KvsError::InvalidRequest(reason)) = e.downcast_ref() {
+                    let response = HttpResponse::json(400, &json!({ "error": reason }));
+                    response.write(stream.get_mut(), true)?;
+                }
+                return Err(e);
+            }
+        };
+        info!("Got request from {}", session.peer_addr);
+        debug!(
+            "HTTP request from {}: {} {}",
+            session.peer_addr, request.method, request.path
+        );
+
+        session.identity = identity.clone();
+        let response = match authenticate(&mut session, &request) {
+            Ok(()) => route(&mut session, &request),
+            Err(code) => HttpResponse::error(code),
+        };
+        let close = request.wants_close();
+        response.write(stream.get_mut(), close)?;
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Authenticate with the `Authorization` header of `request`, if any.
+fn authenticate<E: KvsEngine>(
+    session: &mut Session<E>,
+    request: &HttpRequest,
+) -> std::result::Result<(), ErrorCode> {
+    let Some(authorization) = request.header("authorization") else {
+        return Ok(());
+    };
+    let credentials = match authorization.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Credentials::Token(token.trim().to_owned())
+        }
+        Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => BASE64
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (user, password) = decoded.split_once(':')?;
+                Some(Credentials::Password {
+                    user: user.to_owned(),
+                    password: password.to_owned(),
+                })
+            })
+            .ok_or_else(|| ErrorCode::InvalidRequest("Malformed basic authorization".to_owned()))?,
+        _ => {
+            return Err(ErrorCode::InvalidRequest(
+                "Unsupported authorization scheme".to_owned(),
+            ))
+        }
+    };
+    session.handle(&Request::Auth { credentials })?;
+    Ok(())
+}
+
+fn route<E: KvsEngine>(session: &mut Session<E>, request: &HttpRequest) -> HttpResponse {
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/v1/keys") => list(session, request),
+        (method, path) if path.starts_with("/v1/keys/") => {
+            match percent_decode(&path["/v1/keys/".len()..], false) {
+                Some(key) => match method {
+                    "GET" => get(session, request, key),
+                    "PUT" => put(session, request, key),
+                    "DELETE" => delete(session, key),
+                    _ => Ok(HttpResponse::method_not_allowed("GET, PUT, DELETE")),
+                },
+                None => Err(ErrorCode::InvalidRequest("Malformed key".to_owned())),
+            }
+        }
+        ("GET", "/v1/admin/health") => Ok(HttpResponse::json(200, &json!({ "status": "ok" }))),
+        ("GET", "/v1/admin/info") => session.authorize_admin().map(|()| {
+            let shared = session.shared;
+            HttpResponse::json(
+                200,
+                &json!({
+                    "server": shared.info,
+                    "connections": shared.connections.load(Ordering::SeqCst),
+                    "replication": shared.replication_status(),
+                    "cluster": shared.raft.get().map(|raft| raft.status()),
+                }),
+            )
+        }),
+        ("GET", "/v1/admin/config") => session
+            .authorize_admin()
+            .map(|()| HttpResponse::json(200, &*session.shared.config.read().unwrap())),
+        (_, "/v1/keys" | "/v1/admin/health" | "/v1/admin/info" | "/v1/admin/config") => {
+            Ok(HttpResponse::method_not_allowed("GET"))
+        }
+        _ => Ok(HttpResponse::json(404, &json!({ "error": "Not found" }))),
+    };
+    result.unwrap_or_else(HttpResponse::error)
+}
+
+fn list<E: KvsEngine>(
+    session: &mut Session<E>,
+    request: &HttpRequest,
+) -> std::result::Result<HttpResponse, ErrorCode> {
+    let prefix = request.query_param("prefix")?.unwrap_or_default();
+    match session.handle(&Request::Scan { prefix })? {
+        Reply::Pairs(pairs) => {
+            let pairs: Vec<_> = pairs
+                .into_iter()
+                .map(|(key, value)| json!({ "key": key, "value": value }))
+                .collect();
+            Ok(HttpResponse::json(200, &pairs))
+        }
+        _ => Err(unexpected_reply()),
+    }
+}
+
+fn get<E: KvsEngine>(
+    session: &mut Session<E>,
+    request: &HttpRequest,
+    key: String,
+) -> std::result::Result<HttpResponse, ErrorCode> {
+    let Reply::Value(value) = session.handle(&Request::Get { key })? else {
+        return Err(unexpected_reply());
+    };
+    let value = value.ok_or(ErrorCode::KeyNotFound)?;
+    let etag = etag(&value);
+    if request
+        .header("if-none-match")
+        .is_some_and(|tags| matches_etag(tags, &etag))
+    {
+        return Ok(HttpResponse::new(304).header("ETag", etag));
+    }
+    Ok(HttpResponse::new(200)
+        .header("Content-Type", "text/plain; charset=utf-8".to_owned())
+        .header("ETag", etag)
+        .body(value.into_bytes()))
+}
+
+fn put<E: KvsEngine>(
+    session: &mut Session<E>,
+    request: &HttpRequest,
+    key: String,
+) -> std::result::Result<HttpResponse, ErrorCode> {
+    let value = String::from_utf8(request.body.clone())
+        .map_err(|_| ErrorCode::InvalidRequest("The value must be UTF-8".to_owned()))?;
+    let new_etag = etag(&value);
+
+    let expected = match (request.header("if-match"), request.header("if-none-match")) {
+        (None, None) => {
+            session.handle(&Request::Set { key, value })?;
+            return Ok(HttpResponse::new(204).header("ETag", new_etag));
+        }
+        (Some(_), Some(_)) => {
+            return Err(ErrorCode::InvalidRequest(
+                "Use either If-Match or If-None-Match".to_owned(),
+            ))
+        }
+        (Some(tags), None) => {
+            let Reply::Value(current) = session.handle(&Request::Get { key: key.clone() })? else {
+                return Err(unexpected_reply());
+            };
+            match current {
+                Some(current) if matches_etag(tags, &etag(&current)) => Some(current),
+                _ => return Ok(precondition_failed()),
+            }
+        }
+        (None, Some(tags)) if tags.trim() == "*" => None,
+        (None, Some(_)) => {
+            return Err(ErrorCode::InvalidRequest(
+                "If-None-Match only supports `*` on PUT".to_owned(),
+            ))
+        }
+    };
+    // the value may have changed since it was read, set it atomically
+    match session.handle(&Request::CompareAndSet {
+        key,
+        expected,
+        value,
+    })? {
+        Reply::Swapped(true) => Ok(HttpResponse::new(204).header("ETag", new_etag)),
+        Reply::Swapped(false) => Ok(precondition_failed()),
+        _ => Err(unexpected_reply()),
+    }
+}
+
+fn delete<E: KvsEngine>(
+    session: &mut Session<E>,
+    key: String,
+) -> std::result::Result<HttpResponse, ErrorCode> {
+    session.handle(&Request::Remove { key })?;
+    Ok(HttpResponse::new(204))
+}
+
+fn precondition_failed() -> HttpResponse {
+    HttpResponse::json(412, &json!({ "error": "Precondition failed" }))
+}
+
+fn unexpected_reply() -> ErrorCode {
+    ErrorCode::Internal("Unexpected reply".to_owned())
+}
+
+/// Strong entity tag of a value.
+fn etag(value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    let hex = digest[..16].iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    format!("\"{hex}\"")
+}
+
+/// Whether the `If-Match`/`If-None-Match` list `tags` contains `etag`.
+fn matches_etag(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    /// `HTTP/1.0` or `HTTP/1.1`
+    version: String,
+    /// names in lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query_param(&self, name: &str) -> std::result::Result<Option<String>, ErrorCode> {
+        for param in self.query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            if key == name {
+                return percent_decode(value, true)
+                    .map(Some)
+                    .ok_or_else(|| ErrorCode::InvalidRequest(format!("Malformed `{name}`")));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the connection should close after this request.
+    fn wants_close(&self) -> bool {
+        match self.header("connection") {
+            Some(connection) if connection.eq_ignore_ascii_case("close") => true,
+            Some(connection) if connection.eq_ignore_ascii_case("keep-alive") => false,
+            _ => self.version == "HTTP/1.0",
+        }
+    }
+}
+
+/// Read one request, or `None` if the stream is closed.
+fn read_request<R: BufRead>(stream: &mut R) -> Result<Option<HttpRequest>> {
+    let line = loop {
+        match read_line(stream)? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break String::from_utf8(line).map_err(|_| bad_request("Not UTF-8"))?,
+        }
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("Malformed request line"));
+    };
+    if version != "HTTP/1.0" && version != "HTTP/1.1" {
+        return Err(bad_request("Unsupported HTTP version"));
+    }
+
+    let mut headers = vec![];
+    loop {
+        let line = read_line(stream)?.ok_or_else(|| bad_request("Unexpected end of stream"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(bad_request("Too many headers"));
+        }
+        let line = String::from_utf8(line).map_err(|_| bad_request("Not UTF-8"))?;
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("Malformed header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        version: version.to_owned(),
+        headers,
+        body: vec![],
+    };
+    if request.header("transfer-encoding").is_some() {
+        return Err(bad_request("Chunked bodies are not supported"));
+    }
+    if let Some(len) = request.header("content-length") {
+        let len: usize = len
+            .parse()
+            .ok()
+            .filter(|&len| len <= MAX_BODY)
+            .ok_or_else(|| bad_request("Invalid Content-Length"))?;
+        request.body = vec![0; len];
+        stream.read_exact(&mut request.body)?;
+    }
+    Ok(Some(request))
+}
+
+fn bad_request(reason: &str) -> anyhow::Error {
+    KvsError::InvalidRequest(reason.to_owned()).into()
+}
+
+/// Decode `%XX` escapes, and `+` as a space in a query string.
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = s.bytes();
+    let mut decoded = vec![];
+    while let Some(b) = bytes.next() {
+        decoded.push(match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b'+' if plus_as_space => b' ',
+            b => b,
+        });
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Answer a connection which is refused with `code`.
+pub fn reject<S: Stream>(mut stream: S, code: ErrorCode) -> Result<()> {
+    HttpResponse::error(code).write(&mut stream, true)
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn json<T: Serialize + ?Sized>(status: u16, body: &T) -> Self {
+        Self::new(status)
+            .header("Content-Type", "application/json".to_owned())
+            .body(serde_json::to_vec(body).unwrap_or_default())
+    }
+
+    fn method_not_allowed(allow: &str) -> Self {
+        Self::json(405, &json!({ "error": "Method not allowed" })).header("Allow", allow.to_owned())
+    }
+
+    fn error(code: ErrorCode) -> Self {
+        let status = match &code {
+            ErrorCode::KeyNotFound => 404,
+            ErrorCode::AuthRequired | ErrorCode::AuthFailed => 401,
+            ErrorCode::PermissionDenied(_) | ErrorCode::ReadOnly { .. } => 403,
+            ErrorCode::InvalidRequest(_) | ErrorCode::UnsupportedVersion { .. } => 400,
+            ErrorCode::Internal(_) => 500,
+            ErrorCode::NotLeader { .. } => 421,
+            ErrorCode::Compacted { .. } => 410,
+        };
+        let response = Self::json(
+            status,
+            &json!({ "error": KvsError::from(code).to_string() }),
+        );
+        match status {
+            401 => response.header("WWW-Authenticate", "Bearer".to_owned()),
+            _ => response,
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    fn write<W: Write>(&self, stream: &mut W, close: bool) -> Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        let _ = write!(head, "Server: kvs/{}\r\n", env!("CARGO_PKG_VERSION"));
+        for (name, value) in &self.headers {
+            let _ = write!(head, "{name}: {value}\r\n");
+        }
+        if self.status != 204 && self.status != 304 {
+            let _ = write!(head, "Content-Length: {}\r\n", self.body.len());
+        }
+        if close {
+            head += "Connection: close\r\n";
+        }
+        head += "\r\n";
+        let mut buf = head.into_bytes();
+        buf.extend_from_slice(&self.body);
+        stream.write_all(&buf)?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        412 => "Precondition Failed",
+        421 => "Misdirected Request",
+        _ => "Internal Server Error",
+    }
+}
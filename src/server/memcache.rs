@@ -0,0 +1,508 @@
+//! Memcached ASCII protocol front end.
+//!
+//! Commands are mapped onto native requests, like the RESP front end, so ACLs
+//! and rate limits work as they do for native clients. Supported commands are
+//! get, gets, set, add, replace, cas, delete, incr and decr, plus version and
+//! quit. The text protocol has no authentication: clients are anonymous unless
+//! TLS gave them an identity.
+//!
+//! The flags and expiration time of an item are stored beside its value, under
+//! its key followed by `META_SUFFIX`, and `scan` hides them. Both are written
+//! in one `MSet`. Memcached clients never read an expired item, and `sweep`
+//! removes it within a second or so, for the other clients too. The CAS unique
+//! of an item is a hash of its value.
+//!
+//! The commands which depend on the current item run one at a time, so they
+//! are atomic between memcached clients. A native client writing the same key
+//! meanwhile may be overwritten.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, BufReader, Read},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info};
+use sha2::{Digest, Sha256};
+
+use super::{resp::read_line, Session, Shared, MAX_HELD_RESPONSES};
+use crate::dump::{for_each_page, SCAN_PAGE_SIZE};
+use crate::net::Stream;
+use crate::transport::{Change, ErrorCode, Reply, Request};
+use crate::{KvsEngine, KvsError, Result};
+
+/// Suffix of the keys holding the flags and expiration time of an item.
+pub const META_SUFFIX: &str = "\0memcache";
+
+/// Longest key accepted, as in memcached.
+const MAX_KEY_LEN: usize = 250;
+/// Largest value accepted in one command.
+const MAX_VALUE_LEN: usize = 64 << 20;
+/// Larger expiration times are a Unix time instead of a number of seconds.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// How often `sweep` looks for expired items.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serve one memcached client.
+pub fn serve<E: KvsEngine, S: Stream>(shared: &Shared<E>, stream: S) -> Result<()> {
+    let mut session = Session::new(shared, &stream);
+    let mut stream = BufReader::new(stream);
+    let mut out = vec![];
+
+    loop {
+        let line = match read_line(&mut stream) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                if let Some(KvsError::InvalidRequest(reason)) = e.downcast_ref() {
+                    out.extend_from_slice(format!("CLIENT_ERROR {reason}\r\n").as_bytes());
+                    stream.get_mut().write_all(&out)?;
+                }
+                return Err(e);
+            }
+        };
+        let line = String::from_utf8_lossy(&line);
+        let args: Vec<&str> = line.split_ascii_whitespace().collect();
+        let Some((&name, args)) = args.split_first() else {
+            out.extend_from_slice(b"ERROR\r\n");
+            continue;
+        };
+        info!("Got request from {}", session.peer_addr);
+        debug!("Memcached command from {}: {}", session.peer_addr, name);
+
+        let quit = name == "quit";
+        if !quit {
+            run(&mut session, &mut stream, &mut out, name, args)?;
+        }
+
+        // write the replies of pipelined commands together
+        if quit || stream.buffer().is_empty() || out.len() >= MAX_HELD_RESPONSES {
+            let stream = stream.get_mut();
+            stream.write_all(&out)?;
+            stream.flush()?;
+            out.clear();
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Run one command and write its reply to `out`.
+///
+/// Storage commands read their data block from `stream`.
+fn run<E: KvsEngine, R: BufRead>(
+    session: &mut Session<E>,
+    stream: &mut R,
+    out: &mut Vec<u8>,
+    name: &str,
+    args: &[&str],
+) -> Result<()> {
+    let (reply, noreply) = match name {
+        "get" | "gets" if !args.is_empty() => (get(session, out, args, name == "gets"), false),
+        "set" | "add" | "replace" | "cas" => {
+            let Some(command) = StorageCommand::parse(name, args) else {
+                out.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n");
+                return Ok(());
+            };
+            if command.len > MAX_VALUE_LEN {
+                // skip the data block
+                io::copy(&mut stream.take(command.len as u64 + 2), &mut io::sink())?;
+                out.extend_from_slice(b"SERVER_ERROR object too large for cache\r\n");
+                return Ok(());
+            }
+            let mut data = vec![0; command.len + 2];
+            stream.read_exact(&mut data)?;
+            if !data.ends_with(b"\r\n") {
+                out.extend_from_slice(b"CLIENT_ERROR bad data chunk\r\n");
+                return Ok(());
+            }
+            data.truncate(command.len);
+            let reply = match String::from_utf8(data) {
+                Ok(value) => store(session, &command, value),
+                Err(_) => Err(ErrorCode::InvalidRequest(
+                    "kvs only stores UTF-8 strings".to_owned(),
+                )),
+            };
+            (reply, command.noreply)
+        }
+        "delete" if matches!(args.len(), 1 | 2) && valid_key(args[0]) => {
+            (delete(session, args[0]), args.get(1) == Some(&"noreply"))
+        }
+        "incr" | "decr" if matches!(args.len(), 2 | 3) && valid_key(args[0]) => {
+            let reply = match args[1].parse() {
+                Ok(delta) => incr(session, args[0], delta, name == "incr"),
+                Err(_) => Err(ErrorCode::InvalidRequest(
+                    "invalid numeric delta argument".to_owned(),
+                )),
+            };
+            (reply, args.get(2) == Some(&"noreply"))
+        }
+        "version" => (Ok(format!("VERSION {}", env!("CARGO_PKG_VERSION"))), false),
+        "get" | "gets" | "delete" | "incr" | "decr" => (
+            Err(ErrorCode::InvalidRequest(
+                "bad command line format".to_owned(),
+            )),
+            false,
+        ),
+        _ => (Ok("ERROR".to_owned()), false),
+    };
+    if noreply {
+        return Ok(());
+    }
+    let line = reply.unwrap_or_else(error_message);
+    out.extend_from_slice(line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+    Ok(())
+}
+
+/// Answer a connection which is refused with `code`.
+pub fn reject<S: Stream>(mut stream: S, code: ErrorCode) -> Result<()> {
+    stream.write_all(format!("{}\r\n", error_message(code)).as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn error_message(code: ErrorCode) -> String {
+    match code {
+        ErrorCode::InvalidRequest(reason) => format!("CLIENT_ERROR {reason}"),
+        ErrorCode::Internal(reason) => format!("SERVER_ERROR {reason}"),
+        ErrorCode::AuthRequired | ErrorCode::AuthFailed | ErrorCode::PermissionDenied(_) => {
+            format!("CLIENT_ERROR {}", KvsError::from(code))
+        }
+        code => format!("SERVER_ERROR {}", KvsError::from(code)),
+    }
+}
+
+/// Arguments of set, add, replace and cas.
+struct StorageCommand<'a> {
+    name: &'a str,
+    key: &'a str,
+    flags: u32,
+    exptime: i64,
+    len: usize,
+    /// CAS unique given to `cas`
+    unique: Option<u64>,
+    noreply: bool,
+}
+
+impl<'a> StorageCommand<'a> {
+    fn parse(name: &'a str, args: &[&'a str]) -> Option<Self> {
+        let fixed = if name == "cas" { 5 } else { 4 };
+        let noreply = match &args[fixed.min(args.len())..] {
+            [] => false,
+            ["noreply"] => true,
+            _ => return None,
+        };
+        if args.len() < fixed || !valid_key(args[0]) {
+            return None;
+        }
+        Some(Self {
+            name,
+            key: args[0],
+            flags: args[1].parse().ok()?,
+            exptime: args[2].parse().ok()?,
+            len: args[3].parse().ok()?,
+            unique: match name {
+                "cas" => Some(args[4].parse().ok()?),
+                _ => None,
+            },
+            noreply,
+        })
+    }
+
+    /// Metadata of the stored item.
+    fn meta(&self) -> String {
+        let expires = match self.exptime {
+            0 => 0,
+            // already expired
+            exptime if exptime < 0 => 1,
+            exptime if exptime <= MAX_RELATIVE_EXPTIME => now() + exptime as u64,
+            exptime => exptime as u64,
+        };
+        format!("{} {}", self.flags, expires)
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_LEN && !key.chars().any(char::is_control)
+}
+
+fn meta_key(key: &str) -> String {
+    format!("{key}{META_SUFFIX}")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// A live item.
+struct Item {
+    value: String,
+    flags: u32,
+}
+
+impl Item {
+    fn unique(&self) -> u64 {
+        let digest = Sha256::digest(self.value.as_bytes());
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+}
+
+/// Read the live items under `keys`.
+fn lookup<E: KvsEngine>(
+    session: &mut Session<E>,
+    keys: &[&str],
+) -> std::result::Result<Vec<Option<Item>>, ErrorCode> {
+    let request = Request::MGet {
+        keys: keys
+            .iter()
+            .flat_map(|&key| [key.to_owned(), meta_key(key)])
+            .collect(),
+    };
+    let Reply::Values(values) = session.handle(&request)? else {
+        return Err(ErrorCode::Internal("Unexpected reply".to_owned()));
+    };
+    let now = now();
+    let items = values
+        .chunks(2)
+        .map(|pair| {
+            let value = pair[0].as_ref()?;
+            let (flags, expires) = pair[1].as_deref().and_then(parse_meta).unwrap_or((0, 0));
+            (!expired(expires, now)).then(|| Item {
+                value: value.to_owned(),
+                flags,
+            })
+        })
+        .collect();
+    Ok(items)
+}
+
+/// Flags and expiration time of an item.
+fn parse_meta(meta: &str) -> Option<(u32, u64)> {
+    let (flags, expires) = meta.split_once(' ')?;
+    Some((flags.parse().ok()?, expires.parse().ok()?))
+}
+
+fn expired(expires: u64, now: u64) -> bool {
+    expires != 0 && expires <= now
+}
+
+/// Remove an item and its metadata.
+fn remove<E: KvsEngine>(session: &mut Session<E>, key: &str) -> std::result::Result<(), ErrorCode> {
+    for key in [key.to_owned(), meta_key(key)] {
+        match session.handle(&Request::Remove { key }) {
+            Ok(_) | Err(ErrorCode::KeyNotFound) => {}
+            Err(code) => return Err(code),
+        }
+    }
+    Ok(())
+}
+
+fn get<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Vec<u8>,
+    keys: &[&str],
+    with_unique: bool,
+) -> std::result::Result<String, ErrorCode> {
+    if !keys.iter().all(|key| valid_key(key)) {
+        return Err(ErrorCode::InvalidRequest(
+            "bad command line format".to_owned(),
+        ));
+    }
+    for (key, item) in keys.iter().zip(lookup(session, keys)?) {
+        let Some(item) = item else {
+            continue;
+        };
+        let header = match with_unique {
+            true => format!(
+                "VALUE {key} {} {} {}\r\n",
+                item.flags,
+                item.value.len(),
+                item.unique()
+            ),
+            false => format!("VALUE {key} {} {}\r\n", item.flags, item.value.len()),
+        };
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(item.value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    Ok("END".to_owned())
+}
+
+fn store<E: KvsEngine>(
+    session: &mut Session<E>,
+    command: &StorageCommand,
+    value: String,
+) -> std::result::Result<String, ErrorCode> {
+    let key = command.key;
+    let _writes = session.shared.memcache_writes.lock().unwrap();
+    if command.name != "set" {
+        // the others depend on the current item
+        let current = lookup(session, &[key])?.pop().flatten();
+        match (command.name, current) {
+            ("add", None) => {}
+            ("cas", None) => return Ok("NOT_FOUND".to_owned()),
+            ("add", Some(_)) | (_, None) => return Ok("NOT_STORED".to_owned()),
+            ("cas", Some(item)) if Some(item.unique()) != command.unique => {
+                return Ok("EXISTS".to_owned())
+            }
+            (_, Some(_)) => {}
+        }
+    }
+    session.handle(&Request::MSet {
+        pairs: vec![(key.to_owned(), value), (meta_key(key), command.meta())],
+    })?;
+    Ok("STORED".to_owned())
+}
+
+fn delete<E: KvsEngine>(
+    session: &mut Session<E>,
+    key: &str,
+) -> std::result::Result<String, ErrorCode> {
+    let _writes = session.shared.memcache_writes.lock().unwrap();
+    if lookup(session, &[key])?.pop().flatten().is_none() {
+        return Ok("NOT_FOUND".to_owned());
+    }
+    remove(session, key)?;
+    Ok("DELETED".to_owned())
+}
+
+fn incr<E: KvsEngine>(
+    session: &mut Session<E>,
+    key: &str,
+    delta: u64,
+    up: bool,
+) -> std::result::Result<String, ErrorCode> {
+    let _writes = session.shared.memcache_writes.lock().unwrap();
+    // the value only changes if no native client changed it meanwhile
+    loop {
+        let Some(item) = lookup(session, &[key])?.pop().flatten() else {
+            return Ok("NOT_FOUND".to_owned());
+        };
+        let Ok(number) = item.value.parse::<u64>() else {
+            return Err(ErrorCode::InvalidRequest(
+                "cannot increment or decrement non-numeric value".to_owned(),
+            ));
+        };
+        // like memcached, incr wraps around and decr stops at 0
+        let number = match up {
+            true => number.wrapping_add(delta),
+            false => number.saturating_sub(delta),
+        };
+        let request = Request::CompareAndSet {
+            key: key.to_owned(),
+            expected: Some(item.value),
+            value: number.to_string(),
+        };
+        if let Reply::Swapped(true) = session.handle(&request)? {
+            return Ok(number.to_string());
+        }
+    }
+}
+
+/// Remove the expired items, forever.
+///
+/// The items which expire are loaded from the engine, then followed in the
+/// change log, so that the items written through a leader or a cluster are
+/// swept as well. An item which cannot be removed yet, on a node which isn't
+/// the leader for instance, is tried again later.
+pub fn sweep<E: KvsEngine>(shared: &Shared<E>) {
+    let mut expiring = BTreeSet::new();
+    let mut seq = None;
+    loop {
+        let changes = seq.and_then(|seq| shared.changes.after(seq, SWEEP_INTERVAL));
+        let Some(changes) = changes else {
+            // start over from the engine, the changes since `seq` are not kept
+            seq = Some(shared.changes.seq());
+            expiring.clear();
+            if let Err(e) = load(shared, &mut expiring) {
+                error!("Cannot load the memcached items: {e}");
+            }
+            continue;
+        };
+        for (change_seq, change) in changes {
+            seq = Some(change_seq);
+            if let Change::Set { key, value } = change {
+                if let Some(key) = key.strip_suffix(META_SUFFIX) {
+                    track(&mut expiring, key, &value);
+                }
+            }
+        }
+        remove_expired(shared, &mut expiring);
+    }
+}
+
+/// Load the items of the engine which expire.
+fn load<E: KvsEngine>(shared: &Shared<E>, expiring: &mut BTreeSet<(u64, String)>) -> Result<()> {
+    // lock the engine for one page at a time
+    let scan_page = |prefix, start_after| {
+        let mut engine = shared.engine.lock().unwrap();
+        engine.scan_page(prefix, start_after, SCAN_PAGE_SIZE as usize)
+    };
+    for_each_page("", scan_page, |pairs| {
+        for (key, meta) in pairs {
+            if let Some(key) = key.strip_suffix(META_SUFFIX) {
+                track(expiring, key, &meta);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Remember when the item under `key` expires, if it does.
+fn track(expiring: &mut BTreeSet<(u64, String)>, key: &str, meta: &str) {
+    if let Some((_, expires)) = parse_meta(meta).filter(|&(_, expires)| expires != 0) {
+        expiring.insert((expires, key.to_owned()));
+    }
+}
+
+fn remove_expired<E: KvsEngine>(shared: &Shared<E>, expiring: &mut BTreeSet<(u64, String)>) {
+    let now = now();
+    let mut later = vec![];
+    while expiring.first().is_some_and(|(expires, _)| *expires <= now) {
+        let (expires, key) = expiring.pop_first().unwrap();
+        if let Err(code) = remove_if_expired(shared, &key, now) {
+            debug!(
+                "Cannot remove the expired item {key}: {}",
+                KvsError::from(code)
+            );
+            later.push((expires, key));
+        }
+    }
+    expiring.extend(later);
+}
+
+/// Remove the item under `key` and its metadata if it is expired, it may have
+/// been stored again since.
+fn remove_if_expired<E: KvsEngine>(
+    shared: &Shared<E>,
+    key: &str,
+    now: u64,
+) -> std::result::Result<(), ErrorCode> {
+    let _writes = shared.memcache_writes.lock().unwrap();
+    let meta = shared
+        .engine
+        .lock()
+        .unwrap()
+        .get(meta_key(key))
+        .map_err(|e| ErrorCode::from(&e))?;
+    let expires = meta
+        .as_deref()
+        .and_then(parse_meta)
+        .map_or(0, |(_, expires)| expires);
+    if !expired(expires, now) {
+        return Ok(());
+    }
+    // the value first, a failure leaves the metadata to sweep it again
+    for key in [key.to_owned(), meta_key(key)] {
+        match shared.write(&Request::Remove { key }) {
+            Ok(_) | Err(ErrorCode::KeyNotFound) => {}
+            Err(code) => return Err(code),
+        }
+    }
+    Ok(())
+}
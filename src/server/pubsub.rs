@@ -0,0 +1,197 @@
+//! Publish/subscribe messaging.
+//!
+//! A message published on a channel goes to the connections subscribed to the
+//! channel, by name or by a glob matching its name, and is never stored in
+//! the engine. Each subscriber holds at most `pubsub_buffer` messages it
+//! didn't read yet, and `pubsub_drop` decides what happens when a slow
+//! subscriber's buffer is full.
+
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use log::warn;
+
+use super::{replication::HEARTBEAT_INTERVAL, Shared};
+use crate::acl::glob_match;
+use crate::codec::Codec;
+use crate::config::DropPolicy;
+use crate::transport::{ErrorCode, Message, Reply, Response};
+use crate::{KvsEngine, Result};
+
+/// Error ending a subscription whose buffer overflowed.
+pub const OVERFLOWED: &str = "Subscriber too slow, its buffer overflowed";
+
+/// Subscribers of a server.
+pub struct Broker {
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+}
+
+/// Connection subscribed to some channels.
+pub struct Subscriber {
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    queue: Mutex<Queue>,
+    /// signaled when a message is queued
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    /// messages dropped by `DropNewest` since the last queued one
+    dropped: u64,
+    /// set when `Disconnect` ends the subscription
+    overflowed: bool,
+}
+
+/// Subscription of a connection, which ends when dropped.
+pub struct Subscribed<'a> {
+    broker: &'a Broker,
+    subscriber: Arc<Subscriber>,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Subscribe to the channels named `channels` or matching `patterns`.
+    pub fn subscribe(&self, channels: Vec<String>, patterns: Vec<String>) -> Subscribed<'_> {
+        let subscriber = Arc::new(Subscriber {
+            channels,
+            patterns,
+            queue: Mutex::default(),
+            queued: Condvar::new(),
+        });
+        self.subscribers.lock().unwrap().push(subscriber.clone());
+        Subscribed {
+            broker: self,
+            subscriber,
+        }
+    }
+
+    /// Queue `message` for the subscribers of `channel`, whose buffers hold
+    /// at most `buffer` messages, and return how many subscribers got it.
+    pub fn publish(&self, channel: &str, message: &str, buffer: usize, drop: DropPolicy) -> usize {
+        let subscribers = self.subscribers.lock().unwrap();
+        let mut receivers = 0;
+        for subscriber in subscribers.iter() {
+            let pattern = if subscriber.channels.iter().any(|name| name == channel) {
+                None
+            } else {
+                match subscriber
+                    .patterns
+                    .iter()
+                    .find(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+                {
+                    Some(pattern) => Some(pattern.clone()),
+                    None => continue,
+                }
+            };
+            receivers += 1;
+            subscriber.push(
+                Message {
+                    channel: channel.to_owned(),
+                    pattern,
+                    message: message.to_owned(),
+                    dropped: 0,
+                },
+                buffer,
+                drop,
+            );
+        }
+        receivers
+    }
+}
+
+impl Subscriber {
+    fn push(&self, mut message: Message, buffer: usize, drop: DropPolicy) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.overflowed {
+            return;
+        }
+        if queue.messages.len() >= buffer.max(1) {
+            match drop {
+                DropPolicy::DropOldest => {
+                    let oldest = queue.messages.pop_front().unwrap();
+                    message.dropped += oldest.dropped + 1;
+                    if let Some(next) = queue.messages.front_mut() {
+                        next.dropped += std::mem::replace(&mut message.dropped, 0);
+                    }
+                }
+                DropPolicy::DropNewest => {
+                    queue.dropped += 1;
+                    return;
+                }
+                DropPolicy::Disconnect => {
+                    warn!("Subscriber buffer full, end the subscription");
+                    queue.overflowed = true;
+                    self.queued.notify_all();
+                    return;
+                }
+            }
+        }
+        message.dropped += std::mem::take(&mut queue.dropped);
+        queue.messages.push_back(message);
+        self.queued.notify_all();
+    }
+}
+
+impl Subscribed<'_> {
+    /// The queued messages, waiting up to `timeout` for one if there are none
+    /// yet, or `None` once the subscription overflowed.
+    pub fn next(&self, timeout: Duration) -> Option<Vec<Message>> {
+        let subscriber = &self.subscriber;
+        let mut queue = subscriber.queue.lock().unwrap();
+        if queue.messages.is_empty() && !queue.overflowed {
+            queue = subscriber.queued.wait_timeout(queue, timeout).unwrap().0;
+        }
+        if queue.overflowed {
+            return None;
+        }
+        Some(queue.messages.drain(..).collect())
+    }
+}
+
+impl Drop for Subscribed<'_> {
+    fn drop(&mut self) {
+        self.broker
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| !Arc::ptr_eq(subscriber, &self.subscriber));
+    }
+}
+
+/// Send the messages published on the channels named `channels` or matching
+/// `patterns`, and a heartbeat when there are none for a while, until the
+/// connection fails or the subscriber's buffer overflows.
+pub fn stream<E: KvsEngine, W: Write>(
+    shared: &Shared<E>,
+    stream: &mut W,
+    codec: &Codec,
+    id: u64,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+) -> Result<()> {
+    let subscribed = shared.pubsub.subscribe(channels, patterns);
+    loop {
+        let Some(messages) = subscribed.next(HEARTBEAT_INTERVAL) else {
+            let result = Err(ErrorCode::Internal(OVERFLOWED.to_owned()));
+            return codec.send(stream, &Response { id, result });
+        };
+        codec.send(
+            stream,
+            &Response {
+                id,
+                result: Ok(Reply::Messages(messages)),
+            },
+        )?;
+    }
+}
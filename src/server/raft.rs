@@ -0,0 +1,1252 @@
+//! Raft consensus between the nodes of a cluster.
+//!
+//! Writes are appended to a replicated log, and applied to the engine of every
+//! node once a majority of the members stored them. Only the leader answers
+//! the clients, the other nodes refuse with `ErrorCode::NotLeader` and the
+//! address of the leader.
+//!
+//! A node keeps its term, its vote and its log under its own directory. The
+//! engine is the snapshot: once `snapshot_threshold` entries are applied, the
+//! log up to them is dropped, and a node which needs the dropped entries gets
+//! a copy of the data of the leader instead.
+//!
+//! The members change one node at a time, and a change takes effect as soon
+//! as it is in the log of a node, committed or not.
+//!
+//! The leader answers reads while it holds a lease: a majority of the members
+//! answered one of its messages sent less than `election_timeout` ago, and
+//! these members vote for no one else until then. A leader which loses its
+//! lease steps down, so a leader cut off from the others doesn't serve stale
+//! reads.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::Shared;
+use crate::auth::Credentials;
+use crate::client::{KvsClient, RetryPolicy};
+use crate::transport::{
+    ClusterStatus, Command, Entry, ErrorCode, RaftMessage, RaftReply, RaftRole, Reply, Request,
+};
+use crate::{KvsEngine, KvsError, Result};
+
+/// Interval of the checks for a silent leader.
+const TICK: Duration = Duration::from_millis(10);
+/// Most entries in one `AppendEntries`.
+const MAX_ENTRIES: usize = 1000;
+/// Most pairs in one `InstallSnapshot`.
+const SNAPSHOT_CHUNK: usize = 1000;
+/// Longest wait of a client for its write to commit.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait for the answer of a follower.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Outcome = std::result::Result<Reply, ErrorCode>;
+
+/// Settings of a cluster node.
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// address of this node, where the other nodes and the clients reach it,
+    /// which is also its id
+    pub id: String,
+    /// ids of the first members, this node included, only read when `dir` is
+    /// empty; a node which isn't in the list waits to be added
+    pub members: Vec<String>,
+    /// directory of the term, the vote and the log of this node
+    pub dir: PathBuf,
+    /// credentials to authenticate to the other nodes with
+    pub credentials: Option<Credentials>,
+    /// wait without hearing from a leader before an election, up to twice as
+    /// long at random
+    pub election_timeout: Duration,
+    /// wait between two messages of the leader to a follower
+    pub heartbeat_interval: Duration,
+    /// applied entries kept in the log before it is compacted
+    pub snapshot_threshold: u64,
+}
+
+impl ClusterConfig {
+    /// Settings of the node `id`, of a cluster starting with `members`, with
+    /// its state in `dir`.
+    pub fn new(id: impl Into<String>, members: Vec<String>, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            id: id.into(),
+            members,
+            dir: dir.into(),
+            credentials: None,
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            snapshot_threshold: 10_000,
+        }
+    }
+}
+
+/// State of a node which must survive a restart.
+#[derive(Serialize, Deserialize, Default)]
+struct HardState {
+    term: u64,
+    /// candidate voted for in `term`
+    voted_for: Option<String>,
+    /// last entry applied to the engine
+    applied: u64,
+    /// last entry dropped from the log
+    snapshot_index: u64,
+    /// term of the entry at `snapshot_index`
+    snapshot_term: u64,
+    /// members as of `snapshot_index`
+    snapshot_members: Vec<String>,
+}
+
+/// Files of a node: `state.json`, replaced on each change, and `log.jsonl`
+/// with one `[index, entry]` per line.
+struct Storage {
+    dir: PathBuf,
+    log: File,
+}
+
+impl Storage {
+    /// Open the files in `dir`, and read the state, unless there is none yet,
+    /// and the entries after the snapshot.
+    fn open(dir: &Path) -> Result<(Self, Option<HardState>, Vec<Entry>)> {
+        fs::create_dir_all(dir)?;
+        let hard: Option<HardState> = match fs::read(dir.join("state.json")) {
+            Ok(content) => Some(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot_index = hard.as_ref().map_or(0, |hard| hard.snapshot_index);
+
+        let path = dir.join("log.jsonl");
+        let mut entries = vec![];
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                let Ok((index, entry)) = serde_json::from_str::<(u64, Entry)>(&line) else {
+                    // torn by a crash in the middle of a write
+                    warn!("Ignore the end of the Raft log from {line:?}");
+                    break;
+                };
+                if index <= snapshot_index {
+                    continue;
+                }
+                if index != snapshot_index + entries.len() as u64 + 1 {
+                    return Err(KvsError::StringError(format!(
+                        "Raft log of {} skips to index {index}",
+                        dir.display()
+                    ))
+                    .into());
+                }
+                entries.push(entry);
+            }
+        }
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        let storage = Self {
+            dir: dir.to_owned(),
+            log,
+        };
+        Ok((storage, hard, entries))
+    }
+
+    fn save(&self, hard: &HardState) -> Result<()> {
+        let tmp = self.dir.join("state.json.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(hard)?)?;
+        file.sync_data()?;
+        fs::rename(tmp, self.dir.join("state.json"))?;
+        Ok(())
+    }
+
+    /// Append `entries`, the first one at `first`.
+    fn append(&mut self, first: u64, entries: &[Entry]) -> Result<()> {
+        let mut writer = BufWriter::new(&self.log);
+        write_entries(&mut writer, first, entries)?;
+        writer.flush()?;
+        drop(writer);
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the log by `entries`, the first one at `first`.
+    fn rewrite(&mut self, first: u64, entries: &[Entry]) -> Result<()> {
+        let tmp = self.dir.join("log.jsonl.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        write_entries(&mut writer, first, entries)?;
+        writer.into_inner()?.sync_data()?;
+        let path = self.dir.join("log.jsonl");
+        fs::rename(tmp, &path)?;
+        self.log = OpenOptions::new().append(true).open(path)?;
+        Ok(())
+    }
+}
+
+fn write_entries<W: Write>(writer: &mut W, first: u64, entries: &[Entry]) -> Result<()> {
+    for (index, entry) in (first..).zip(entries) {
+        serde_json::to_writer(&mut *writer, &(index, entry))?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Consensus state of a cluster node.
+pub struct Raft {
+    config: ClusterConfig,
+    state: Mutex<RaftState>,
+    /// signaled when the log, the commit index or the role changes
+    changed: Condvar,
+}
+
+struct RaftState {
+    hard: HardState,
+    /// entries after the snapshot, the first one at `hard.snapshot_index + 1`
+    entries: Vec<Entry>,
+    storage: Storage,
+    role: RaftRole,
+    leader: Option<String>,
+    /// last entry known committed
+    commit: u64,
+    /// members as of the last entry
+    members: Vec<String>,
+    election_deadline: Instant,
+    /// last message of the leader
+    last_contact: Option<Instant>,
+    /// voters for this node, while a candidate
+    votes: HashSet<String>,
+    /// state of each follower, while the leader
+    progress: HashMap<String, Progress>,
+    /// when this node became the leader
+    elected: Instant,
+    /// followers with a thread replicating to them
+    replicators: HashSet<String>,
+    /// results of the writes proposed here by index, `None` until applied
+    results: HashMap<u64, Option<Outcome>>,
+    /// snapshot being received from the leader
+    incoming: Option<Incoming>,
+}
+
+/// What the leader knows of a follower.
+#[derive(Clone, Copy)]
+struct Progress {
+    /// next entry to send
+    next: u64,
+    /// last entry known stored on the follower
+    matched: u64,
+    /// when the last message the follower answered was sent
+    acked: Option<Instant>,
+}
+
+/// Snapshot received in parts.
+struct Incoming {
+    index: u64,
+    last_term: u64,
+    members: Vec<String>,
+    next_chunk: u64,
+    pairs: Vec<(String, String)>,
+}
+
+/// Message for a follower, built with the state locked and sent without.
+enum Outgoing {
+    Append(RaftMessage),
+    Snapshot {
+        index: u64,
+        last_term: u64,
+        members: Vec<String>,
+        pairs: Vec<(String, String)>,
+    },
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.hard.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.hard.snapshot_term, |entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.hard.snapshot_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+
+    /// Term of the entry at `index`, `None` if the log doesn't keep it.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.hard.snapshot_index {
+            return Some(self.hard.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    /// Members as of the entry at `index`, which must be in the log.
+    fn members_at(&self, index: u64) -> Vec<String> {
+        let end = (index - self.hard.snapshot_index) as usize;
+        self.entries[..end]
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.command {
+                Command::Members(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.hard.snapshot_members.clone())
+    }
+
+    /// Whether the nodes for which `counts` holds are a majority of the members.
+    fn majority(&self, counts: impl Fn(&str) -> bool) -> bool {
+        let count = self.members.iter().filter(|id| counts(id)).count();
+        count * 2 > self.members.len()
+    }
+
+    /// Whether a majority of the members answered a message of the leader
+    /// `id` sent less than `timeout` ago.
+    fn lease_holds(&self, id: &str, timeout: Duration) -> bool {
+        self.majority(|member| {
+            member == id
+                || self
+                    .progress
+                    .get(member)
+                    .and_then(|progress| progress.acked)
+                    .is_some_and(|sent| sent.elapsed() < timeout)
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        self.storage.save(&self.hard)
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        self.storage.append(self.last_index() + 1, &entries)?;
+        self.entries.extend(entries);
+        self.members = self.members_at(self.last_index());
+        Ok(())
+    }
+
+    /// Drop the entries from `index` on.
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        if index <= self.commit {
+            error!("Drop committed Raft entries from {index}");
+        }
+        self.entries
+            .truncate((index - self.hard.snapshot_index - 1) as usize);
+        self.storage
+            .rewrite(self.hard.snapshot_index + 1, &self.entries)?;
+        self.members = self.members_at(self.last_index());
+        Ok(())
+    }
+
+    fn reset_election(&mut self, timeout: Duration) {
+        let jitter = Uuid::new_v4().as_u128() % (timeout.as_millis() + 1);
+        self.election_deadline = Instant::now() + timeout + Duration::from_millis(jitter as u64);
+    }
+
+    /// Follow whoever leads in `term`, a later term than the current one if
+    /// it is not the same.
+    fn become_follower(&mut self, term: u64) -> Result<()> {
+        if term > self.hard.term {
+            self.hard.term = term;
+            self.hard.voted_for = None;
+            self.leader = None;
+            self.save()?;
+        }
+        if self.role != RaftRole::Follower {
+            info!("Follow in term {term}");
+        }
+        self.role = RaftRole::Follower;
+        self.votes.clear();
+        self.progress.clear();
+        Ok(())
+    }
+
+    fn not_leader(&self) -> ErrorCode {
+        ErrorCode::NotLeader {
+            leader: self.leader.clone(),
+        }
+    }
+}
+
+impl Raft {
+    /// Load the state of a node from `config.dir`.
+    pub fn open(config: ClusterConfig) -> Result<Self> {
+        let (storage, hard, entries) = Storage::open(&config.dir)?;
+        let hard = match hard {
+            Some(hard) => hard,
+            None => {
+                let hard = HardState {
+                    snapshot_members: if config.members.contains(&config.id) {
+                        config.members.clone()
+                    } else {
+                        vec![]
+                    },
+                    ..HardState::default()
+                };
+                storage.save(&hard)?;
+                hard
+            }
+        };
+        let mut state = RaftState {
+            commit: hard.applied,
+            hard,
+            entries,
+            storage,
+            role: RaftRole::Follower,
+            leader: None,
+            members: vec![],
+            election_deadline: Instant::now(),
+            last_contact: None,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            elected: Instant::now(),
+            replicators: HashSet::new(),
+            results: HashMap::new(),
+            incoming: None,
+        };
+        state.members = state.members_at(state.last_index());
+        state.reset_election(config.election_timeout);
+        info!(
+            "Cluster node {} in term {}, members {:?}",
+            config.id, state.hard.term, state.members
+        );
+        Ok(Self {
+            config,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+        })
+    }
+
+    /// State of this node.
+    pub fn status(&self) -> ClusterStatus {
+        let state = self.state.lock().unwrap();
+        ClusterStatus {
+            id: self.config.id.clone(),
+            role: state.role,
+            term: state.hard.term,
+            leader: state.leader.clone(),
+            members: state.members.clone(),
+            last_index: state.last_index(),
+            commit: state.commit,
+            applied: state.hard.applied,
+            snapshot_index: state.hard.snapshot_index,
+        }
+    }
+
+    /// Run a write through the log, and return its result once applied.
+    pub fn write<E: KvsEngine>(&self, shared: &Shared<E>, request: &Request) -> Outcome {
+        let state = self.state.lock().unwrap();
+        self.propose(shared, state, Command::Write(request.clone()))
+    }
+
+    /// Wait until this node may answer reads: it is the leader, holds its
+    /// lease, so no other node leads, and applied an entry of its term, so
+    /// every committed write before it.
+    pub fn read_barrier(&self) -> std::result::Result<(), ErrorCode> {
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.role != RaftRole::Leader {
+                return Err(state.not_leader());
+            }
+            let caught_up = state.term_at(state.hard.applied) == Some(state.hard.term);
+            let leased = state.lease_holds(&self.config.id, self.config.election_timeout);
+            if caught_up && leased {
+                return Ok(());
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                let failure = match caught_up {
+                    true => "Timed out waiting for a majority of the members to answer",
+                    false => "Timed out waiting for the new leader to catch up",
+                };
+                return Err(ErrorCode::Internal(failure.to_owned()));
+            }
+            state = self.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Add the node `id` to the members, or remove it, once the previous
+    /// change is committed.
+    pub fn change_members<E: KvsEngine>(&self, shared: &Shared<E>, id: &str, add: bool) -> Outcome {
+        let state = self.state.lock().unwrap();
+        if state.role != RaftRole::Leader {
+            return Err(state.not_leader());
+        }
+        let pending = (state.commit + 1..=state.last_index()).any(|index| {
+            matches!(
+                state.entry(index).map(|entry| &entry.command),
+                Some(Command::Members(_))
+            )
+        });
+        if pending {
+            return Err(ErrorCode::InvalidRequest(
+                "Another membership change is in progress".to_owned(),
+            ));
+        }
+        let mut members = state.members.clone();
+        if add == members.iter().any(|member| member == id) {
+            return Ok(Reply::Done);
+        }
+        if add {
+            members.push(id.to_owned());
+        } else {
+            members.retain(|member| member != id);
+            if members.is_empty() {
+                return Err(ErrorCode::InvalidRequest(
+                    "Cannot remove the last member".to_owned(),
+                ));
+            }
+        }
+        info!("Change the members to {members:?}");
+        self.propose(shared, state, Command::Members(members))
+    }
+
+    /// Append `command` to the log, and wait until it is applied.
+    fn propose<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        mut state: MutexGuard<RaftState>,
+        command: Command,
+    ) -> Outcome {
+        if state.role != RaftRole::Leader {
+            return Err(state.not_leader());
+        }
+        let term = state.hard.term;
+        state
+            .append(vec![Entry { term, command }])
+            .map_err(|e| ErrorCode::from(&e))?;
+        let index = state.last_index();
+        state.results.insert(index, None);
+        self.changed.notify_all();
+        self.advance_commit(shared, &mut state)
+            .map_err(|e| ErrorCode::from(&e))?;
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if let Some(Some(_)) = state.results.get(&index) {
+                return state.results.remove(&index).flatten().unwrap();
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let failure = if state.role != RaftRole::Leader || state.hard.term != term {
+                "Lost the leadership before the write committed, it may be applied or not"
+            } else if timeout.is_zero() {
+                "Timed out waiting for the write to commit, it may be applied or not"
+            } else {
+                state = self.changed.wait_timeout(state, timeout).unwrap().0;
+                continue;
+            };
+            state.results.remove(&index);
+            return Err(ErrorCode::Internal(failure.to_owned()));
+        }
+    }
+
+    /// Answer a message of another node.
+    pub fn handle<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        message: RaftMessage,
+    ) -> Result<RaftReply> {
+        let mut state = self.state.lock().unwrap();
+        let reply = match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => self.vote(&mut state, term, candidate, (last_term, last_index))?,
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if !self.accept_leader(&mut state, term, leader)? {
+                    return Ok(RaftReply::Appended {
+                        term: state.hard.term,
+                        success: false,
+                        last_index: state.last_index(),
+                    });
+                }
+                self.append_entries(shared, &mut state, prev_index, prev_term, entries, commit)?
+            }
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                index,
+                last_term,
+                members,
+                chunk,
+                pairs,
+                done,
+            } => {
+                if self.accept_leader(&mut state, term, leader)? {
+                    if chunk == 0 {
+                        state.incoming = Some(Incoming {
+                            index,
+                            last_term,
+                            members,
+                            next_chunk: 0,
+                            pairs: vec![],
+                        });
+                    }
+                    match &mut state.incoming {
+                        Some(incoming)
+                            if incoming.index == index && incoming.next_chunk == chunk =>
+                        {
+                            incoming.pairs.extend(pairs);
+                            incoming.next_chunk += 1;
+                        }
+                        // a part is missing, the leader will find out and send it again
+                        _ => state.incoming = None,
+                    }
+                    if done {
+                        if let Some(incoming) = state.incoming.take() {
+                            self.install(shared, &mut state, incoming)?;
+                        }
+                    }
+                }
+                RaftReply::Installed {
+                    term: state.hard.term,
+                }
+            }
+        };
+        self.changed.notify_all();
+        Ok(reply)
+    }
+
+    fn vote(
+        &self,
+        state: &mut RaftState,
+        term: u64,
+        candidate: String,
+        candidate_last: (u64, u64),
+    ) -> Result<RaftReply> {
+        // a node cut off from the leader, or removed from the cluster, must
+        // not depose a leader which still reaches the others
+        let leader_alive = state.role == RaftRole::Leader
+            || state.leader.is_some()
+                && state
+                    .last_contact
+                    .is_some_and(|contact| contact.elapsed() < self.config.election_timeout);
+        if leader_alive {
+            return Ok(RaftReply::Vote {
+                term: state.hard.term,
+                granted: false,
+            });
+        }
+        if term > state.hard.term {
+            state.become_follower(term)?;
+        }
+        let granted = term == state.hard.term
+            && candidate_last >= (state.last_term(), state.last_index())
+            && state
+                .hard
+                .voted_for
+                .as_ref()
+                .map_or(true, |voted_for| *voted_for == candidate);
+        if granted {
+            debug!("Vote for {candidate} in term {term}");
+            state.hard.voted_for = Some(candidate);
+            state.save()?;
+            state.reset_election(self.config.election_timeout);
+        }
+        Ok(RaftReply::Vote {
+            term: state.hard.term,
+            granted,
+        })
+    }
+
+    /// Follow `leader` unless `term` is over.
+    fn accept_leader(&self, state: &mut RaftState, term: u64, leader: String) -> Result<bool> {
+        if term < state.hard.term {
+            return Ok(false);
+        }
+        if term > state.hard.term || state.role != RaftRole::Follower {
+            state.become_follower(term)?;
+        }
+        if state.leader.as_ref() != Some(&leader) {
+            info!("Follow {leader} in term {term}");
+            state.leader = Some(leader);
+        }
+        state.last_contact = Some(Instant::now());
+        state.reset_election(self.config.election_timeout);
+        Ok(true)
+    }
+
+    fn append_entries<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        state: &mut RaftState,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<RaftReply> {
+        let fail = |last_index| RaftReply::Appended {
+            term: state.hard.term,
+            success: false,
+            last_index,
+        };
+        if prev_index > state.last_index() {
+            return Ok(fail(state.last_index()));
+        }
+        if prev_index > state.hard.snapshot_index {
+            let term = state.term_at(prev_index).unwrap();
+            if term != prev_term {
+                // skip the whole conflicting term
+                let mut first = prev_index;
+                while first - 1 > state.hard.snapshot_index
+                    && state.term_at(first - 1) == Some(term)
+                {
+                    first -= 1;
+                }
+                return Ok(fail(first - 1));
+            }
+        }
+
+        let mut index = prev_index;
+        let mut new = vec![];
+        for entry in entries {
+            index += 1;
+            // the entries up to the snapshot are committed, so they match
+            if !new.is_empty() || index <= state.hard.snapshot_index {
+                if index > state.hard.snapshot_index {
+                    new.push(entry);
+                }
+                continue;
+            }
+            match state.term_at(index) {
+                Some(term) if term == entry.term => {}
+                Some(_) => {
+                    state.truncate(index)?;
+                    new.push(entry);
+                }
+                None => new.push(entry),
+            }
+        }
+        if !new.is_empty() {
+            state.append(new)?;
+        }
+        let matched = index.max(state.hard.snapshot_index);
+        state.commit = state.commit.max(commit.min(matched));
+        self.apply(shared, state)?;
+        Ok(RaftReply::Appended {
+            term: state.hard.term,
+            success: true,
+            last_index: matched,
+        })
+    }
+
+    /// Replace the data and the log of this node by a snapshot of the leader.
+    fn install<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        state: &mut RaftState,
+        incoming: Incoming,
+    ) -> Result<()> {
+        if incoming.index <= state.hard.applied {
+            return Ok(());
+        }
+        {
+            let mut engine = shared.engine.lock().unwrap();
+            let keys: HashSet<&String> = incoming.pairs.iter().map(|(key, _)| key).collect();
+            for (key, _) in engine.scan(String::new())? {
+                if !keys.contains(&key) {
+                    engine.remove(key)?;
+                }
+            }
+            engine.mset(incoming.pairs)?;
+        }
+        // keep the entries after the snapshot if they follow it
+        if state.term_at(incoming.index) == Some(incoming.last_term) {
+            let end = (incoming.index - state.hard.snapshot_index) as usize;
+            state.entries.drain(..end);
+        } else {
+            state.entries.clear();
+        }
+        state.hard.snapshot_index = incoming.index;
+        state.hard.snapshot_term = incoming.last_term;
+        state.hard.snapshot_members = incoming.members;
+        state.hard.applied = incoming.index;
+        state.commit = state.commit.max(incoming.index);
+        state.save()?;
+        state.storage.rewrite(incoming.index + 1, &state.entries)?;
+        state.members = state.members_at(state.last_index());
+        info!("Installed a snapshot up to entry {}", incoming.index);
+        Ok(())
+    }
+
+    /// Start an election if the leader is silent, or the threads replicating
+    /// to new followers if this node leads.
+    fn tick<E: KvsEngine + Send + 'static>(&self, shared: &Arc<Shared<E>>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = &self.config.id;
+        let timeout = self.config.election_timeout;
+        if state.role == RaftRole::Leader
+            && state.elected.elapsed() >= timeout
+            && !state.lease_holds(id, timeout)
+        {
+            warn!(
+                "Lost touch with a majority of the members, step down in term {}",
+                state.hard.term
+            );
+            let term = state.hard.term;
+            state.become_follower(term)?;
+            state.leader = None;
+            state.reset_election(timeout);
+            self.changed.notify_all();
+            return Ok(());
+        }
+        if state.role == RaftRole::Leader {
+            let next = state.last_index() + 1;
+            for peer in state.members.clone() {
+                if peer != *id && state.replicators.insert(peer.clone()) {
+                    state.progress.entry(peer.clone()).or_insert(Progress {
+                        next,
+                        matched: 0,
+                        acked: None,
+                    });
+                    let shared = shared.clone();
+                    let term = state.hard.term;
+                    thread::spawn(move || replicate(&shared, peer, term));
+                }
+            }
+            return Ok(());
+        }
+        if Instant::now() < state.election_deadline {
+            return Ok(());
+        }
+        state.reset_election(self.config.election_timeout);
+        if !state.members.contains(id) {
+            return Ok(());
+        }
+
+        state.hard.term += 1;
+        state.hard.voted_for = Some(id.clone());
+        state.save()?;
+        state.role = RaftRole::Candidate;
+        state.leader = None;
+        state.votes = HashSet::from([id.clone()]);
+        let term = state.hard.term;
+        info!("Start an election in term {term}");
+        if state.majority(|member| state.votes.contains(member)) {
+            return self.become_leader(shared, &mut state);
+        }
+        let message = RaftMessage::RequestVote {
+            term,
+            candidate: id.clone(),
+            last_index: state.last_index(),
+            last_term: state.last_term(),
+        };
+        for peer in state.members.iter().filter(|peer| *peer != id) {
+            let shared = shared.clone();
+            let peer = peer.clone();
+            let message = message.clone();
+            thread::spawn(move || request_vote(&shared, peer, term, message));
+        }
+        Ok(())
+    }
+
+    fn become_leader<E: KvsEngine>(&self, shared: &Shared<E>, state: &mut RaftState) -> Result<()> {
+        info!("Lead in term {}", state.hard.term);
+        state.role = RaftRole::Leader;
+        state.leader = Some(self.config.id.clone());
+        state.progress.clear();
+        state.elected = Instant::now();
+        let term = state.hard.term;
+        state.append(vec![Entry {
+            term,
+            command: Command::Noop,
+        }])?;
+        self.advance_commit(shared, state)?;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Commit the last entry of the current term stored by a majority.
+    fn advance_commit<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        state: &mut RaftState,
+    ) -> Result<()> {
+        if state.role != RaftRole::Leader {
+            return Ok(());
+        }
+        let last_index = state.last_index();
+        let stored = |id: &str, index: u64| {
+            if id == self.config.id {
+                last_index >= index
+            } else {
+                state
+                    .progress
+                    .get(id)
+                    .is_some_and(|progress| progress.matched >= index)
+            }
+        };
+        // an entry of a previous term only commits with a later one
+        let commit = (state.commit + 1..=last_index)
+            .rev()
+            .take_while(|index| state.term_at(*index) == Some(state.hard.term))
+            .find(|index| state.majority(|id| stored(id, *index)));
+        if let Some(commit) = commit {
+            state.commit = commit;
+            self.apply(shared, state)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the committed entries to the engine.
+    fn apply<E: KvsEngine>(&self, shared: &Shared<E>, state: &mut RaftState) -> Result<()> {
+        if state.hard.applied >= state.commit {
+            return Ok(());
+        }
+        {
+            let mut engine = shared.engine.lock().unwrap();
+            while state.hard.applied < state.commit {
+                let index = state.hard.applied + 1;
+                let result = match &state.entry(index).unwrap().command {
+                    Command::Write(request) => {
+                        let result = write(&mut *engine, request);
+                        if let Ok(reply) = &result {
+                            shared.changes.record(request, reply);
+                        }
+                        result.map_err(|e| ErrorCode::from(&e))
+                    }
+                    Command::Noop | Command::Members(_) => Ok(Reply::Done),
+                };
+                state.hard.applied = index;
+                if let Some(slot) = state.results.get_mut(&index) {
+                    *slot = Some(result);
+                }
+            }
+        }
+        state.save()?;
+
+        // a leader removed from the cluster leaves once the change committed
+        if state.role == RaftRole::Leader
+            && !state.members_at(state.commit).contains(&self.config.id)
+        {
+            info!("Removed from the cluster, stop leading");
+            state.role = RaftRole::Follower;
+            state.leader = None;
+            state.progress.clear();
+        }
+        if state.hard.applied - state.hard.snapshot_index >= self.config.snapshot_threshold {
+            self.compact(state)?;
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Drop the applied entries from the log, the engine holds their effect.
+    fn compact(&self, state: &mut RaftState) -> Result<()> {
+        let index = state.hard.applied;
+        let term = state.term_at(index).unwrap();
+        let members = state.members_at(index);
+        let end = (index - state.hard.snapshot_index) as usize;
+        state.entries.drain(..end);
+        state.hard.snapshot_index = index;
+        state.hard.snapshot_term = term;
+        state.hard.snapshot_members = members;
+        // the state first: a log with entries before the snapshot is fine
+        state.save()?;
+        state.storage.rewrite(index + 1, &state.entries)?;
+        debug!("Compacted the Raft log up to entry {index}");
+        Ok(())
+    }
+
+    /// Wait until `peer` needs a message, and build it, or return `None` once
+    /// this node doesn't lead in `term` or `peer` left.
+    fn next_message<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        peer: &str,
+        term: u64,
+        last_sent: Option<Instant>,
+    ) -> Result<Option<Outgoing>> {
+        let mut state = self.state.lock().unwrap();
+        let heartbeat = self.config.heartbeat_interval;
+        let progress = loop {
+            let leads = state.role == RaftRole::Leader && state.hard.term == term;
+            let progress = match state.progress.get(peer) {
+                Some(progress) if leads && state.members.iter().any(|id| id == peer) => *progress,
+                _ => {
+                    state.replicators.remove(peer);
+                    return Ok(None);
+                }
+            };
+            let since = last_sent.map_or(heartbeat, |sent| sent.elapsed());
+            if progress.next <= state.last_index() || since >= heartbeat {
+                break progress;
+            }
+            state = self
+                .changed
+                .wait_timeout(state, heartbeat - since)
+                .unwrap()
+                .0;
+        };
+
+        if progress.next <= state.hard.snapshot_index {
+            // the entries are gone, send the data they led to
+            let index = state.hard.applied;
+            let pairs = shared.engine.lock().unwrap().scan(String::new())?;
+            return Ok(Some(Outgoing::Snapshot {
+                index,
+                last_term: state.term_at(index).unwrap(),
+                members: state.members_at(index),
+                pairs,
+            }));
+        }
+        let prev_index = progress.next - 1;
+        let end = state.last_index().min(prev_index + MAX_ENTRIES as u64);
+        let entries = (progress.next..=end)
+            .map(|index| state.entry(index).unwrap().clone())
+            .collect();
+        Ok(Some(Outgoing::Append(RaftMessage::AppendEntries {
+            term,
+            leader: self.config.id.clone(),
+            prev_index,
+            prev_term: state.term_at(prev_index).unwrap(),
+            entries,
+            commit: state.commit,
+        })))
+    }
+
+    /// Update what this node knows of `peer` after its `reply` in `term` to
+    /// a message sent at `sent`.
+    fn on_reply<E: KvsEngine>(
+        &self,
+        shared: &Shared<E>,
+        peer: &str,
+        term: u64,
+        sent: Instant,
+        snapshot_index: Option<u64>,
+        reply: RaftReply,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let reply_term = match reply {
+            RaftReply::Vote { term, .. }
+            | RaftReply::Appended { term, .. }
+            | RaftReply::Installed { term } => term,
+        };
+        if reply_term > state.hard.term {
+            state.become_follower(reply_term)?;
+            self.changed.notify_all();
+            return Ok(());
+        }
+        if state.role != RaftRole::Leader || state.hard.term != term {
+            return Ok(());
+        }
+        let leased = state.lease_holds(&self.config.id, self.config.election_timeout);
+        let Some(progress) = state.progress.get_mut(peer) else {
+            return Ok(());
+        };
+        // the peer follows this node, whatever it answers
+        progress.acked = progress.acked.max(Some(sent));
+        match (reply, snapshot_index) {
+            (
+                RaftReply::Appended {
+                    success: true,
+                    last_index,
+                    ..
+                },
+                None,
+            ) => {
+                progress.matched = progress.matched.max(last_index);
+                progress.next = progress.matched + 1;
+                self.advance_commit(shared, &mut state)?;
+            }
+            (
+                RaftReply::Appended {
+                    success: false,
+                    last_index,
+                    ..
+                },
+                None,
+            ) => {
+                progress.next = (progress.next - 1).min(last_index + 1).max(1);
+            }
+            (RaftReply::Installed { .. }, Some(index)) => {
+                progress.matched = progress.matched.max(index);
+                progress.next = progress.matched + 1;
+            }
+            (reply, _) => warn!("Unexpected answer from {peer}: {reply:?}"),
+        }
+        if !leased && state.lease_holds(&self.config.id, self.config.election_timeout) {
+            // wake the reads waiting for the lease
+            self.changed.notify_all();
+        }
+        Ok(())
+    }
+
+    /// Connect to another node.
+    fn connect(&self, peer: &str, timeout: Duration) -> Result<KvsClient> {
+        let mut builder = KvsClient::builder(&peer.parse()?)
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .write_timeout(timeout)
+            .retry_policy(RetryPolicy {
+                max_retries: 0,
+                ..RetryPolicy::default()
+            });
+        if let Some(credentials) = &self.config.credentials {
+            builder = builder.credentials(credentials.clone());
+        }
+        builder.build()
+    }
+}
+
+/// Run a write on the engine.
+pub(super) fn write<E: KvsEngine>(engine: &mut E, request: &Request) -> Result<Reply> {
+    match request {
+        Request::Set { key, value } => engine
+            .set(key.to_owned(), value.to_owned())
+            .map(|()| Reply::Done),
+        Request::Remove { key } => engine.remove(key.to_owned()).map(|()| Reply::Done),
+        Request::MSet { pairs } => engine.mset(pairs.to_owned()).map(|()| Reply::Done),
+        Request::CompareAndSet {
+            key,
+            expected,
+            value,
+        } => engine
+            .compare_and_set(key.to_owned(), expected.to_owned(), value.to_owned())
+            .map(Reply::Swapped),
+        _ => Err(KvsError::InvalidRequest(format!("Not a write: {request:?}")).into()),
+    }
+}
+
+/// Watch the leader and run the elections, forever.
+pub fn run<E: KvsEngine + Send + 'static>(shared: Arc<Shared<E>>) {
+    let raft = shared.raft.get().unwrap();
+    loop {
+        thread::sleep(TICK);
+        if let Err(e) = raft.tick(&shared) {
+            error!("Cluster node failed: {e}");
+        }
+    }
+}
+
+/// Ask `peer` for its vote in `term`.
+fn request_vote<E: KvsEngine>(shared: &Shared<E>, peer: String, term: u64, message: RaftMessage) {
+    let raft = shared.raft.get().unwrap();
+    let reply = raft
+        .connect(&peer, raft.config.election_timeout)
+        .and_then(|mut client| client.raft(message));
+    let (reply_term, granted) = match reply {
+        Ok(RaftReply::Vote { term, granted }) => (term, granted),
+        Ok(reply) => return warn!("Unexpected answer from {peer}: {reply:?}"),
+        Err(e) => return debug!("No vote from {peer}: {e}"),
+    };
+    let mut state = raft.state.lock().unwrap();
+    let result = if reply_term > state.hard.term {
+        state.become_follower(reply_term)
+    } else if granted && state.role == RaftRole::Candidate && state.hard.term == term {
+        debug!("{peer} votes for this node in term {term}");
+        state.votes.insert(peer);
+        if state.majority(|member| state.votes.contains(member)) {
+            raft.become_leader(shared, &mut state)
+        } else {
+            Ok(())
+        }
+    } else {
+        Ok(())
+    };
+    if let Err(e) = result {
+        error!("Cluster node failed: {e}");
+    }
+}
+
+/// Send the log of the leader of `term` to `peer`, for as long as it leads.
+fn replicate<E: KvsEngine>(shared: &Shared<E>, peer: String, term: u64) {
+    let raft = shared.raft.get().unwrap();
+    debug!("Replicate to {peer} in term {term}");
+    let mut client = None;
+    let mut last_sent = None;
+    loop {
+        let outgoing = match raft.next_message(shared, &peer, term, last_sent) {
+            Ok(Some(outgoing)) => outgoing,
+            Ok(None) => return debug!("Stop replicating to {peer}"),
+            Err(e) => {
+                error!("Replicating to {peer} failed: {e}");
+                thread::sleep(raft.config.heartbeat_interval);
+                continue;
+            }
+        };
+        let sent = Instant::now();
+        last_sent = Some(sent);
+        let snapshot_index = match &outgoing {
+            Outgoing::Snapshot { index, .. } => Some(*index),
+            Outgoing::Append(_) => None,
+        };
+        let result = match &mut client {
+            Some(client) => send(raft, client, &peer, term, outgoing),
+            None => raft
+                .connect(&peer, PEER_TIMEOUT)
+                .and_then(|connected| send(raft, client.insert(connected), &peer, term, outgoing)),
+        };
+        match result {
+            Ok(reply) => {
+                if let Err(e) = raft.on_reply(shared, &peer, term, sent, snapshot_index, reply) {
+                    error!("Cluster node failed: {e}");
+                }
+            }
+            Err(e) => {
+                debug!("Cannot reach {peer}: {e}");
+                client = None;
+                thread::sleep(raft.config.heartbeat_interval);
+            }
+        }
+    }
+}
+
+/// Send `outgoing` from the leader of `term`, in parts for a snapshot.
+fn send(
+    raft: &Raft,
+    client: &mut KvsClient,
+    peer: &str,
+    term: u64,
+    outgoing: Outgoing,
+) -> Result<RaftReply> {
+    let (index, last_term, members, pairs) = match outgoing {
+        Outgoing::Append(message) => return client.raft(message),
+        Outgoing::Snapshot {
+            index,
+            last_term,
+            members,
+            pairs,
+        } => (index, last_term, members, pairs),
+    };
+    info!("Send a snapshot up to entry {index} to {peer}");
+    let chunks: Vec<&[(String, String)]> = match pairs.is_empty() {
+        true => vec![&[]],
+        false => pairs.chunks(SNAPSHOT_CHUNK).collect(),
+    };
+    let mut reply = None;
+    for (chunk, pairs) in chunks.iter().enumerate() {
+        let installed = client.raft(RaftMessage::InstallSnapshot {
+            term,
+            leader: raft.config.id.clone(),
+            index,
+            last_term,
+            members: members.clone(),
+            chunk: chunk as u64,
+            pairs: pairs.to_vec(),
+            done: chunk + 1 == chunks.len(),
+        })?;
+        if matches!(installed, RaftReply::Installed { term: reply_term } if reply_term > term) {
+            return Ok(installed);
+        }
+        reply = Some(installed);
+    }
+    Ok(reply.unwrap())
+}
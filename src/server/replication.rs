@@ -0,0 +1,406 @@
+//! Leader-follower replication.
+//!
+//! Every write of a server is numbered and kept in a `ChangeLog`. A replica
+//! sends `Replicate` to its leader, which answers with a snapshot of its data
+//! and then streams its changes as they happen. A replica which reconnects
+//! resumes after the last change it applied, if the leader still has the
+//! changes since then, and starts over with a snapshot otherwise.
+//!
+//! The snapshot is read page by page, releasing the engine in between, so
+//! it may already hold some of the changes which follow it; applying them
+//! again gives the same data. Its pages come in key order, which lets the
+//! replica remove the keys the leader doesn't have one page at a time.
+
+use std::{
+    collections::VecDeque,
+    io::Write,
+    mem,
+    sync::{atomic::Ordering, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use super::Shared;
+use crate::client::{KvsClientBuilder, RetryPolicy};
+use crate::codec::Codec;
+use crate::dump::{for_each_page, SCAN_PAGE_SIZE};
+use crate::transport::{
+    Change, Position, ReplicationEvent, ReplicationStatus, Reply, Request, Response,
+};
+use crate::{KvsEngine, KvsError, Result};
+
+/// Most bytes of changes kept for the replicas which fall behind.
+const MAX_BACKLOG_BYTES: usize = 64 << 20;
+/// Wait between two heartbeats when there are no changes.
+pub(super) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before a replica connects to its leader again.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Recent writes of a server, numbered from 1.
+pub struct ChangeLog {
+    inner: Mutex<Backlog>,
+    /// signaled when a change is recorded
+    recorded: Condvar,
+}
+
+struct Backlog {
+    /// sequence number of the last change
+    seq: u64,
+    /// the last changes, up to `MAX_BACKLOG_BYTES`
+    changes: VecDeque<(u64, Change)>,
+    /// bytes of `changes`
+    bytes: usize,
+}
+
+/// Bytes a change takes in the backlog.
+fn backlog_bytes(change: &Change) -> usize {
+    let data = match change {
+        Change::Set { key, value } => key.len() + value.len(),
+        Change::Remove { key } => key.len(),
+    };
+    mem::size_of::<(u64, Change)>() + data
+}
+
+impl ChangeLog {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Backlog {
+                seq: 0,
+                changes: VecDeque::new(),
+                bytes: 0,
+            }),
+            recorded: Condvar::new(),
+        }
+    }
+
+    /// Sequence number of the last change.
+    pub fn seq(&self) -> u64 {
+        self.inner.lock().unwrap().seq
+    }
+
+    /// Record the changes made by `request`, with the engine still locked so
+    /// that the order of the changes is the order of the writes.
+    pub fn record(&self, request: &Request, reply: &Reply) {
+        let changes = match (request, reply) {
+            (Request::Set { key, value }, _)
+            | (Request::CompareAndSet { key, value, .. }, Reply::Swapped(true)) => {
+                vec![Change::Set {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                }]
+            }
+            (Request::Remove { key }, _) => vec![Change::Remove {
+                key: key.to_owned(),
+            }],
+            (Request::MSet { pairs }, _) => pairs
+                .iter()
+                .map(|(key, value)| Change::Set {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                })
+                .collect(),
+            _ => return,
+        };
+        self.push(changes);
+    }
+
+    fn push(&self, changes: Vec<Change>) {
+        let mut backlog = self.inner.lock().unwrap();
+        for change in changes {
+            backlog.seq += 1;
+            backlog.bytes += backlog_bytes(&change);
+            let seq = backlog.seq;
+            backlog.changes.push_back((seq, change));
+        }
+        while backlog.bytes > MAX_BACKLOG_BYTES {
+            let Some((_, change)) = backlog.changes.pop_front() else {
+                break;
+            };
+            backlog.bytes -= backlog_bytes(&change);
+        }
+        drop(backlog);
+        self.recorded.notify_all();
+    }
+
+    /// The changes after `seq`, waiting up to `timeout` for one if there are
+    /// none yet, or `None` if some of them are not kept any more.
+    pub(super) fn after(&self, seq: u64, timeout: Duration) -> Option<Vec<(u64, Change)>> {
+        let mut backlog = self.inner.lock().unwrap();
+        if backlog.seq == seq {
+            backlog = self.recorded.wait_timeout(backlog, timeout).unwrap().0;
+        }
+        let first = backlog
+            .changes
+            .front()
+            .map_or(backlog.seq + 1, |(seq, _)| *seq);
+        if seq > backlog.seq || seq + 1 < first {
+            return None;
+        }
+        let skip = (seq + 1 - first) as usize;
+        Some(backlog.changes.iter().skip(skip).cloned().collect())
+    }
+}
+
+/// State of a replica, updated by the thread following the leader.
+pub struct Follower {
+    /// address of the leader
+    pub leader: String,
+    state: Mutex<FollowerState>,
+}
+
+#[derive(Default)]
+struct FollowerState {
+    connected: bool,
+    /// last change of the leader applied here
+    position: Option<Position>,
+    /// last change of the leader
+    head: u64,
+    last_contact: Option<Instant>,
+}
+
+impl Follower {
+    pub fn new(leader: String) -> Self {
+        Self {
+            leader,
+            state: Mutex::default(),
+        }
+    }
+}
+
+impl<E: KvsEngine> Shared<E> {
+    /// Replication state of this server.
+    pub(super) fn replication_status(&self) -> ReplicationStatus {
+        let mut status = ReplicationStatus {
+            seq: self.changes.seq(),
+            replicas: self.replicas.load(Ordering::SeqCst),
+            ..ReplicationStatus::default()
+        };
+        if let Some(follower) = self.follower.get() {
+            let state = follower.state.lock().unwrap();
+            let applied = state.position.as_ref().map_or(0, |position| position.seq);
+            status.leader = Some(follower.leader.clone());
+            status.connected = state.connected;
+            status.applied = applied;
+            status.lag = state.head.saturating_sub(applied);
+            status.last_contact_ms = state
+                .last_contact
+                .map(|contact| contact.elapsed().as_millis() as u64);
+        }
+        status
+    }
+
+    /// Apply the changes of the leader, and record them for the replicas of
+    /// this server.
+    fn apply(&self, changes: Vec<Change>) -> Result<()> {
+        let mut engine = self.engine.lock().unwrap();
+        for change in &changes {
+            match change {
+                Change::Set { key, value } => engine.set(key.to_owned(), value.to_owned())?,
+                Change::Remove { key } => match engine.remove(key.to_owned()) {
+                    Err(e) if matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => {}
+                    result => result?,
+                },
+            }
+        }
+        self.changes.push(changes);
+        Ok(())
+    }
+}
+
+/// Stream the data and then its changes to a replica, until the connection
+/// fails.
+pub fn stream<E: KvsEngine, W: Write>(
+    shared: &Shared<E>,
+    stream: &mut W,
+    codec: &Codec,
+    id: u64,
+    from: Option<Position>,
+) -> Result<()> {
+    shared.replicas.fetch_add(1, Ordering::SeqCst);
+    let result = stream_changes(shared, stream, codec, id, from);
+    shared.replicas.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+fn stream_changes<E: KvsEngine, W: Write>(
+    shared: &Shared<E>,
+    stream: &mut W,
+    codec: &Codec,
+    id: u64,
+    from: Option<Position>,
+) -> Result<()> {
+    let send = |stream: &mut W, event| {
+        codec.send(
+            stream,
+            &Response {
+                id,
+                result: Ok(Reply::Replication(event)),
+            },
+        )
+    };
+    let mut seq = match from {
+        Some(position) if position.server_id == shared.info.id => Some(position.seq),
+        _ => None,
+    };
+    loop {
+        let Some(after) = seq else {
+            // the pages hold every change up to here, and maybe later ones,
+            // which are sent again after the snapshot
+            let snapshot_seq = shared.changes.seq();
+            send(stream, ReplicationEvent::SnapshotStart)?;
+            for_each_page(
+                "",
+                |prefix, start_after| {
+                    let mut engine = shared.engine.lock().unwrap();
+                    engine.scan_page(prefix, start_after, SCAN_PAGE_SIZE as usize)
+                },
+                |pairs| send(stream, ReplicationEvent::SnapshotChunk(pairs)),
+            )?;
+            send(stream, ReplicationEvent::SnapshotEnd { seq: snapshot_seq })?;
+            seq = Some(snapshot_seq);
+            continue;
+        };
+        match shared.changes.after(after, HEARTBEAT_INTERVAL) {
+            Some(changes) if changes.is_empty() => {
+                let head = shared.changes.seq();
+                send(stream, ReplicationEvent::Heartbeat { head })?;
+            }
+            Some(changes) => {
+                seq = changes.last().map(|(seq, _)| *seq);
+                let head = shared.changes.seq();
+                send(stream, ReplicationEvent::Changes { changes, head })?;
+            }
+            // fell too far behind
+            None => seq = None,
+        }
+    }
+}
+
+/// Remove the keys of the replica after `start_after` and up to `end`, or
+/// to the last one if `end` is `None`, which the sorted `pairs` of the
+/// snapshot don't have.
+fn remove_missing<E: KvsEngine>(
+    shared: &Shared<E>,
+    start_after: Option<String>,
+    end: Option<&str>,
+    pairs: &[(String, String)],
+) -> Result<()> {
+    let mut start_after = start_after;
+    loop {
+        let page = shared.engine.lock().unwrap().scan_page(
+            String::new(),
+            start_after,
+            SCAN_PAGE_SIZE as usize,
+        )?;
+        let mut changes = vec![];
+        for (key, _) in page.pairs {
+            if end.is_some_and(|end| key.as_str() > end) {
+                return shared.apply(changes);
+            }
+            if pairs.binary_search_by(|(k, _)| k.cmp(&key)).is_err() {
+                changes.push(Change::Remove { key });
+            }
+        }
+        shared.apply(changes)?;
+        match page.next {
+            Some(next) => start_after = Some(next),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Copy the data of the leader, then apply its changes, reconnecting whenever
+/// the connection fails.
+pub fn follow<E: KvsEngine>(shared: &Shared<E>, leader: KvsClientBuilder) {
+    let follower = shared.follower.get().unwrap();
+    let leader = leader
+        .read_timeout(HEARTBEAT_INTERVAL * 5)
+        .retry_policy(RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        });
+    loop {
+        if let Err(e) = follow_once(shared, follower, &leader) {
+            warn!("Replication from {} failed: {e}", follower.leader);
+        }
+        follower.state.lock().unwrap().connected = false;
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+fn follow_once<E: KvsEngine>(
+    shared: &Shared<E>,
+    follower: &Follower,
+    leader: &KvsClientBuilder,
+) -> Result<()> {
+    let client = leader.clone().build()?;
+    let server_id = client
+        .capabilities()
+        .server
+        .as_ref()
+        .map(|server| server.id.clone())
+        .unwrap_or_default();
+    let from = follower.state.lock().unwrap().position.clone();
+    let events = client.replicate(from)?;
+    info!("Replicating from {}", follower.leader);
+    follower.state.lock().unwrap().connected = true;
+
+    // while a snapshot loads, its last key so far
+    let mut loaded: Option<Option<String>> = None;
+    for event in events {
+        let event = event?;
+        let mut position = None;
+        let mut head = None;
+        match event {
+            ReplicationEvent::SnapshotStart => {
+                info!("Loading a snapshot from {}", follower.leader);
+                loaded = Some(None);
+            }
+            ReplicationEvent::SnapshotChunk(pairs) => {
+                let last = loaded.as_mut().ok_or(KvsError::UnexpectedResponse)?;
+                let Some((end, _)) = pairs.last() else {
+                    continue;
+                };
+                let end = end.clone();
+                remove_missing(shared, last.take(), Some(&end), &pairs)?;
+                *last = Some(end);
+                let changes = pairs
+                    .into_iter()
+                    .map(|(key, value)| Change::Set { key, value })
+                    .collect();
+                shared.apply(changes)?;
+            }
+            ReplicationEvent::SnapshotEnd { seq } => {
+                let last = loaded.take().ok_or(KvsError::UnexpectedResponse)?;
+                remove_missing(shared, last, None, &[])?;
+                info!("Loaded a snapshot from {}", follower.leader);
+                position = Some(seq);
+                head = Some(seq);
+            }
+            ReplicationEvent::Changes {
+                changes,
+                head: leader_head,
+            } => {
+                position = changes.last().map(|(seq, _)| *seq);
+                shared.apply(changes.into_iter().map(|(_, change)| change).collect())?;
+                head = Some(leader_head);
+            }
+            ReplicationEvent::Heartbeat { head: leader_head } => head = Some(leader_head),
+        }
+
+        let mut state = follower.state.lock().unwrap();
+        state.last_contact = Some(Instant::now());
+        if let Some(seq) = position {
+            state.position = Some(Position {
+                server_id: server_id.clone(),
+                seq,
+            });
+        }
+        if let Some(head) = head {
+            state.head = head;
+        }
+    }
+    Err(KvsError::IO(std::io::ErrorKind::UnexpectedEof.into()).into())
+}
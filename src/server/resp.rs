@@ -0,0 +1,631 @@
+//! Redis serialization protocol (RESP2 and RESP3) front end.
+//!
+//! Commands are mapped onto native requests, so authentication, ACLs and rate
+//! limits work as they do for native clients. Supported commands are GET, SET,
+//! DEL, EXISTS, MGET, MSET, SCAN, PING, INFO and PUBLISH, plus AUTH, HELLO and
+//! QUIT to manage the connection. A connection speaks RESP2 until `HELLO 3`.
+//!
+//! After SUBSCRIBE or PSUBSCRIBE, the connection only carries the messages of
+//! its channels, and the commands the client sends are ignored.
+//!
+//! `AUTH password` (or the Redis `default` user) presents a token, and
+//! `AUTH user password` a user name and password.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    sync::atomic::Ordering,
+};
+
+use log::{debug, info};
+
+use super::pubsub::OVERFLOWED;
+use super::replication::HEARTBEAT_INTERVAL;
+use super::{Session, Shared, MAX_HELD_RESPONSES};
+use crate::acl::{glob_match, Op};
+use crate::auth::Credentials;
+use crate::net::Stream;
+use crate::transport::{ErrorCode, Reply, Request};
+use crate::{KvsEngine, KvsError, Result};
+
+/// Longest line (command name or length header) accepted from a client.
+const MAX_LINE: u64 = 64 << 10;
+/// Longest bulk string accepted from a client.
+const MAX_BULK_LEN: usize = 64 << 20;
+/// Most arguments accepted in one command.
+const MAX_ARGS: usize = 1 << 20;
+
+type CommandResult = std::result::Result<(), ErrorCode>;
+
+/// Names of the channels and patterns a connection subscribed to.
+type Subscription = (Vec<String>, Vec<String>);
+
+/// Serve one RESP client.
+pub fn serve<E: KvsEngine, S: Stream>(shared: &Shared<E>, stream: S) -> Result<()> {
+    let mut session = Session::new(shared, &stream);
+    let mut stream = BufReader::new(stream);
+    let mut out = Output {
+        buf: vec![],
+        resp3: false,
+    };
+
+    loop {
+        let args = match read_command(&mut stream) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                // like Redis, answer a protocol error and close the connection
+                if let Some(KvsError::InvalidRequest(reason)) = e.downcast_ref() {
+                    out.error(&format!("ERR Protocol error: {reason}"));
+                    stream.get_mut().write_all(&out.buf)?;
+                }
+                return Err(e);
+            }
+        };
+        let Some((name, args)) = args.split_first() else {
+            continue;
+        };
+        info!("Got request from {}", session.peer_addr);
+        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+        debug!("RESP command from {}: {}", session.peer_addr, name);
+
+        let quit = name == "QUIT";
+        let subscription = match args
+            .iter()
+            .map(|arg| String::from_utf8(arg.to_vec()))
+            .collect()
+        {
+            Ok(args) => run(&mut session, &mut out, &name, args),
+            Err(_) => {
+                out.error("ERR kvs only stores UTF-8 strings");
+                None
+            }
+        };
+
+        // write the replies of pipelined commands together
+        if quit
+            || subscription.is_some()
+            || stream.buffer().is_empty()
+            || out.buf.len() >= MAX_HELD_RESPONSES
+        {
+            let stream = stream.get_mut();
+            stream.write_all(&out.buf)?;
+            stream.flush()?;
+            out.buf.clear();
+        }
+        if quit {
+            break;
+        }
+        if let Some((channels, patterns)) = subscription {
+            return push_messages(shared, stream.get_mut(), out, channels, patterns);
+        }
+    }
+    Ok(())
+}
+
+/// Run one command and write its reply to `out`, and return the subscription
+/// the connection turns into, if any.
+fn run<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Output,
+    name: &str,
+    args: Vec<String>,
+) -> Option<Subscription> {
+    let n = args.len();
+    let arity_ok = match name {
+        "PING" | "INFO" => n <= 1,
+        "QUIT" | "HELLO" => true,
+        "AUTH" => n == 1 || n == 2,
+        "GET" => n == 1,
+        "SET" => n >= 2,
+        "PUBLISH" => n == 2,
+        "DEL" | "EXISTS" | "MGET" | "SCAN" | "SUBSCRIBE" | "PSUBSCRIBE" => n >= 1,
+        "MSET" => n >= 2 && n % 2 == 0,
+        _ => {
+            let args: Vec<String> = args.iter().take(3).map(|arg| format!("'{arg}'")).collect();
+            out.error(&format!(
+                "ERR unknown command '{name}', with args beginning with: {}",
+                args.join(" ")
+            ));
+            return None;
+        }
+    };
+    if !arity_ok {
+        out.error(&format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ));
+        return None;
+    }
+
+    if name == "SUBSCRIBE" || name == "PSUBSCRIBE" {
+        return match subscribe(session, out, name, args) {
+            Ok(subscription) => Some(subscription),
+            Err(code) => {
+                out.error(&error_message(code));
+                None
+            }
+        };
+    }
+    let result = match name {
+        "PING" => ping(session, out, args),
+        "QUIT" => {
+            out.simple("OK");
+            Ok(())
+        }
+        "AUTH" => auth(session, out, args),
+        "HELLO" => hello(session, out, args),
+        "INFO" => info(session, out),
+        "SCAN" => scan(session, out, args),
+        "PUBLISH" => publish(session, out, args),
+        _ => data(session, out, name, args),
+    };
+    if let Err(code) = result {
+        out.error(&error_message(code));
+    }
+    None
+}
+
+fn ping<E: KvsEngine>(session: &Session<E>, out: &mut Output, args: Vec<String>) -> CommandResult {
+    if session.needs_auth() {
+        return Err(ErrorCode::AuthRequired);
+    }
+    match args.first() {
+        Some(message) => out.bulk(Some(message)),
+        None => out.simple("PONG"),
+    }
+    Ok(())
+}
+
+fn auth<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Output,
+    mut args: Vec<String>,
+) -> CommandResult {
+    let password = args.pop().unwrap_or_default();
+    session.handle(&Request::Auth {
+        credentials: credentials(args.pop(), password),
+    })?;
+    out.simple("OK");
+    Ok(())
+}
+
+/// Map the Redis `AUTH` arguments onto our credentials.
+fn credentials(user: Option<String>, password: String) -> Credentials {
+    match user {
+        Some(user) if user != "default" => Credentials::Password { user, password },
+        _ => Credentials::Token(password),
+    }
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+fn hello<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Output,
+    args: Vec<String>,
+) -> CommandResult {
+    let syntax_error = || ErrorCode::InvalidRequest("syntax error".to_owned());
+    let mut args = args.into_iter();
+    let resp3 = match args.next().as_deref() {
+        None => out.resp3,
+        Some("2") => false,
+        Some("3") => true,
+        Some(_) => return Err(ErrorCode::UnsupportedVersion { supported: 3 }),
+    };
+    while let Some(option) = args.next() {
+        match option.to_ascii_uppercase().as_str() {
+            "AUTH" => {
+                let (Some(user), Some(password)) = (args.next(), args.next()) else {
+                    return Err(syntax_error());
+                };
+                session.handle(&Request::Auth {
+                    credentials: credentials(Some(user), password),
+                })?;
+            }
+            "SETNAME" => {
+                args.next().ok_or_else(syntax_error)?;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    if session.needs_auth() {
+        return Err(ErrorCode::AuthRequired);
+    }
+
+    out.resp3 = resp3;
+    out.map_len(6);
+    out.bulk(Some("server"));
+    out.bulk(Some("kvs"));
+    out.bulk(Some("version"));
+    out.bulk(Some(&session.shared.info.version));
+    out.bulk(Some("proto"));
+    out.integer(if resp3 { 3 } else { 2 });
+    out.bulk(Some("mode"));
+    out.bulk(Some("standalone"));
+    out.bulk(Some("role"));
+    out.bulk(Some("master"));
+    out.bulk(Some("modules"));
+    out.array_len(0);
+    Ok(())
+}
+
+fn info<E: KvsEngine>(session: &Session<E>, out: &mut Output) -> CommandResult {
+    if session.needs_auth() {
+        return Err(ErrorCode::AuthRequired);
+    }
+    let shared = session.shared;
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\nrun_id:{}\r\nredis_mode:standalone\r\n\r\n\
+         # Clients\r\nconnected_clients:{}\r\n",
+        shared.info.version,
+        shared.info.id,
+        shared.connections.load(Ordering::SeqCst),
+    );
+    out.bulk(Some(&info));
+    Ok(())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`
+///
+/// The cursor is the offset in the ordered list of matching keys. Patterns
+/// understand `*` and `?`.
+fn scan<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Output,
+    args: Vec<String>,
+) -> CommandResult {
+    let syntax_error = || ErrorCode::InvalidRequest("syntax error".to_owned());
+    let mut args = args.into_iter();
+    let cursor: usize = args
+        .next()
+        .and_then(|cursor| cursor.parse().ok())
+        .ok_or_else(|| ErrorCode::InvalidRequest("invalid cursor".to_owned()))?;
+    let mut pattern = "*".to_owned();
+    let mut count = 10;
+    let mut only_strings = true;
+    while let Some(option) = args.next() {
+        let value = args.next().ok_or_else(syntax_error)?;
+        match option.to_ascii_uppercase().as_str() {
+            "MATCH" => pattern = value,
+            "COUNT" => {
+                count = value
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(syntax_error)?
+            }
+            "TYPE" => only_strings = value.eq_ignore_ascii_case("string"),
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let prefix = pattern
+        .split(|c| matches!(c, '*' | '?' | '[' | '\\'))
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let keys: Vec<String> = match session.handle(&Request::Scan { prefix })? {
+        Reply::Pairs(pairs) if only_strings => pairs
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .collect(),
+        _ => vec![],
+    };
+    let next = match cursor.saturating_add(count) {
+        next if next < keys.len() => next,
+        _ => 0,
+    };
+    out.array_len(2);
+    out.bulk(Some(&next.to_string()));
+    let page: Vec<&String> = keys.iter().skip(cursor).take(count).collect();
+    out.array_len(page.len());
+    for key in page {
+        out.bulk(Some(key));
+    }
+    Ok(())
+}
+
+/// `PUBLISH channel message`
+fn publish<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Output,
+    args: Vec<String>,
+) -> CommandResult {
+    let mut args = args.into_iter();
+    let (channel, message) = (
+        args.next().unwrap_or_default(),
+        args.next().unwrap_or_default(),
+    );
+    if let Reply::Receivers(receivers) = session.handle(&Request::Publish { channel, message })? {
+        out.integer(receivers as i64);
+    }
+    Ok(())
+}
+
+/// `SUBSCRIBE channel [channel ...]` and `PSUBSCRIBE pattern [pattern ...]`:
+/// confirm every subscription.
+fn subscribe<E: KvsEngine>(
+    session: &Session<E>,
+    out: &mut Output,
+    name: &str,
+    args: Vec<String>,
+) -> std::result::Result<Subscription, ErrorCode> {
+    let names: Vec<&str> = args.iter().map(String::as_str).collect();
+    session.authorize_stream(Op::Subscribe, &names)?;
+    info!("{} subscribes to {:?}", session.peer_addr, names);
+    let kind = name.to_ascii_lowercase();
+    for (i, name) in names.iter().enumerate() {
+        out.push_len(3);
+        out.bulk(Some(&kind));
+        out.bulk(Some(name));
+        out.integer(i as i64 + 1);
+    }
+    Ok(if kind == "subscribe" {
+        (args, vec![])
+    } else {
+        (vec![], args)
+    })
+}
+
+/// Push the messages of the subscription until the connection fails or the
+/// subscriber's buffer overflows.
+fn push_messages<E: KvsEngine, W: Write>(
+    shared: &Shared<E>,
+    stream: &mut W,
+    mut out: Output,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+) -> Result<()> {
+    let subscribed = shared.pubsub.subscribe(channels, patterns);
+    loop {
+        let Some(messages) = subscribed.next(HEARTBEAT_INTERVAL) else {
+            out.error(&format!("ERR {OVERFLOWED}"));
+            stream.write_all(&out.buf)?;
+            return Ok(());
+        };
+        for message in messages {
+            match &message.pattern {
+                Some(pattern) => {
+                    out.push_len(4);
+                    out.bulk(Some("pmessage"));
+                    out.bulk(Some(pattern));
+                }
+                None => {
+                    out.push_len(3);
+                    out.bulk(Some("message"));
+                }
+            }
+            out.bulk(Some(&message.channel));
+            out.bulk(Some(&message.message));
+        }
+        if !out.buf.is_empty() {
+            stream.write_all(&out.buf)?;
+            stream.flush()?;
+            out.buf.clear();
+        }
+    }
+}
+
+/// Commands on the data: GET, SET, DEL, EXISTS, MGET and MSET.
+fn data<E: KvsEngine>(
+    session: &mut Session<E>,
+    out: &mut Output,
+    name: &str,
+    args: Vec<String>,
+) -> CommandResult {
+    let mut args = args.into_iter();
+    match name {
+        "GET" => {
+            let key = args.next().unwrap_or_default();
+            if let Reply::Value(value) = session.handle(&Request::Get { key })? {
+                out.bulk(value.as_deref());
+            }
+        }
+        "SET" => {
+            let (key, value) = (
+                args.next().unwrap_or_default(),
+                args.next().unwrap_or_default(),
+            );
+            if args.len() > 0 {
+                // expiry and conditions are not supported
+                return Err(ErrorCode::InvalidRequest("syntax error".to_owned()));
+            }
+            session.handle(&Request::Set { key, value })?;
+            out.simple("OK");
+        }
+        "DEL" => {
+            let mut removed = 0;
+            for key in args {
+                match session.handle(&Request::Remove { key }) {
+                    Ok(_) => removed += 1,
+                    Err(ErrorCode::KeyNotFound) => {}
+                    Err(code) => return Err(code),
+                }
+            }
+            out.integer(removed);
+        }
+        "EXISTS" => {
+            if let Reply::Values(values) = session.handle(&Request::MGet {
+                keys: args.collect(),
+            })? {
+                out.integer(values.iter().filter(|value| value.is_some()).count() as i64);
+            }
+        }
+        "MGET" => {
+            if let Reply::Values(values) = session.handle(&Request::MGet {
+                keys: args.collect(),
+            })? {
+                out.array_len(values.len());
+                for value in values {
+                    out.bulk(value.as_deref());
+                }
+            }
+        }
+        "MSET" => {
+            let mut pairs = vec![];
+            while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                pairs.push((key, value));
+            }
+            session.handle(&Request::MSet { pairs })?;
+            out.simple("OK");
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Answer a connection which is refused with `code`.
+pub fn reject<S: Stream>(mut stream: S, code: ErrorCode) -> Result<()> {
+    let mut out = Output {
+        buf: vec![],
+        resp3: false,
+    };
+    out.error(&error_message(code));
+    stream.write_all(&out.buf)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn error_message(code: ErrorCode) -> String {
+    match code {
+        ErrorCode::KeyNotFound => "ERR no such key".to_owned(),
+        ErrorCode::AuthRequired => "NOAUTH Authentication required.".to_owned(),
+        ErrorCode::AuthFailed => {
+            "WRONGPASS invalid username-password pair or user is disabled.".to_owned()
+        }
+        ErrorCode::PermissionDenied(reason) => format!("NOPERM {reason}"),
+        ErrorCode::UnsupportedVersion { .. } => "NOPROTO unsupported protocol version".to_owned(),
+        ErrorCode::InvalidRequest(reason) | ErrorCode::Internal(reason) => format!("ERR {reason}"),
+        ErrorCode::ReadOnly { .. } => {
+            "READONLY You can't write against a read only replica.".to_owned()
+        }
+        ErrorCode::NotLeader { leader } => match leader {
+            Some(leader) => format!("NOTLEADER the leader is {leader}"),
+            None => "TRYAGAIN the cluster is electing a leader".to_owned(),
+        },
+        ErrorCode::Compacted { first } => format!("ERR changes before {first} were compacted"),
+    }
+}
+
+/// Read one command as its arguments, or `None` if the stream is closed.
+///
+/// Understands both arrays of bulk strings and inline commands.
+fn read_command<R: BufRead>(stream: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(stream)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        // inline command, as typed in telnet
+        return Ok(Some(
+            line.split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(stream)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => parse_len(len, MAX_BULK_LEN)?,
+            None => return Err(protocol_error("expected '$'")),
+        };
+        let mut arg = vec![0; len + 2];
+        stream.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected CRLF after a bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read one line without its line ending, or `None` if the stream is closed.
+pub(super) fn read_line<R: BufRead>(stream: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    if stream
+        .by_ref()
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)?
+        == 0
+    {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line too long or unterminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(reason: &str) -> anyhow::Error {
+    KvsError::InvalidRequest(reason.to_owned()).into()
+}
+
+/// Replies waiting to be written, in RESP2 or RESP3.
+struct Output {
+    buf: Vec<u8>,
+    resp3: bool,
+}
+
+impl Output {
+    fn simple(&mut self, s: &str) {
+        self.buf.extend_from_slice(format!("+{s}\r\n").as_bytes());
+    }
+
+    fn error(&mut self, s: &str) {
+        let s = s.replace(['\r', '\n'], " ");
+        self.buf.extend_from_slice(format!("-{s}\r\n").as_bytes());
+    }
+
+    fn integer(&mut self, i: i64) {
+        self.buf.extend_from_slice(format!(":{i}\r\n").as_bytes());
+    }
+
+    fn bulk(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => {
+                self.buf
+                    .extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                self.buf.extend_from_slice(s.as_bytes());
+                self.buf.extend_from_slice(b"\r\n");
+            }
+            None if self.resp3 => self.buf.extend_from_slice(b"_\r\n"),
+            None => self.buf.extend_from_slice(b"$-1\r\n"),
+        }
+    }
+
+    fn array_len(&mut self, len: usize) {
+        self.buf.extend_from_slice(format!("*{len}\r\n").as_bytes());
+    }
+
+    /// A push in RESP3, an array in RESP2.
+    fn push_len(&mut self, len: usize) {
+        if self.resp3 {
+            self.buf.extend_from_slice(format!(">{len}\r\n").as_bytes());
+        } else {
+            self.array_len(len);
+        }
+    }
+
+    /// A map in RESP3, a flat array of keys and values in RESP2.
+    fn map_len(&mut self, len: usize) {
+        if self.resp3 {
+            self.buf.extend_from_slice(format!("%{len}\r\n").as_bytes());
+        } else {
+            self.array_len(len * 2);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Change notifications.
+//!
+//! A `Watch` request turns the connection into a stream of the changes of the
+//! keys under a prefix, read from the `ChangeLog` of the server, from the
+//! moment the request arrives.
+
+use std::{io::Write, time::Instant};
+
+use super::{memcache, replication::HEARTBEAT_INTERVAL, Shared};
+use crate::acl::Op;
+use crate::codec::Codec;
+use crate::transport::{Change, ErrorCode, Reply, Response, WatchEvent};
+use crate::{KvsEngine, Result};
+
+/// Send the changes of the keys starting with `prefix` which `user` may get,
+/// and a heartbeat when there are none for a while, until the connection
+/// fails or the watcher falls behind the changes kept by the server.
+pub fn stream<E: KvsEngine, W: Write>(
+    shared: &Shared<E>,
+    stream: &mut W,
+    codec: &Codec,
+    id: u64,
+    prefix: &str,
+    user: &str,
+) -> Result<()> {
+    let mut seq = shared.changes.seq();
+    let mut last_sent = Instant::now();
+    loop {
+        let Some(changes) = shared.changes.after(seq, HEARTBEAT_INTERVAL) else {
+            let result = Err(ErrorCode::Internal(
+                "Watcher fell behind the changes kept by the server".to_owned(),
+            ));
+            return codec.send(stream, &Response { id, result });
+        };
+        seq = changes.last().map_or(seq, |(seq, _)| *seq);
+        let acl = shared.acl.read().unwrap();
+        let events = changes
+            .into_iter()
+            .filter(|(_, change)| {
+                let key = match change {
+                    Change::Set { key, .. } | Change::Remove { key } => key,
+                };
+                key.starts_with(prefix)
+                    && !key.ends_with(memcache::META_SUFFIX)
+                    && acl
+                        .as_ref()
+                        .map_or(true, |acl| acl.allows(user, Op::Get, key))
+            })
+            .map(|(seq, change)| WatchEvent { seq, change })
+            .collect::<Vec<_>>();
+        drop(acl);
+        if events.is_empty() && last_sent.elapsed() < HEARTBEAT_INTERVAL {
+            continue;
+        }
+        last_sent = Instant::now();
+        codec.send(
+            stream,
+            &Response {
+                id,
+                result: Ok(Reply::Watch(events)),
+            },
+        )?;
+    }
+}
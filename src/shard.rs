@@ -0,0 +1,643 @@
+//! Keys partitioned across several servers by consistent hashing.
+//!
+//! A `ShardMap` lists the servers, each placed at many points of a hash ring,
+//! and a key belongs to the server of the first point after its hash. The map
+//! is a TOML file:
+//!
+//! ```toml
+//! version = 3
+//! nodes = ["10.0.0.1:4000", "10.0.0.2:4000"]
+//! # only while keys move to `nodes`
+//! previous = ["10.0.0.1:4000"]
+//! ```
+//!
+//! or the same text stored on a designated server.
+//!
+//! While a resharding moves keys, the map keeps the `previous` servers: the
+//! clients read from the previous owner of a key, which has every key, and
+//! write to both owners, so that the copy made by `ShardedClient::reshard`
+//! doesn't miss any write.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    client::KvsClientBuilder, dump::SCAN_PAGE_SIZE, net::Addr, transport::Page, KvsClient,
+    KvsError, Result,
+};
+
+/// Key of the shard map on a designated server, left out of the scans.
+const SHARD_MAP_KEY: &str = "\0kvs:shard-map";
+
+/// Most rounds `ShardedClient::reshard` copies a key which clients keep
+/// changing before it gives up.
+const CATCH_UP_ROUNDS: usize = 100;
+
+fn default_vnodes() -> u32 {
+    64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ShardMapFile {
+    #[serde(default)]
+    version: u64,
+    nodes: Vec<String>,
+    #[serde(default)]
+    previous: Option<Vec<String>>,
+    #[serde(default = "default_vnodes")]
+    vnodes: u32,
+}
+
+/// Which server owns each key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "ShardMapFile", into = "ShardMapFile")]
+pub struct ShardMap {
+    version: u64,
+    vnodes: u32,
+    ring: Ring,
+    /// ring of the servers the keys move from, during a resharding
+    previous: Option<Ring>,
+}
+
+impl From<ShardMapFile> for ShardMap {
+    fn from(file: ShardMapFile) -> Self {
+        Self {
+            version: file.version,
+            vnodes: file.vnodes,
+            ring: Ring::new(file.nodes, file.vnodes),
+            previous: file
+                .previous
+                .map(|previous| Ring::new(previous, file.vnodes)),
+        }
+    }
+}
+
+impl From<ShardMap> for ShardMapFile {
+    fn from(map: ShardMap) -> Self {
+        Self {
+            version: map.version,
+            nodes: map.ring.nodes,
+            previous: map.previous.map(|previous| previous.nodes),
+            vnodes: map.vnodes,
+        }
+    }
+}
+
+impl ShardMap {
+    /// Map spreading the keys over `nodes`.
+    pub fn new(nodes: Vec<String>) -> Self {
+        ShardMapFile {
+            version: 1,
+            nodes,
+            previous: None,
+            vnodes: default_vnodes(),
+        }
+        .into()
+    }
+
+    /// Read and parse the shard map file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the TOML text of a shard map.
+    pub fn parse(text: &str) -> Result<Self> {
+        let map: Self = toml::from_str(text)?;
+        if map.ring.nodes.is_empty() {
+            return Err(KvsError::StringError("Shard map without nodes".to_owned()).into());
+        }
+        Ok(map)
+    }
+
+    /// Write the map to `path`, replacing the file at once.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_toml()?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// TOML text of the map.
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Incremented on each change.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Servers owning the keys.
+    pub fn nodes(&self) -> &[String] {
+        &self.ring.nodes
+    }
+
+    /// Servers the keys move from, `None` unless a resharding is going on.
+    pub fn previous(&self) -> Option<&[String]> {
+        self.previous.as_ref().map(|previous| &previous.nodes[..])
+    }
+
+    /// Server owning `key`.
+    pub fn owner(&self, key: &str) -> &str {
+        self.ring.owner(key)
+    }
+
+    /// Server owning `key` before the resharding going on, if any.
+    pub fn previous_owner(&self, key: &str) -> Option<&str> {
+        self.previous.as_ref().map(|previous| previous.owner(key))
+    }
+
+    /// Server to read `key` from: its previous owner during a resharding.
+    fn reader(&self, key: &str) -> &str {
+        self.previous_owner(key).unwrap_or_else(|| self.owner(key))
+    }
+
+    /// Servers to write `key` to, the previous owner first.
+    fn writers(&self, key: &str) -> Vec<&str> {
+        let owner = self.owner(key);
+        match self.previous_owner(key) {
+            Some(previous) if previous != owner => vec![previous, owner],
+            _ => vec![owner],
+        }
+    }
+
+    /// Next version of the map, moving the keys to `nodes`.
+    fn moving_to(&self, nodes: Vec<String>) -> Self {
+        ShardMapFile {
+            version: self.version + 1,
+            nodes,
+            previous: Some(self.ring.nodes.clone()),
+            vnodes: self.vnodes,
+        }
+        .into()
+    }
+
+    /// Next version of the map, once the keys moved.
+    fn moved(&self) -> Self {
+        ShardMapFile {
+            version: self.version + 1,
+            nodes: self.ring.nodes.clone(),
+            previous: None,
+            vnodes: self.vnodes,
+        }
+        .into()
+    }
+}
+
+/// Servers placed on a hash ring.
+#[derive(Clone, Debug, PartialEq)]
+struct Ring {
+    nodes: Vec<String>,
+    /// points of the ring with the index of their node, in order
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(nodes: Vec<String>, vnodes: u32) -> Self {
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| (0..vnodes).map(move |v| (hash(&format!("{node}#{v}")), i)))
+            .collect();
+        points.sort_unstable();
+        Self { nodes, points }
+    }
+
+    fn owner(&self, key: &str) -> &str {
+        let hash = hash(key);
+        let i = self.points.partition_point(|(point, _)| *point < hash);
+        let (_, node) = self.points[i % self.points.len()];
+        &self.nodes[node]
+    }
+}
+
+fn hash(text: &str) -> u64 {
+    let digest = Sha256::digest(text.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Where a `ShardedClient` gets its shard map from.
+#[derive(Clone, Debug)]
+pub enum MapSource {
+    /// a shard map file
+    File(PathBuf),
+    /// a server holding the map
+    Node(Addr),
+}
+
+/// Client of several servers, sending each key to the server owning it.
+pub struct ShardedClient {
+    map: ShardMap,
+    /// `None` for a map which never changes
+    source: Option<MapSource>,
+    /// settings of the connections
+    template: Option<KvsClientBuilder>,
+    clients: HashMap<String, KvsClient>,
+    refresh_interval: Duration,
+    last_refresh: Instant,
+}
+
+impl ShardedClient {
+    /// Client of the servers of `map`, which never changes.
+    pub fn new(map: ShardMap) -> Self {
+        Self {
+            map,
+            source: None,
+            template: None,
+            clients: HashMap::new(),
+            refresh_interval: Duration::from_secs(1),
+            last_refresh: Instant::now(),
+        }
+    }
+
+    /// Client loading its map from `source`, and loading it again every
+    /// second so that it follows the reshardings.
+    pub fn open(source: MapSource) -> Result<Self> {
+        Self::open_with(source, None)
+    }
+
+    /// Like `open`, with the connections configured as `template`, whose
+    /// address is ignored.
+    pub fn open_with(source: MapSource, template: Option<KvsClientBuilder>) -> Result<Self> {
+        let mut client = Self::new(ShardMap::new(vec![]));
+        client.template = template;
+        client.map = client
+            .fetch(&source)?
+            .ok_or_else(|| KvsError::StringError(format!("No shard map in {source:?}")))?;
+        client.source = Some(source);
+        Ok(client)
+    }
+
+    /// Like `open_with`, publishing a map of `nodes` to `source` when it
+    /// holds none yet.
+    pub fn open_or_create(
+        source: MapSource,
+        nodes: Vec<String>,
+        template: Option<KvsClientBuilder>,
+    ) -> Result<Self> {
+        let mut client = Self::new(ShardMap::new(vec![]));
+        client.template = template;
+        match client.fetch(&source) {
+            Ok(Some(map)) => {
+                client.map = map;
+                client.source = Some(source);
+            }
+            Err(e) if !matches!(&source, MapSource::File(path) if !path.exists()) => return Err(e),
+            _ => {
+                client.source = Some(source);
+                client.publish(ShardMap::new(nodes))?;
+            }
+        }
+        Ok(client)
+    }
+
+    /// Load the map again after `interval` instead of a second.
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    /// The shard map in use.
+    pub fn map(&self) -> &ShardMap {
+        &self.map
+    }
+
+    /// Load the map from its source now.
+    pub fn refresh(&mut self) -> Result<()> {
+        if let Some(source) = self.source.clone() {
+            if let Some(map) = self.fetch(&source)? {
+                if map.version != self.map.version {
+                    debug!("Shard map version {}", map.version);
+                }
+                self.map = map;
+            }
+        }
+        self.last_refresh = Instant::now();
+        Ok(())
+    }
+
+    fn refresh_if_due(&mut self) -> Result<()> {
+        if self.source.is_some() && self.last_refresh.elapsed() >= self.refresh_interval {
+            self.refresh()?;
+        }
+        Ok(())
+    }
+
+    fn fetch(&mut self, source: &MapSource) -> Result<Option<ShardMap>> {
+        let text = match source {
+            MapSource::File(path) => Some(fs::read_to_string(path)?),
+            MapSource::Node(addr) => self
+                .client(&addr.to_string())?
+                .get(SHARD_MAP_KEY.to_owned())?,
+        };
+        text.as_deref().map(ShardMap::parse).transpose()
+    }
+
+    fn publish(&mut self, map: ShardMap) -> Result<()> {
+        match self.source.clone() {
+            Some(MapSource::File(path)) => map.save(path)?,
+            Some(MapSource::Node(addr)) => self
+                .client(&addr.to_string())?
+                .set(SHARD_MAP_KEY.to_owned(), map.to_toml()?)?,
+            None => {
+                return Err(KvsError::StringError(
+                    "Cannot change a shard map without a source".to_owned(),
+                )
+                .into())
+            }
+        }
+        info!(
+            "Published shard map version {}: {:?}",
+            map.version,
+            map.nodes()
+        );
+        self.map = map;
+        self.last_refresh = Instant::now();
+        Ok(())
+    }
+
+    /// Connection to `node`, opened on first use.
+    fn client(&mut self, node: &str) -> Result<&mut KvsClient> {
+        if !self.clients.contains_key(node) {
+            let addr: Addr = node.parse()?;
+            let client = match &self.template {
+                Some(template) => template.with_addr(&addr).build()?,
+                None => KvsClient::builder(&addr).build()?,
+            };
+            self.clients.insert(node.to_owned(), client);
+        }
+        Ok(self.clients.get_mut(node).unwrap())
+    }
+
+    /// request `get` from the owner of `key`
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.refresh_if_due()?;
+        let node = self.map.reader(&key).to_owned();
+        self.client(&node)?.get(key)
+    }
+
+    /// request `set` on the owner of `key`
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.refresh_if_due()?;
+        for node in self.writers(&key) {
+            self.client(&node)?.set(key.clone(), value.clone())?;
+        }
+        Ok(())
+    }
+
+    /// request `remove` on the owner of `key`
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.refresh_if_due()?;
+        let nodes = self.writers(&key);
+        // the first node has every key, the next one may not have it yet
+        self.client(&nodes[0])?.remove(key.clone())?;
+        for node in &nodes[1..] {
+            match self.client(node)?.remove(key.clone()) {
+                Err(e) if !matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => {
+                    return Err(e)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// request `compare_and_set` on the owner of `key`
+    pub fn compare_and_set(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<bool> {
+        self.refresh_if_due()?;
+        let nodes = self.writers(&key);
+        let swapped =
+            self.client(&nodes[0])?
+                .compare_and_set(key.clone(), expected, value.clone())?;
+        if swapped {
+            for node in &nodes[1..] {
+                self.client(node)?.set(key.clone(), value.clone())?;
+            }
+        }
+        Ok(swapped)
+    }
+
+    /// request `mget` from the owners of `keys`, the values come in the
+    /// order of `keys`
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.refresh_if_due()?;
+        let mut by_node: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let node = self.map.reader(key).to_owned();
+            by_node.entry(node).or_default().push(i);
+        }
+        let mut values = vec![None; keys.len()];
+        for (node, positions) in by_node {
+            let node_keys = positions.iter().map(|i| keys[*i].clone()).collect();
+            let node_values = self.client(&node)?.mget(node_keys)?;
+            for (i, value) in positions.into_iter().zip(node_values) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// request `mset` on the owners of the keys
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.refresh_if_due()?;
+        let mut by_node: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in pairs {
+            for node in self.writers(&key) {
+                by_node
+                    .entry(node)
+                    .or_default()
+                    .push((key.clone(), value.clone()));
+            }
+        }
+        for (node, pairs) in by_node {
+            self.client(&node)?.mset(pairs)?;
+        }
+        Ok(())
+    }
+
+    /// request `scan` on every server, the pairs come ordered by key
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.refresh_if_due()?;
+        let nodes = self.map.previous().unwrap_or(self.map.nodes()).to_vec();
+        let mut pairs = vec![];
+        for node in nodes {
+            let node_pairs = self.client(&node)?.scan(prefix.clone())?;
+            // leave out the copies a node doesn't own
+            pairs.extend(
+                node_pairs
+                    .into_iter()
+                    .filter(|(key, _)| key != SHARD_MAP_KEY && self.map.reader(key) == node),
+            );
+        }
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs)
+    }
+
+    /// request `scan_page` on every server, and merge their pages
+    pub fn scan_page(
+        &mut self,
+        prefix: String,
+        start_after: Option<String>,
+        limit: u32,
+    ) -> Result<Page> {
+        self.refresh_if_due()?;
+        let nodes = self.map.previous().unwrap_or(self.map.nodes()).to_vec();
+        let mut pages = vec![];
+        for node in nodes {
+            let mut page =
+                self.client(&node)?
+                    .scan_page(prefix.clone(), start_after.clone(), limit)?;
+            page.pairs
+                .retain(|(key, _)| key != SHARD_MAP_KEY && self.map.reader(key) == node);
+            pages.push(page);
+        }
+        Ok(Page::merge(pages, limit as usize))
+    }
+
+    fn writers(&self, key: &str) -> Vec<String> {
+        self.map
+            .writers(key)
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Move the keys to `nodes`, while the clients keep reading and writing,
+    /// and return how many keys moved.
+    ///
+    /// The map must come from a source, where the new versions are published.
+    /// Each step waits twice the refresh interval, for the other clients to
+    /// load the new map. A key written too often to be copied fails the
+    /// resharding, which runs again from the start with the same `nodes`.
+    pub fn reshard(&mut self, nodes: Vec<String>) -> Result<usize> {
+        self.refresh()?;
+        match self.map.previous() {
+            None => {
+                let moving = self.map.moving_to(nodes);
+                self.publish(moving)?;
+            }
+            Some(_) if self.map.nodes() == nodes => info!("Resume the resharding"),
+            Some(_) => {
+                return Err(
+                    KvsError::StringError("Another resharding is going on".to_owned()).into(),
+                )
+            }
+        }
+        // from now on, the clients write to both owners
+        thread::sleep(self.refresh_interval * 2);
+
+        let previous = self.map.previous().unwrap_or_default().to_vec();
+        for from in &previous {
+            self.for_each_page_on(from, |client, pairs| {
+                let mut moved = vec![];
+                for (key, value) in pairs {
+                    let to = client.map.owner(&key).to_owned();
+                    if key == SHARD_MAP_KEY || to == *from || client.map.reader(&key) != from {
+                        continue;
+                    }
+                    // a key there already came from a client, and is newer
+                    client
+                        .client(&to)?
+                        .compare_and_set(key.clone(), None, value)?;
+                    moved.push((to, key));
+                }
+                // catch up with the writes which raced with the copy
+                for (to, key) in moved {
+                    client.catch_up(from, &to, key)?;
+                }
+                Ok(())
+            })?;
+        }
+
+        let done = self.map.moved();
+        self.publish(done)?;
+        // from now on, the clients only use the new owners
+        thread::sleep(self.refresh_interval * 2);
+        // the keys copied, and the ones the clients wrote to both owners
+        let mut removed = 0;
+        for from in &previous {
+            self.for_each_page_on(from, |client, pairs| {
+                for (key, _) in pairs {
+                    if key == SHARD_MAP_KEY || client.map.owner(&key) == from {
+                        continue;
+                    }
+                    match client.client(from)?.remove(key) {
+                        Err(e) if !matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => {
+                            return Err(e)
+                        }
+                        _ => removed += 1,
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        info!("Moved {} keys to {:?}", removed, self.map.nodes());
+        Ok(removed)
+    }
+
+    /// Pass the pages of every pair on `node` to `f`, one at a time.
+    fn for_each_page_on(
+        &mut self,
+        node: &str,
+        mut f: impl FnMut(&mut Self, Vec<(String, String)>) -> Result<()>,
+    ) -> Result<()> {
+        let mut start_after = None;
+        loop {
+            let page = self
+                .client(node)?
+                .scan_page(String::new(), start_after, SCAN_PAGE_SIZE)?;
+            f(self, page.pairs)?;
+            match page.next {
+                Some(next) => start_after = Some(next),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Copy `key` from `from` to `to` until both hold the same value, in at
+    /// most `CATCH_UP_ROUNDS` rounds.
+    ///
+    /// The clients write to both nodes meanwhile: a value is only set on `to`
+    /// in place of the one just read there, and a round which read `from`
+    /// before a client changed it is made up for by the next one.
+    fn catch_up(&mut self, from: &str, to: &str, key: String) -> Result<()> {
+        for _ in 0..CATCH_UP_ROUNDS {
+            let value = self.client(from)?.get(key.clone())?;
+            let current = self.client(to)?.get(key.clone())?;
+            if current == value {
+                return Ok(());
+            }
+            match value {
+                Some(value) => {
+                    self.client(to)?
+                        .compare_and_set(key.clone(), current, value)?;
+                }
+                None => match self.client(to)?.remove(key.clone()) {
+                    Err(e) if !matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => {
+                        return Err(e)
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Err(KvsError::StringError(format!(
+            "{key:?} kept changing while it moved to {to}, resume the resharding later"
+        ))
+        .into())
+    }
+}
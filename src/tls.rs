@@ -0,0 +1,218 @@
+//! TLS and mutual TLS on top of any `Stream`.
+//!
+//! The server side requires client certificates when a CA is given, and the
+//! subject of the client certificate becomes the identity of the connection.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientConnection, PrivateKey,
+    RootCertStore, ServerConfig, ServerConnection, ServerName, StreamOwned,
+};
+
+use crate::{
+    net::{Addr, AnyStream, Stream},
+    KvsError, Result,
+};
+
+/// Paths of the PEM files used to set up TLS.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// Certificate chain presented to the other side.
+    pub cert: Option<PathBuf>,
+    /// Private key of `cert`.
+    pub key: Option<PathBuf>,
+    /// CA used to verify the other side.
+    ///
+    /// On the server it turns on client certificate verification (mTLS).
+    pub ca: Option<PathBuf>,
+    /// Name the client expects in the server certificate,
+    /// taken from the server address if `None`.
+    pub server_name: Option<String>,
+}
+
+impl TlsOptions {
+    /// Build the rustls configuration of a server.
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let (Some(cert), Some(key)) = (&self.cert, &self.key) else {
+            return Err(
+                KvsError::Tls("server needs both a certificate and a key".to_owned()).into(),
+            );
+        };
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.ca {
+            Some(ca) => builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| KvsError::Tls(e.to_string()))?;
+        Ok(Arc::new(config))
+    }
+
+    /// Build the rustls configuration of a client.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let Some(ca) = &self.ca else {
+            return Err(KvsError::Tls("client needs a CA to verify the server".to_owned()).into());
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(ca)?);
+        let config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|e| KvsError::Tls(e.to_string()))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(
+                    KvsError::Tls("client certificate and key go together".to_owned()).into(),
+                )
+            }
+        };
+        Ok(Arc::new(config))
+    }
+
+    /// Connect to `addr` and run the TLS handshake as a client.
+    pub fn connect(&self, addr: &Addr) -> Result<AnyStream> {
+        self.handshake(addr, addr.connect()?)
+    }
+
+    /// Run the TLS handshake as a client on `stream`, connected to `addr`.
+    pub fn handshake(&self, addr: &Addr, stream: AnyStream) -> Result<AnyStream> {
+        let server_name = match (&self.server_name, addr) {
+            (Some(name), _) => name.to_owned(),
+            (None, Addr::Tcp(addr)) => addr.ip().to_string(),
+            #[cfg(unix)]
+            (None, Addr::Unix(_)) => "localhost".to_owned(),
+        };
+        let stream = TlsStream::connect(self.client_config()?, &server_name, stream)?;
+        Ok(AnyStream::Tls(Box::new(stream)))
+    }
+}
+
+/// A `Stream` wrapped in TLS.
+pub enum TlsStream<S: Stream> {
+    /// Server side of a connection
+    Server(StreamOwned<ServerConnection, S>),
+    /// Client side of a connection
+    Client(StreamOwned<ClientConnection, S>),
+}
+
+impl<S: Stream> TlsStream<S> {
+    /// Run the server side handshake on `stream`.
+    pub fn accept(config: Arc<ServerConfig>, mut stream: S) -> Result<Self> {
+        let mut conn = ServerConnection::new(config).map_err(|e| KvsError::Tls(e.to_string()))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(TlsStream::Server(StreamOwned::new(conn, stream)))
+    }
+
+    /// Run the client side handshake on `stream`.
+    pub fn connect(config: Arc<ClientConfig>, server_name: &str, mut stream: S) -> Result<Self> {
+        let name = ServerName::try_from(server_name)
+            .map_err(|_| KvsError::Tls(format!("invalid server name {server_name}")))?;
+        let mut conn =
+            ClientConnection::new(config, name).map_err(|e| KvsError::Tls(e.to_string()))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(TlsStream::Client(StreamOwned::new(conn, stream)))
+    }
+
+    /// The stream under TLS.
+    pub fn get_ref(&self) -> &S {
+        match self {
+            TlsStream::Server(s) => &s.sock,
+            TlsStream::Client(s) => &s.sock,
+        }
+    }
+}
+
+impl<S: Stream> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Server(s) => s.read(buf),
+            TlsStream::Client(s) => s.read(buf),
+        }
+    }
+}
+
+impl<S: Stream> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Server(s) => s.write(buf),
+            TlsStream::Client(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Server(s) => s.flush(),
+            TlsStream::Client(s) => s.flush(),
+        }
+    }
+}
+
+impl<S: Stream> Stream for TlsStream<S> {
+    fn peer(&self) -> String {
+        match self {
+            TlsStream::Server(s) => s.sock.peer(),
+            TlsStream::Client(s) => s.sock.peer(),
+        }
+    }
+
+    /// Common name (or the whole subject) of the verified client certificate.
+    fn identity(&self) -> Option<String> {
+        let TlsStream::Server(s) = self else {
+            return None;
+        };
+        let cert = s.conn.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+        let subject = cert.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok());
+        Some(match common_name {
+            Some(cn) => cn.to_owned(),
+            None => subject.to_string(),
+        })
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!("no certificate in {}", path.display())).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(KvsError::Tls(format!("no private key in {}", path.display())).into())
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(|e| KvsError::Tls(e.to_string()))?;
+    }
+    Ok(roots)
+}
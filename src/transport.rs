@@ -1,28 +1,642 @@
 //! Transport Layer interface
+//!
+//! Every request is wrapped in a `RequestFrame` carrying the protocol version
+//! and a request id, and the server answers each one with a `Response`
+//! carrying the same id.
+//!
+//! A connection starts with a `Hello` request, in which the client offers a
+//! range of protocol versions and its features. The server answers with the
+//! version and the features both sides will use, and its own identity.
+//! Version 1 had no handshake: a client which skips the `Hello` is served with
+//! version 1. Version 3 moved from bare JSON values to length-prefixed frames,
+//! see the `codec` module.
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::{auth::Credentials, codec::Encoding, KvsError};
+
+/// Version of the wire protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest version of the wire protocol this crate can fall back to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional part of the protocol, used only if both sides support it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    /// requests on several keys at once
+    Batch,
+    /// `scan` of the keys under a prefix
+    Scan,
+    /// compressed frames
+    Compression,
+    /// authentication of the connection
+    Auth,
+}
+
+/// Identity of a server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// name of the server software
+    pub name: String,
+    /// version of the server software
+    pub version: String,
+    /// unique id of the server process
+    pub id: String,
+}
+
+/// What a connection agreed on in its handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// protocol version of every later request
+    pub version: u32,
+    /// features supported by both sides
+    pub features: Vec<Feature>,
+    /// the server on the other side, `None` for a version 1 server
+    pub server: Option<ServerInfo>,
+    /// encoding of the frames, used from version 3 on
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+impl Capabilities {
+    /// Whether both sides support `feature`.
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    Scan {
+        prefix: String,
+    },
+    /// One page of `scan`, to go through more pairs than fit in a frame
+    ScanPage {
+        prefix: String,
+        /// last key of the previous page, `None` for the first page
+        start_after: Option<String>,
+        /// most pairs of the page
+        limit: u32,
+    },
+    Auth {
+        credentials: Credentials,
+    },
+    Hello {
+        min_version: u32,
+        max_version: u32,
+        features: Vec<Feature>,
+        /// encodings of the client, by order of preference
+        #[serde(default)]
+        encodings: Vec<Encoding>,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    MSet {
+        pairs: Vec<(String, String)>,
+    },
+    CompareAndSet {
+        key: String,
+        expected: Option<String>,
+        value: String,
+    },
+    /// Check that the connection works
+    Ping,
+    /// Stream the data and then its changes, to a replica
+    Replicate {
+        /// resume after this change instead of starting with a snapshot
+        from: Option<Position>,
+    },
+    /// Replication state of the server
+    ReplicationStatus,
+    /// Message from another node of the cluster
+    Raft(RaftMessage),
+    /// State of the cluster node
+    ClusterStatus,
+    /// Add the node at `addr` to the cluster
+    AddMember {
+        /// address of the node, which is also its id
+        addr: String,
+    },
+    /// Remove the node at `addr` from the cluster
+    RemoveMember {
+        /// address of the node, which is also its id
+        addr: String,
+    },
+    /// Statistics of a proxy
+    ProxyStats,
+    /// Stream the changes of the keys starting with `key_or_prefix`
+    Watch {
+        /// key, or prefix of the keys, to watch
+        key_or_prefix: String,
+    },
+    /// Send `message` to the subscribers of `channel`
+    Publish {
+        /// name of the channel
+        channel: String,
+        /// message, which is not stored
+        message: String,
+    },
+    /// Stream the messages published on `channels`
+    Subscribe {
+        /// names of the channels
+        channels: Vec<String>,
+    },
+    /// Stream the messages published on the channels matching `patterns`
+    PSubscribe {
+        /// globs of channel names, with `*` and `?`
+        patterns: Vec<String>,
+    },
+    /// Stream the changes logged by the engine after sequence number `from`
+    Cdc {
+        /// sequence number of the last change already seen, 0 for all
+        from: u64,
+    },
+    /// Write a backup of the data to `path` on the server, consistent if the
+    /// engine can freeze its data. Only an admin may, on a server where
+    /// clients authenticate.
+    Backup {
+        /// new directory, or new tarball if it ends with `.tar`, relative to
+        /// the `backup_dir` of the server
+        path: String,
+    },
+}
+
+impl Request {
+    /// Whether running the request twice has the same effect as running it once.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::Get { .. }
+            | Request::Set { .. }
+            | Request::Scan { .. }
+            | Request::ScanPage { .. }
+            | Request::Auth { .. }
+            | Request::Hello { .. }
+            | Request::MGet { .. }
+            | Request::MSet { .. }
+            | Request::Ping
+            | Request::ReplicationStatus
+            | Request::Raft(_)
+            | Request::ClusterStatus
+            | Request::ProxyStats => true,
+            // a second `remove` fails, a second `compare_and_set` doesn't swap
+            Request::Remove { .. } | Request::CompareAndSet { .. } => false,
+            // a second change fails once the first one is in progress
+            Request::AddMember { .. } | Request::RemoveMember { .. } => false,
+            // turns the connection into a stream
+            Request::Replicate { .. }
+            | Request::Watch { .. }
+            | Request::Subscribe { .. }
+            | Request::PSubscribe { .. }
+            | Request::Cdc { .. } => false,
+            // each subscriber would get the message twice
+            Request::Publish { .. } => false,
+            // the first one created the backup
+            Request::Backup { .. } => false,
+        }
+    }
+}
+
+/// A write, as sent to the replicas.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// `key` was set to `value`
+    Set {
+        /// key written
+        key: String,
+        /// new value
+        value: String,
+    },
+    /// `key` was removed
+    Remove {
+        /// key removed
+        key: String,
+    },
+}
+
+/// Change of a watched key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// sequence number of the change on the server
+    pub seq: u64,
+    /// the change
+    pub change: Change,
+}
+
+/// Message published on a channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// channel the message was published on
+    pub channel: String,
+    /// pattern of the subscription matching `channel`, `None` if subscribed
+    /// by name
+    pub pattern: Option<String>,
+    /// the message
+    pub message: String,
+    /// messages to this subscriber lost just before this one, because its
+    /// buffer was full
+    pub dropped: u64,
+}
+
+/// Point in the changes of a server, where a replica can resume from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// id of the server process, whose sequence numbers start over on restart
+    pub server_id: String,
+    /// sequence number of the last change applied
+    pub seq: u64,
+}
+
+/// Message of a replication stream.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationEvent {
+    /// Start of a copy of the whole data, which replaces the data of the replica
+    SnapshotStart,
+    /// Part of the snapshot, sorted by key and after the previous part
+    SnapshotChunk(Vec<(String, String)>),
+    /// End of the snapshot, the changes which follow come after `seq`
+    SnapshotEnd {
+        /// sequence number of the last change surely in the snapshot, which
+        /// may hold later ones too
+        seq: u64,
+    },
+    /// Changes with their sequence numbers, in order
+    Changes {
+        /// changes with their sequence numbers
+        changes: Vec<(u64, Change)>,
+        /// sequence number of the last change of the leader
+        head: u64,
+    },
+    /// Sent while there are no changes
+    Heartbeat {
+        /// sequence number of the last change of the leader
+        head: u64,
+    },
+}
+
+/// Replication state of a server.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    /// address of the leader, `None` unless this server is a replica
+    pub leader: Option<String>,
+    /// whether the replication stream from the leader is up
+    pub connected: bool,
+    /// sequence number of the last change of this server
+    pub seq: u64,
+    /// sequence number of the last change of the leader applied here
+    pub applied: u64,
+    /// changes of the leader not applied here yet
+    pub lag: u64,
+    /// milliseconds since the last message of the leader
+    pub last_contact_ms: Option<u64>,
+    /// replicas streaming from this server
+    pub replicas: usize,
+}
+
+/// Message between the nodes of a cluster, as in the Raft paper.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RaftMessage {
+    /// A candidate asks for the vote of a node
+    RequestVote {
+        /// term of the candidate
+        term: u64,
+        /// id of the candidate
+        candidate: String,
+        /// index of the last entry of the candidate
+        last_index: u64,
+        /// term of the last entry of the candidate
+        last_term: u64,
+    },
+    /// The leader sends entries, or nothing as a heartbeat
+    AppendEntries {
+        /// term of the leader
+        term: u64,
+        /// id of the leader
+        leader: String,
+        /// index of the entry before `entries`
+        prev_index: u64,
+        /// term of the entry before `entries`
+        prev_term: u64,
+        /// entries from `prev_index + 1`
+        entries: Vec<Entry>,
+        /// commit index of the leader
+        commit: u64,
+    },
+    /// The leader sends a part of its data, to a node which needs entries
+    /// it doesn't keep any more
+    InstallSnapshot {
+        /// term of the leader
+        term: u64,
+        /// id of the leader
+        leader: String,
+        /// index of the last entry in the snapshot
+        index: u64,
+        /// term of the last entry in the snapshot
+        last_term: u64,
+        /// members as of `index`
+        members: Vec<String>,
+        /// number of the part, from 0
+        chunk: u64,
+        /// pairs of this part
+        pairs: Vec<(String, String)>,
+        /// whether this is the last part
+        done: bool,
+    },
+}
+
+/// Answer to a `RaftMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RaftReply {
+    /// `RequestVote` result
+    Vote {
+        /// term of the node
+        term: u64,
+        /// whether the node votes for the candidate
+        granted: bool,
+    },
+    /// `AppendEntries` result
+    Appended {
+        /// term of the node
+        term: u64,
+        /// whether the entries follow the log of the node
+        success: bool,
+        /// last entry matching the leader on success, otherwise the last
+        /// entry which may match
+        last_index: u64,
+    },
+    /// `InstallSnapshot` result
+    Installed {
+        /// term of the node
+        term: u64,
+    },
+}
+
+/// Entry of the replicated log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    /// term of the leader which created the entry
+    pub term: u64,
+    /// what to apply
+    pub command: Command,
+}
+
+/// Content of a log entry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Command {
+    /// Appended by a new leader, to commit the entries of the previous terms
+    Noop,
+    /// `set`, `remove`, `mset` or `compare_and_set`
+    Write(Request),
+    /// The new members of the cluster
+    Members(Vec<String>),
+}
+
+/// Role of a cluster node.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    /// Follows the leader
+    Follower,
+    /// Asks for votes to become the leader
+    Candidate,
+    /// Answers the clients and replicates the log
+    Leader,
+}
+
+/// State of a cluster node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClusterStatus {
+    /// address of the node, which is also its id
+    pub id: String,
+    /// role of the node
+    pub role: RaftRole,
+    /// current term
+    pub term: u64,
+    /// id of the leader, `None` while there is none
+    pub leader: Option<String>,
+    /// ids of the members, as of the last entry of the log
+    pub members: Vec<String>,
+    /// index of the last entry of the log
+    pub last_index: u64,
+    /// index of the last entry known committed
+    pub commit: u64,
+    /// index of the last entry applied to the engine
+    pub applied: u64,
+    /// index of the last entry dropped from the log
+    pub snapshot_index: u64,
+}
+
+/// One page of the pairs under a prefix, ordered by key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Page {
+    /// pairs of the page
+    pub pairs: Vec<(String, String)>,
+    /// key to start the next page after, `None` on the last page
+    ///
+    /// The page may hold fewer pairs than asked for and still not be the last
+    /// one, when the server left out pairs the client may not see.
+    pub next: Option<String>,
+}
+
+impl Page {
+    /// The page of `pairs`, read with a limit of `limit`: a full page may not
+    /// be the last one.
+    pub fn new(pairs: Vec<(String, String)>, limit: usize) -> Self {
+        let next = match pairs.last() {
+            Some((key, _)) if pairs.len() >= limit => Some(key.to_owned()),
+            _ => None,
+        };
+        Self { pairs, next }
+    }
+
+    /// One page of at most `limit` pairs out of pages of the same request on
+    /// several servers holding different keys.
+    pub fn merge(pages: Vec<Page>, limit: usize) -> Self {
+        // past the end of the shortest unfinished page, a server may have
+        // pairs not read yet
+        let bound = pages.iter().filter_map(|page| page.next.clone()).min();
+        let mut pairs: Vec<(String, String)> = pages
+            .into_iter()
+            .flat_map(|page| page.pairs)
+            .filter(|(key, _)| bound.as_ref().map_or(true, |bound| key <= bound))
+            .collect();
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        if pairs.len() > limit {
+            pairs.truncate(limit);
+            let next = pairs.last().map(|(key, _)| key.to_owned());
+            return Self { pairs, next };
+        }
+        Self { pairs, next: bound }
+    }
+}
+
+/// Statistics of a proxy, since it started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProxyStats {
+    /// requests of the clients
+    pub requests: u64,
+    /// requests which failed
+    pub errors: u64,
+    /// client connections open
+    pub connections: usize,
+    /// servers behind the proxy
+    pub backends: Vec<BackendStats>,
+}
+
+/// Statistics of a server behind a proxy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendStats {
+    /// address of the server
+    pub addr: String,
+    /// index of the shard the server belongs to
+    pub shard: usize,
+    /// whether the server is a replica of the shard, rather than its primary
+    pub replica: bool,
+    /// whether the server answered the last health check
+    pub healthy: bool,
+    /// requests forwarded to the server
+    pub requests: u64,
+    /// forwarded requests which failed
+    pub errors: u64,
+    /// connections of the pool open to the server
+    pub connections: usize,
+}
+
+/// `R` is a `Result` on the server, so that a request it cannot decode gets an
+/// error response instead of closing the connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestFrame<R = Request> {
+    pub version: u32,
+    pub id: u64,
+    pub request: R,
+}
+
+/// Successful result of a request.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Reply {
+    /// `get` result
+    Value(Option<String>),
+    /// `set`, `mset`, `remove` and `ping` result
+    Done,
+    /// `scan` result
+    Pairs(Vec<(String, String)>),
+    /// `scan_page` result
+    Page(Page),
+    /// `auth` result, the identity of the connection
+    Identity(String),
+    /// `hello` result
+    Welcome(Capabilities),
+    /// `mget` result, in the order of the keys
+    Values(Vec<Option<String>>),
+    /// `compare_and_set` result, whether the value was set
+    Swapped(bool),
+    /// one message of the stream started by `replicate`
+    Replication(ReplicationEvent),
+    /// `replication_status` result
+    ReplicationStatus(ReplicationStatus),
+    /// answer to a message from another node of the cluster
+    Raft(RaftReply),
+    /// `cluster_status` result
+    ClusterStatus(ClusterStatus),
+    /// `proxy_stats` result
+    ProxyStats(ProxyStats),
+    /// changes of the stream started by `watch`, none for a heartbeat
+    Watch(Vec<WatchEvent>),
+    /// `publish` result, the number of subscribers which got the message
+    Receivers(usize),
+    /// messages of the stream started by `subscribe`, none for a heartbeat
+    Messages(Vec<Message>),
+    /// changes of the stream started by `cdc`, none for a heartbeat
+    Cdc(Vec<WatchEvent>),
+}
+
+/// Failure of a request, which maps to a `KvsError` variant on the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    KeyNotFound,
+    AuthRequired,
+    AuthFailed,
+    PermissionDenied(String),
+    UnsupportedVersion {
+        supported: u32,
+    },
+    InvalidRequest(String),
+    /// Any other failure on the server, such as an I/O error of the engine
+    Internal(String),
+    /// Write sent to a replica
+    ReadOnly {
+        leader: String,
+    },
+    /// Request sent to a cluster node which isn't the leader
+    NotLeader {
+        leader: Option<String>,
+    },
+    /// Changes asked for which compaction dropped
+    Compacted {
+        first: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub id: u64,
+    pub result: std::result::Result<Reply, ErrorCode>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ResponseGet {
-    Ok(Option<String>),
-    Err(String),
+impl From<&anyhow::Error> for ErrorCode {
+    fn from(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => ErrorCode::KeyNotFound,
+            Some(KvsError::AuthRequired) => ErrorCode::AuthRequired,
+            Some(KvsError::AuthFailed) => ErrorCode::AuthFailed,
+            Some(KvsError::PermissionDenied(reason)) => {
+                ErrorCode::PermissionDenied(reason.to_owned())
+            }
+            Some(KvsError::UnsupportedVersion(version)) => ErrorCode::UnsupportedVersion {
+                supported: *version,
+            },
+            Some(KvsError::ReadOnly(leader)) => ErrorCode::ReadOnly {
+                leader: leader.to_owned(),
+            },
+            Some(KvsError::NotLeader(leader)) => ErrorCode::NotLeader {
+                leader: leader.to_owned(),
+            },
+            Some(KvsError::Compacted(first)) => ErrorCode::Compacted { first: *first },
+            Some(KvsError::InvalidRequest(reason)) => ErrorCode::InvalidRequest(reason.to_owned()),
+            _ => ErrorCode::Internal(format!("{e}")),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ResponseSet {
-    Ok(()),
-    Err(String),
+impl From<KvsError> for ErrorCode {
+    fn from(e: KvsError) -> Self {
+        (&anyhow::Error::from(e)).into()
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ResponseRemove {
-    Ok(()),
-    Err(String),
+impl From<ErrorCode> for KvsError {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::AuthRequired => KvsError::AuthRequired,
+            ErrorCode::AuthFailed => KvsError::AuthFailed,
+            ErrorCode::PermissionDenied(reason) => KvsError::PermissionDenied(reason),
+            ErrorCode::UnsupportedVersion { supported } => KvsError::UnsupportedVersion(supported),
+            ErrorCode::InvalidRequest(reason) => KvsError::InvalidRequest(reason),
+            ErrorCode::Internal(reason) => KvsError::Server(reason),
+            ErrorCode::ReadOnly { leader } => KvsError::ReadOnly(leader),
+            ErrorCode::NotLeader { leader } => KvsError::NotLeader(leader),
+            ErrorCode::Compacted { first } => KvsError::Compacted(first),
+        }
+    }
 }
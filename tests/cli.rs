@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use predicates::ord::eq;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,7 +158,7 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
@@ -178,7 +179,7 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
@@ -186,7 +187,7 @@ fn cli_wrong_engine() {
         child.kill().expect("server exited before killed");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,7 +198,7 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
@@ -205,7 +206,7 @@ fn cli_wrong_engine() {
         child.kill().expect("server exited before killed");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,7 +218,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -229,7 +230,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +238,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +246,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +254,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +262,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +270,15 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["get", "key1", "key2", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\nKey not found\nvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +286,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +294,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -300,7 +309,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -312,14 +321,14 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -337,3 +346,397 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+#[cfg(unix)]
+fn cli_reload_config_on_sighup() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    let stderr_path = temp_dir.path().join("stderr");
+    fs::write(&config_path, "engine = \"kvs\"\nlog_level = \"info\"\n").unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4006", "--config"])
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    fs::write(
+        &config_path,
+        "engine = \"sled\"\nlog_level = \"debug\"\nslow_log_threshold_ms = 0\n",
+    )
+    .unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // The slow request is logged after the response is sent
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Configuration reloaded"));
+    assert!(content.contains("Reject change of `engine`"));
+    assert!(content.contains("Slow request"));
+    assert!(content.contains("DEBUG"));
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_access_server_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = format!("unix:{}", temp_dir.path().join("kvs.sock").display());
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // the socket of a running server is not taken over
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Address in use"));
+
+    // the one it left behind is
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+
+    // a file which isn't a socket is kept
+    let file = temp_dir.path().join("not-a-socket");
+    fs::write(&file, "data").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr"])
+        .arg(format!("unix:{}", file.display()))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Address in use"));
+    assert_eq!(fs::read_to_string(&file).unwrap(), "data");
+}
+
+#[test]
+fn cli_authentication() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let output = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--hash-secret")
+        .with_stdin()
+        .buffer("secret\n")
+        .output()
+        .unwrap();
+    let hash = String::from_utf8(output.stdout).unwrap();
+    fs::write(
+        temp_dir.path().join("credentials.toml"),
+        format!("[users.alice]\npassword = \"{}\"\n", hash.trim()),
+    )
+    .unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "credentials_file = \"credentials.toml\"\n",
+    )
+    .unwrap();
+    fs::write(temp_dir.path().join("good"), "secret\n").unwrap();
+    fs::write(temp_dir.path().join("bad"), "guess\n").unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Authentication required"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(["--user", "alice", "--password-file", "bad"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Authentication failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(["--user", "alice", "--password-file", "good"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .args(["--user", "alice", "--password-file", "good"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs backup` and `kvs restore` should copy a store offline, to a directory
+// or a tarball
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = temp_dir.path().join("store");
+    fs::create_dir(&store).unwrap();
+    let kvs = |dir: &std::path::Path, args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .assert()
+    };
+    kvs(&store, &["set", "key1", "value1"]).success();
+    kvs(&store, &["set", "key2", "value2"]).success();
+    kvs(&store, &["rm", "key2"]).success();
+
+    for backup in ["backup", "backup.tar"] {
+        let path = temp_dir.path().join(backup);
+        let path = path.to_str().unwrap();
+        kvs(&store, &["backup", path]).success();
+        // never overwritten
+        kvs(&store, &["backup", path]).failure();
+
+        let restored = temp_dir.path().join(format!("{backup}.restored"));
+        fs::create_dir(&restored).unwrap();
+        kvs(&restored, &["restore", path])
+            .success()
+            .stdout(is_empty());
+        kvs(&restored, &["get", "key1"])
+            .success()
+            .stdout(eq("value1\n"));
+        kvs(&restored, &["get", "key2"])
+            .success()
+            .stdout(eq("Key not found\n"));
+        kvs(&restored, &["restore", path])
+            .failure()
+            .stderr(contains("The store is not empty"));
+    }
+
+    // a broken backup is found out before the store is touched
+    let broken = temp_dir.path().join("broken");
+    fs::create_dir(&broken).unwrap();
+    fs::write(broken.join("0.log"), "not a command").unwrap();
+    let empty = temp_dir.path().join("empty");
+    fs::create_dir(&empty).unwrap();
+    fs::write(empty.join("empty.log"), "").unwrap();
+    kvs(&empty, &["restore", broken.to_str().unwrap()]).failure();
+    assert_eq!(
+        fs::read_dir(&empty).unwrap().count(),
+        1,
+        "only the empty log is left"
+    );
+    assert!(empty.join("empty.log").exists());
+
+    // a sled backup goes to the sled store of a data directory
+    let sled = temp_dir.path().join("sled");
+    fs::create_dir(&sled).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--engine", "sled"])
+        .current_dir(&sled)
+        .with_stdin()
+        .buffer("{\"key\":\"key1\",\"value\":\"value1\"}\n")
+        .assert()
+        .success();
+    let data_dir = temp_dir.path().join("data");
+    kvs(
+        temp_dir.path(),
+        &[
+            "restore",
+            sled.to_str().unwrap(),
+            "--data-dir",
+            data_dir.to_str().unwrap(),
+        ],
+    )
+    .success();
+    assert!(!data_dir.join("kvs").exists());
+    kvs(&data_dir.join("sled"), &["export", "--engine", "sled"])
+        .success()
+        .stdout(contains("value1"));
+}
+
+// `kvs export` piped into `kvs import` should migrate a sled store to kvs
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let sled_dir = temp_dir.path().join("sled");
+    let kvs_dir = temp_dir.path().join("kvs");
+    fs::create_dir(&sled_dir).unwrap();
+    fs::create_dir(&kvs_dir).unwrap();
+
+    let csv = "key,value\nuser:1,\"Doe, Jane\"\nuser:2,\"say \"\"hi\"\"\nbye\"\nother,x\n";
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--engine", "sled", "--format", "csv"])
+        .current_dir(&sled_dir)
+        .with_stdin()
+        .buffer(csv)
+        .assert()
+        .success();
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--engine", "sled", "--prefix", "user:"])
+        .current_dir(&sled_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout.clone()).unwrap(),
+        "{\"key\":\"user:1\",\"value\":\"Doe, Jane\"}\n\
+         {\"key\":\"user:2\",\"value\":\"say \\\"hi\\\"\\nbye\"}\n"
+    );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import"])
+        .current_dir(&kvs_dir)
+        .with_stdin()
+        .buffer(output.stdout)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "user:1"])
+        .current_dir(&kvs_dir)
+        .assert()
+        .success()
+        .stdout("Doe, Jane\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "other"])
+        .current_dir(&kvs_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv"])
+        .current_dir(&kvs_dir)
+        .assert()
+        .success()
+        .stdout("key,value\nuser:1,\"Doe, Jane\"\nuser:2,\"say \"\"hi\"\"\nbye\"\n");
+}
+
+// `kvs migrate` should copy a sled data directory to a kvs one, and back
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let sled_store = temp_dir.path().join("old").join("sled");
+    fs::create_dir_all(&sled_store).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--engine", "sled"])
+        .current_dir(&sled_store)
+        .with_stdin()
+        .buffer(
+            "{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n",
+        )
+        .assert()
+        .success();
+
+    let migrate = |from: &str, to: &str, src: &str, dst: &str| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args([
+                "migrate", "--from", from, "--to", to, "--src", src, "--dst", dst,
+            ])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    let output = migrate("sled", "kvs", "old", "new")
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("pairs\t2\nchecksum\t"), "{output}");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(temp_dir.path().join("new").join("kvs"))
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    migrate("sled", "kvs", "old", "new")
+        .failure()
+        .stderr(contains("already holds a kvs store"));
+    migrate("kvs", "sled", "old", "back")
+        .failure()
+        .stderr(contains("No kvs store"));
+    // the checksum doesn't depend on the engine
+    migrate("kvs", "sled", "new", "back")
+        .success()
+        .stdout(output);
+
+    // a failed copy leaves no store behind
+    let db = sled::open(temp_dir.path().join("bad").join("sled")).unwrap();
+    db.insert("key", vec![0xff]).unwrap();
+    db.flush().unwrap();
+    drop(db);
+    migrate("sled", "kvs", "bad", "none").failure();
+    assert!(!temp_dir.path().join("none").exists());
+}
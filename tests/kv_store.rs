@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{Change, KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -76,6 +76,97 @@ fn remove_key() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Should list the live pairs under a prefix, ordered by key
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a/2".to_owned(), "value2".to_owned())?;
+    store.set("a/1".to_owned(), "value1".to_owned())?;
+    store.set("a/3".to_owned(), "value3".to_owned())?;
+    store.set("b/1".to_owned(), "value4".to_owned())?;
+    store.remove("a/3".to_owned())?;
+
+    let expected = vec![
+        ("a/1".to_owned(), "value1".to_owned()),
+        ("a/2".to_owned(), "value2".to_owned()),
+    ];
+    assert_eq!(store.scan("a/".to_owned())?, expected);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("a/".to_owned())?, expected);
+    assert_eq!(store.scan("".to_owned())?.len(), 3);
+    Ok(())
+}
+
+// Should list the pairs under a prefix page by page, on both engines
+#[test]
+fn scan_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn KvsEngine>> = vec![
+        Box::new(KvStore::open(temp_dir.path().join("kvs"))?),
+        Box::new(SledKvsEngine::new(sled::open(
+            temp_dir.path().join("sled"),
+        )?)),
+    ];
+    for mut engine in engines {
+        for i in 0..5 {
+            engine.set(format!("a/{}", i), format!("value{}", i))?;
+        }
+        engine.set("b/1".to_owned(), "value".to_owned())?;
+        engine.remove("a/2".to_owned())?;
+
+        let first = engine.scan_page("a/".to_owned(), None, 2)?;
+        assert_eq!(first.pairs.len(), 2);
+        assert_eq!(first.next, Some("a/1".to_owned()));
+        let second = engine.scan_page("a/".to_owned(), first.next, 2)?;
+        assert_eq!(
+            second.pairs,
+            vec![
+                ("a/3".to_owned(), "value3".to_owned()),
+                ("a/4".to_owned(), "value4".to_owned()),
+            ]
+        );
+        let last = engine.scan_page("a/".to_owned(), second.next, 2)?;
+        assert_eq!((last.pairs.len(), last.next), (0, None));
+
+        // a start before the prefix starts with the prefix
+        let page = engine.scan_page("b/".to_owned(), Some("a/9".to_owned()), 2)?;
+        assert_eq!((page.pairs.len(), page.next), (1, None));
+    }
+    Ok(())
+}
+
+// Should get and set several keys at once, on both engines
+#[test]
+fn mget_and_mset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn KvsEngine>> = vec![
+        Box::new(KvStore::open(temp_dir.path().join("kvs"))?),
+        Box::new(SledKvsEngine::new(sled::open(
+            temp_dir.path().join("sled"),
+        )?)),
+    ];
+    for mut engine in engines {
+        engine.mset(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ])?;
+        assert_eq!(
+            engine.mget(vec![
+                "key2".to_owned(),
+                "key3".to_owned(),
+                "key1".to_owned()
+            ])?,
+            vec![Some("value2".to_owned()), None, Some("value1".to_owned())]
+        );
+    }
     Ok(())
 }
 
@@ -124,3 +215,159 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should number every write, keep the numbers across restarts, and keep the
+// last changes through compactions
+#[test]
+fn change_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.changes(1, 10)?,
+        vec![
+            (
+                2,
+                Change::Set {
+                    key: "key2".to_owned(),
+                    value: "value2".to_owned()
+                }
+            ),
+            (
+                3,
+                Change::Remove {
+                    key: "key1".to_owned()
+                }
+            ),
+        ]
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.changes(0, 2)?.len(), 2);
+    assert_eq!(
+        store.changes(3, 10)?,
+        vec![(
+            4,
+            Change::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned()
+            }
+        )]
+    );
+
+    store.set_cdc_retention(100);
+    store.set_compaction_threshold(10_000);
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("{}", i))?;
+    }
+    // compacted, the first live changes and the last 100 are kept
+    let err = store.changes(3, 10).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(KvsError::Compacted(_))));
+    let changes = store.changes(1004 - 100, 1000)?;
+    assert_eq!(changes.len(), 100);
+    assert_eq!(changes.last().unwrap().0, 1004);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.changes(1004 - 100, 1000)?, changes);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("999".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.changes(1004, 10)?[0].0, 1005);
+
+    let mut sled = SledKvsEngine::new(sled::open(temp_dir.path().join("sled"))?);
+    assert!(sled.changes(0, 10).is_err());
+    Ok(())
+}
+
+// Should back up the data as it was when the snapshot was taken, even if a
+// compaction runs before it is written out, and as it is when written out on
+// sled
+#[test]
+fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<(&str, Box<dyn KvsEngine>)> = vec![
+        ("kvs", Box::new(KvStore::open(temp_dir.path().join("kvs"))?)),
+        (
+            "sled",
+            Box::new(SledKvsEngine::new(sled::open(
+                temp_dir.path().join("sled"),
+            )?)),
+        ),
+    ];
+    for (name, mut engine) in engines {
+        engine.set_compaction_threshold(1000);
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        let snapshot = engine.snapshot()?;
+        for i in 0..100 {
+            engine.set("key1".to_owned(), format!("{}", i))?;
+        }
+        engine.remove("key2".to_owned())?;
+        let path = temp_dir.path().join(format!("{name}-backup"));
+        kvs::write_backup(snapshot, &path)?;
+        engine.backup_to(&temp_dir.path().join(format!("{name}-backup.tar")))?;
+
+        let restored = temp_dir.path().join(format!("{name}-restored"));
+        kvs::restore_backup(&path, &restored)?;
+        let tar_restored = temp_dir.path().join(format!("{name}-tar-restored"));
+        kvs::restore_backup(
+            &temp_dir.path().join(format!("{name}-backup.tar")),
+            &tar_restored,
+        )?;
+        let (mut old, mut new): (Box<dyn KvsEngine>, Box<dyn KvsEngine>) = if name == "kvs" {
+            (
+                Box::new(KvStore::open(&restored)?),
+                Box::new(KvStore::open(&tar_restored)?),
+            )
+        } else {
+            (
+                Box::new(SledKvsEngine::new(sled::open(&restored)?)),
+                Box::new(SledKvsEngine::new(sled::open(&tar_restored)?)),
+            )
+        };
+        if name == "kvs" {
+            assert_eq!(old.get("key1".to_owned())?, Some("value1".to_owned()));
+            assert_eq!(old.get("key2".to_owned())?, Some("value2".to_owned()));
+        } else {
+            // sled cannot freeze its data, it is copied when written out
+            assert_eq!(old.get("key1".to_owned())?, Some("99".to_owned()));
+            assert_eq!(old.get("key2".to_owned())?, None);
+        }
+        assert_eq!(new.get("key1".to_owned())?, Some("99".to_owned()));
+        assert_eq!(new.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Should copy every pair to the other engine, and notice when the copy
+// differs
+#[test]
+fn migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sled = SledKvsEngine::new(sled::open(temp_dir.path().join("sled"))?);
+    let pairs: Vec<(String, String)> = (0..2500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    sled.mset(pairs)?;
+
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    let digest = kvs::migrate(&mut sled, &mut store, 1000)?;
+    assert_eq!(digest.pairs, 2500);
+    assert_eq!(digest, kvs::PairsDigest::of(&mut store)?);
+    assert_eq!(
+        store.get("key2499".to_owned())?,
+        Some("value2499".to_owned())
+    );
+
+    let mut other = KvStore::open(temp_dir.path().join("other"))?;
+    other.set("extra".to_owned(), "x".to_owned())?;
+    let err = kvs::migrate(&mut sled, &mut other, 1000).unwrap_err();
+    assert!(err.to_string().contains("Migration check failed"), "{err}");
+    Ok(())
+}
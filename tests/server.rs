@@ -0,0 +1,1797 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    hash_secret, Acl, Addr, Change, ClusterConfig, Credentials, DropPolicy, Encoding, Feature,
+    KvStore, KvsClient, KvsClientPool, KvsEngine, KvsError, KvsProxy, KvsServer, MapSource,
+    Message, Op, PoolOptions, ProxyOptions, RaftRole, Result, RetryPolicy, RuntimeConfig, Shard,
+    ShardedClient, WatchEvent,
+};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_server(dir: &Path, addr: &str, config: RuntimeConfig) -> Addr {
+    let server = KvsServer::with_config(KvStore::open(dir.join("kvs")).unwrap(), config).unwrap();
+    let addr: Addr = addr.parse().unwrap();
+    let listen = addr.clone();
+    thread::spawn(move || server.run_addr(&listen).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    addr
+}
+
+fn connect(addr: &Addr, token: &str) -> Result<KvsClient> {
+    KvsClient::connect_with_credentials(addr, Credentials::Token(token.to_owned()))
+}
+
+fn is_permission_denied(result: Result<impl std::fmt::Debug>) -> bool {
+    matches!(
+        result.unwrap_err().downcast_ref::<KvsError>(),
+        Some(KvsError::PermissionDenied(_))
+    )
+}
+
+// Each user should only reach the keys its ACL rules allow
+#[test]
+fn acl_on_key_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials_file = temp_dir.path().join("credentials.toml");
+    let acl_file = temp_dir.path().join("acl.toml");
+    fs::write(
+        &credentials_file,
+        format!(
+            "[[tokens]]\nname = \"team-a\"\nhash = \"{}\"\n\n\
+             [[tokens]]\nname = \"team-b\"\nhash = \"{}\"\n",
+            hash_secret("token-a"),
+            hash_secret("token-b"),
+        ),
+    )?;
+    fs::write(
+        &acl_file,
+        "[[rules]]\nuser = \"team-a\"\nops = [\"get\", \"set\", \"remove\", \"scan\"]\nkeys = \"a/*\"\n\n\
+         [[rules]]\nuser = \"*\"\nops = [\"get\", \"scan\"]\nkeys = \"public/*\"\n\n\
+         [[rules]]\nuser = \"team-b\"\nops = [\"set\"]\nkeys = \"public/*\"\n",
+    )?;
+    let config = RuntimeConfig {
+        credentials_file: Some(credentials_file),
+        acl_file: Some(acl_file),
+        ..RuntimeConfig::default()
+    };
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4012", config);
+
+    let mut team_a = connect(&addr, "token-a")?;
+    let mut team_b = connect(&addr, "token-b")?;
+    team_a.set("a/1".to_owned(), "value1".to_owned())?;
+    team_b.set("public/1".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(
+        team_a.get("public/1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert!(is_permission_denied(
+        team_a.set("public/1".to_owned(), "x".to_owned())
+    ));
+    assert!(is_permission_denied(team_b.get("a/1".to_owned())));
+    assert!(is_permission_denied(team_b.remove("a/1".to_owned())));
+    // a batch is denied as a whole if one of its keys is
+    assert!(is_permission_denied(
+        team_b.mget(vec!["public/1".to_owned(), "a/1".to_owned()])
+    ));
+    assert_eq!(
+        team_b.mget(vec!["public/1".to_owned(), "public/2".to_owned()])?,
+        vec![Some("value2".to_owned()), None]
+    );
+
+    // scan only lists the allowed keys
+    assert_eq!(team_a.scan("".to_owned())?.len(), 2);
+    assert_eq!(
+        team_b.scan("".to_owned())?,
+        vec![("public/1".to_owned(), "value2".to_owned())]
+    );
+
+    // a wrong token is not an identity
+    assert!(connect(&addr, "token-c").is_err());
+    Ok(())
+}
+
+// Long keys and patterns with many stars should match quickly, and without
+// running out of stack
+#[test]
+fn acl_globs_on_long_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl_file = temp_dir.path().join("acl.toml");
+    let many_stars = format!("{}b", "*a".repeat(30));
+    fs::write(
+        &acl_file,
+        format!(
+            "[[rules]]\nuser = \"*\"\nops = [\"get\"]\n\n\
+             [[rules]]\nuser = \"*\"\nops = [\"set\"]\nkeys = \"{many_stars}\"\n"
+        ),
+    )?;
+    let acl = Acl::load(&acl_file)?;
+
+    let long_key = "a".repeat(8 << 20);
+    assert!(acl.allows("anyone", Op::Get, &long_key));
+    let start = Instant::now();
+    assert!(!acl.allows("anyone", Op::Set, &long_key[..100_000]));
+    assert!(acl.allows("anyone", Op::Set, &format!("{}b", &long_key[..100_000])));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+// Errors of the server should come back as the matching `KvsError` variant
+#[test]
+fn typed_errors_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4013", RuntimeConfig::default());
+
+    let mut client = KvsClient::connect_addr(&addr)?;
+    assert!(matches!(
+        client
+            .remove("key1".to_owned())
+            .unwrap_err()
+            .downcast_ref::<KvsError>(),
+        Some(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        client
+            .authenticate(Credentials::Token("token".to_owned()))
+            .unwrap_err()
+            .downcast_ref::<KvsError>(),
+        Some(KvsError::InvalidRequest(_))
+    ));
+    // also when the engine or the server refuse it
+    assert!(matches!(
+        client.cluster_status().unwrap_err().downcast_ref::<KvsError>(),
+        Some(KvsError::InvalidRequest(reason)) if reason == "Not a cluster node"
+    ));
+
+    // a request of an unknown protocol version is rejected, not misread
+    let mut stream = TcpStream::connect("127.0.0.1:4013")?;
+    stream.write_all(br#"{"version":99,"id":7,"request":{"Get":{"key":"key1"}}}"#)?;
+    let response = read_response(&stream)?;
+    assert_eq!(
+        response,
+        serde_json::json!({"id": 7, "result": {"Err": {"UnsupportedVersion": {"supported": 3}}}})
+    );
+    // so is a bare request of a client from before the protocol versions
+    let mut stream = TcpStream::connect("127.0.0.1:4013")?;
+    stream.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    let response = read_response(&stream)?;
+    assert_eq!(
+        response,
+        serde_json::json!({"id": 0, "result": {"Err": {"UnsupportedVersion": {"supported": 3}}}})
+    );
+    Ok(())
+}
+
+// A client should fall back to version 1 with a server which has no handshake
+#[test]
+fn client_falls_back_to_version_1() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4050")?;
+    thread::spawn(move || {
+        // a version 1 server refuses the `Hello`
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = read_response(&stream);
+        stream
+            .write_all(br#"{"id":1,"result":{"Err":{"UnsupportedVersion":{"supported":1}}}}"#)
+            .unwrap();
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_response(&stream).unwrap();
+        assert_eq!(
+            request,
+            serde_json::json!({"version": 1, "id": 1, "request": {"Get": {"key": "key1"}}})
+        );
+        stream
+            .write_all(br#"{"id":1,"result":{"Ok":{"Value":"value1"}}}"#)
+            .unwrap();
+    });
+
+    let mut client = KvsClient::connect_addr(&"127.0.0.1:4050".parse()?)?;
+    assert_eq!(client.capabilities().version, 1);
+    assert!(client.capabilities().server.is_none());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a server hanging up on the handshake is no reason to fall back
+    let listener = TcpListener::bind("127.0.0.1:4051")?;
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let _ = read_response(&stream);
+    });
+    let error = KvsClient::connect_addr(&"127.0.0.1:4051".parse()?)
+        .err()
+        .expect("the handshake should fail");
+    assert!(!matches!(
+        error.downcast_ref::<KvsError>(),
+        Some(KvsError::UnsupportedVersion(_))
+    ));
+    Ok(())
+}
+
+fn read_response(stream: &TcpStream) -> Result<serde_json::Value> {
+    Ok(serde_json::Deserializer::from_reader(stream)
+        .into_iter()
+        .next()
+        .unwrap()?)
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<serde_json::Value> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+// Both sides should agree on a protocol version and a set of features
+#[test]
+fn handshake_negotiates_capabilities() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials_file = temp_dir.path().join("credentials.toml");
+    fs::write(
+        &credentials_file,
+        format!(
+            "[[tokens]]\nname = \"ci\"\nhash = \"{}\"\n",
+            hash_secret("token")
+        ),
+    )?;
+    let config = RuntimeConfig {
+        credentials_file: Some(credentials_file),
+        ..RuntimeConfig::default()
+    };
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4014", config);
+
+    let client = connect(&addr, "token")?;
+    let capabilities = client.capabilities();
+    assert_eq!(capabilities.version, 3);
+    assert_eq!(capabilities.encoding, Encoding::Binary);
+    assert!(capabilities.supports(Feature::Scan));
+    assert!(capabilities.supports(Feature::Auth));
+    assert!(!capabilities.supports(Feature::Compression));
+    assert_eq!(capabilities.server.as_ref().unwrap().name, "kvs-server");
+
+    // a newer client is downgraded to the version of the server
+    let mut stream = TcpStream::connect("127.0.0.1:4014")?;
+    stream.write_all(
+        br#"{"version":5,"id":1,"request":{"Hello":{"min_version":1,"max_version":5,"features":["scan","batch"],"encodings":["json"]}}}"#,
+    )?;
+    let response = read_response(&stream)?;
+    assert_eq!(response["result"]["Ok"]["Welcome"]["version"], 3);
+    assert_eq!(
+        response["result"]["Ok"]["Welcome"]["features"],
+        serde_json::json!(["scan", "batch"])
+    );
+    // and gets a clear error for requests it doesn't know
+    write_frame(
+        &mut stream,
+        br#"{"version":3,"id":2,"request":{"Teleport":{}}}"#,
+    )?;
+    let response = read_frame(&mut stream)?;
+    assert_eq!(response["id"], 2);
+    assert!(response["result"]["Err"]["InvalidRequest"].is_string());
+
+    // a client which cannot speak version 3 is rejected
+    let mut stream = TcpStream::connect("127.0.0.1:4014")?;
+    stream.write_all(
+        br#"{"version":4,"id":1,"request":{"Hello":{"min_version":4,"max_version":4,"features":[]}}}"#,
+    )?;
+    let response = read_response(&stream)?;
+    assert_eq!(
+        response["result"]["Err"],
+        serde_json::json!({"UnsupportedVersion": {"supported": 3}})
+    );
+    Ok(())
+}
+
+// Both encodings should work, and a bad frame should not end the connection
+#[test]
+fn length_prefixed_frames() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4015", RuntimeConfig::default());
+
+    for encoding in [Encoding::Json, Encoding::Binary] {
+        let mut client = KvsClient::from_stream_with_encoding(addr.connect()?, encoding)?;
+        assert_eq!(client.capabilities().encoding, encoding);
+        client.set(format!("{encoding:?}"), "value".to_owned())?;
+        assert_eq!(
+            client.get(format!("{encoding:?}"))?,
+            Some("value".to_owned())
+        );
+    }
+
+    let mut stream = TcpStream::connect("127.0.0.1:4015")?;
+    stream.write_all(
+        br#"{"version":3,"id":1,"request":{"Hello":{"min_version":3,"max_version":3,"features":[],"encodings":["json"]}}}"#,
+    )?;
+    read_response(&stream)?;
+    write_frame(&mut stream, b"\x00garbage")?;
+    let response = read_frame(&mut stream)?;
+    assert!(response["result"]["Err"]["InvalidRequest"].is_string());
+    write_frame(
+        &mut stream,
+        br#"{"version":3,"id":3,"request":{"Get":{"key":"Json"}}}"#,
+    )?;
+    assert_eq!(
+        read_frame(&mut stream)?,
+        serde_json::json!({"id": 3, "result": {"Ok": {"Value": "value"}}})
+    );
+    Ok(())
+}
+
+// Pipelined requests should get their own results, in order
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4016", RuntimeConfig::default());
+    let mut client = KvsClient::connect_addr(&addr)?;
+
+    let value = "v".repeat(1_000);
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{i}"), value.clone());
+    }
+    pipeline.remove("missing".to_owned());
+    for i in 0..1000 {
+        pipeline.get(format!("key{i}"));
+    }
+    assert_eq!(pipeline.len(), 2001);
+
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 2001);
+    assert!(results[..1000].iter().all(|r| matches!(r, Ok(None))));
+    assert!(matches!(
+        results[1000]
+            .as_ref()
+            .unwrap_err()
+            .downcast_ref::<KvsError>(),
+        Some(KvsError::KeyNotFound)
+    ));
+    assert!(results[1001..]
+        .iter()
+        .all(|r| r.as_ref().unwrap().as_deref() == Some(value.as_str())));
+
+    // the connection is still usable afterwards
+    assert_eq!(client.get("key0".to_owned())?, Some(value));
+    Ok(())
+}
+
+/// Send one RESP command and read its whole reply as text.
+fn resp_call(stream: &mut BufReader<TcpStream>, args: &[&str]) -> Result<String> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.get_mut().write_all(command.as_bytes())?;
+    let mut reply = String::new();
+    read_resp(stream, &mut reply)?;
+    Ok(reply)
+}
+
+fn read_resp(stream: &mut BufReader<TcpStream>, reply: &mut String) -> Result<()> {
+    let start = reply.len();
+    stream.read_line(reply)?;
+    let line = reply[start..].trim_end().to_owned();
+    let (kind, len) = line.split_at(1);
+    match kind {
+        "$" if len != "-1" => {
+            let mut data = vec![0; len.parse::<usize>()? + 2];
+            stream.read_exact(&mut data)?;
+            reply.push_str(&String::from_utf8(data)?);
+        }
+        "*" | "%" => {
+            let items = len.parse::<usize>()? * if kind == "%" { 2 } else { 1 };
+            for _ in 0..items {
+                read_resp(stream, reply)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Redis clients should reach the same data through the RESP listener
+#[test]
+fn redis_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials_file = temp_dir.path().join("credentials.toml");
+    fs::write(
+        &credentials_file,
+        format!(
+            "[[tokens]]\nname = \"redis\"\nhash = \"{}\"\n",
+            hash_secret("token")
+        ),
+    )?;
+    let config = RuntimeConfig {
+        credentials_file: Some(credentials_file),
+        ..RuntimeConfig::default()
+    };
+    let server = KvsServer::with_config(KvStore::open(temp_dir.path().join("kvs"))?, config)?
+        .with_resp("127.0.0.1:4018".parse::<Addr>()?.bind()?);
+    thread::spawn(move || server.run("127.0.0.1:4017").unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut redis = BufReader::new(TcpStream::connect("127.0.0.1:4018")?);
+    assert!(resp_call(&mut redis, &["GET", "key1"])?.starts_with("-NOAUTH"));
+    assert!(resp_call(&mut redis, &["AUTH", "wrong"])?.starts_with("-WRONGPASS"));
+    assert_eq!(resp_call(&mut redis, &["AUTH", "token"])?, "+OK\r\n");
+
+    assert_eq!(resp_call(&mut redis, &["PING"])?, "+PONG\r\n");
+    assert_eq!(
+        resp_call(&mut redis, &["SET", "key1", "value1"])?,
+        "+OK\r\n"
+    );
+    assert_eq!(resp_call(&mut redis, &["GET", "key1"])?, "$6\r\nvalue1\r\n");
+    assert_eq!(resp_call(&mut redis, &["GET", "key2"])?, "$-1\r\n");
+    assert_eq!(
+        resp_call(&mut redis, &["MSET", "a", "1", "b", "2"])?,
+        "+OK\r\n"
+    );
+    assert_eq!(
+        resp_call(&mut redis, &["MGET", "a", "x", "b"])?,
+        "*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n"
+    );
+    assert_eq!(resp_call(&mut redis, &["EXISTS", "a", "x", "b"])?, ":2\r\n");
+    assert_eq!(resp_call(&mut redis, &["DEL", "a", "x"])?, ":1\r\n");
+    assert_eq!(
+        resp_call(&mut redis, &["SCAN", "0", "MATCH", "b*"])?,
+        "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nb\r\n"
+    );
+    assert!(resp_call(&mut redis, &["INFO"])?.contains("kvs_version:"));
+    assert_eq!(
+        resp_call(&mut redis, &["GET"])?,
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert!(resp_call(&mut redis, &["FLUSHALL"])?.starts_with("-ERR unknown command 'FLUSHALL'"));
+
+    // RESP3 after HELLO 3
+    assert!(resp_call(&mut redis, &["HELLO", "3"])?.starts_with("%6\r\n"));
+    assert_eq!(resp_call(&mut redis, &["GET", "key2"])?, "_\r\n");
+
+    // the native protocol sees the same data
+    let mut client = connect(&"127.0.0.1:4017".parse()?, "token")?;
+    assert_eq!(client.get("b".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+/// Status, headers and body of an HTTP response.
+type HttpResponse = (u16, Vec<(String, String)>, String);
+
+/// Send one HTTP request.
+fn http_call(
+    stream: &mut BufReader<TcpStream>,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<HttpResponse> {
+    let mut request = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n");
+    for (name, value) in headers {
+        request += &format!("{name}: {value}\r\n");
+    }
+    request += &format!("Content-Length: {}\r\n\r\n{body}", body.len());
+    stream.get_mut().write_all(request.as_bytes())?;
+
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    let status = line.split(' ').nth(1).unwrap().parse()?;
+    let mut headers = vec![];
+    let mut len = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line)?;
+        let Some((name, value)) = line.trim_end().split_once(": ") else {
+            break;
+        };
+        if name == "Content-Length" {
+            len = value.parse()?;
+        }
+        headers.push((name.to_owned(), value.to_owned()));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok((status, headers, String::from_utf8(body)?))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_str())
+        .unwrap()
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials_file = temp_dir.path().join("credentials.toml");
+    fs::write(
+        &credentials_file,
+        format!(
+            "[[tokens]]\nname = \"web\"\nhash = \"{}\"\n",
+            hash_secret("token")
+        ),
+    )?;
+    let config = RuntimeConfig {
+        credentials_file: Some(credentials_file),
+        ..RuntimeConfig::default()
+    };
+    let server = KvsServer::with_config(KvStore::open(temp_dir.path().join("kvs"))?, config)?
+        .with_http("127.0.0.1:4020".parse::<Addr>()?.bind()?);
+    thread::spawn(move || server.run("127.0.0.1:4019").unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut http = BufReader::new(TcpStream::connect("127.0.0.1:4020")?);
+    let auth = ("Authorization", "Bearer token");
+    assert_eq!(
+        http_call(&mut http, "GET", "/v1/admin/health", &[], "")?.0,
+        200
+    );
+    let (status, headers, _) = http_call(&mut http, "GET", "/v1/keys/key1", &[], "")?;
+    assert_eq!(status, 401);
+    assert_eq!(header(&headers, "WWW-Authenticate"), "Bearer");
+    let wrong = ("Authorization", "Bearer wrong");
+    assert_eq!(
+        http_call(&mut http, "GET", "/v1/keys/key1", &[wrong], "")?.0,
+        401
+    );
+
+    assert_eq!(
+        http_call(&mut http, "GET", "/v1/keys/key1", &[auth], "")?.0,
+        404
+    );
+    let (status, headers, _) = http_call(&mut http, "PUT", "/v1/keys/key1", &[auth], "value1")?;
+    assert_eq!(status, 204);
+    let etag = header(&headers, "ETag").to_owned();
+    let (status, headers, body) = http_call(&mut http, "GET", "/v1/keys/key1", &[auth], "")?;
+    assert_eq!((status, body.as_str()), (200, "value1"));
+    assert_eq!(header(&headers, "ETag"), etag);
+    let not_modified = [auth, ("If-None-Match", &etag)];
+    assert_eq!(
+        http_call(&mut http, "GET", "/v1/keys/key1", &not_modified, "")?.0,
+        304
+    );
+
+    // conditional writes
+    let create = [auth, ("If-None-Match", "*")];
+    assert_eq!(
+        http_call(&mut http, "PUT", "/v1/keys/key1", &create, "x")?.0,
+        412
+    );
+    let stale = [auth, ("If-Match", "\"stale\"")];
+    assert_eq!(
+        http_call(&mut http, "PUT", "/v1/keys/key1", &stale, "x")?.0,
+        412
+    );
+    let current = [auth, ("If-Match", &etag)];
+    assert_eq!(
+        http_call(&mut http, "PUT", "/v1/keys/key1", &current, "value2")?.0,
+        204
+    );
+    assert_eq!(
+        http_call(&mut http, "PUT", "/v1/keys/key1", &current, "value3")?.0,
+        412
+    );
+    assert_eq!(
+        http_call(&mut http, "PUT", "/v1/keys/new%20key", &create, "v")?.0,
+        204
+    );
+
+    let (status, _, body) = http_call(&mut http, "GET", "/v1/keys?prefix=new+", &[auth], "")?;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body)?,
+        serde_json::json!([{ "key": "new key", "value": "v" }])
+    );
+    assert_eq!(
+        http_call(&mut http, "DELETE", "/v1/keys/new%20key", &[auth], "")?.0,
+        204
+    );
+    assert_eq!(
+        http_call(&mut http, "DELETE", "/v1/keys/new%20key", &[auth], "")?.0,
+        404
+    );
+
+    let (status, _, body) = http_call(&mut http, "GET", "/v1/admin/info", &[auth], "")?;
+    assert_eq!(status, 200);
+    assert!(body.contains("\"kvs-server\""));
+    assert_eq!(
+        http_call(&mut http, "GET", "/v1/admin/config", &[auth], "")?.0,
+        200
+    );
+    assert_eq!(
+        http_call(&mut http, "POST", "/v1/keys/key1", &[auth], "")?.0,
+        405
+    );
+    assert_eq!(http_call(&mut http, "GET", "/v2/keys", &[auth], "")?.0, 404);
+
+    // the native protocol sees the same data
+    let mut client = connect(&"127.0.0.1:4019".parse()?, "token")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Send one memcached command, reading its reply up to a line starting with
+/// `last`.
+fn memcache_call(stream: &mut BufReader<TcpStream>, command: &str, last: &str) -> Result<String> {
+    stream.get_mut().write_all(command.as_bytes())?;
+    let mut reply = String::new();
+    loop {
+        let len = reply.len();
+        if stream.read_line(&mut reply)? == 0 || reply[len..].starts_with(last) {
+            return Ok(reply);
+        }
+    }
+}
+
+/// Send one memcached command with a one-line reply.
+fn memcache_line(stream: &mut BufReader<TcpStream>, command: &str) -> Result<String> {
+    memcache_call(stream, command, "")
+}
+
+#[test]
+fn memcached_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(KvStore::open(temp_dir.path().join("kvs"))?)
+        .with_memcache("127.0.0.1:4022".parse::<Addr>()?.bind()?);
+    thread::spawn(move || server.run("127.0.0.1:4021").unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut mc = BufReader::new(TcpStream::connect("127.0.0.1:4022")?);
+    assert_eq!(
+        memcache_line(&mut mc, "set key1 42 0 6\r\nvalue1\r\n")?,
+        "STORED\r\n"
+    );
+    assert_eq!(
+        memcache_line(&mut mc, "add key1 0 0 1\r\nx\r\n")?,
+        "NOT_STORED\r\n"
+    );
+    assert_eq!(
+        memcache_line(&mut mc, "replace key2 0 0 1\r\nx\r\n")?,
+        "NOT_STORED\r\n"
+    );
+    assert_eq!(
+        memcache_line(&mut mc, "add key2 0 0 1\r\n5\r\n")?,
+        "STORED\r\n"
+    );
+    assert_eq!(memcache_line(&mut mc, "incr key2 10\r\n")?, "15\r\n");
+    assert_eq!(memcache_line(&mut mc, "decr key2 100\r\n")?, "0\r\n");
+    assert!(memcache_line(&mut mc, "incr key1 1\r\n")?.starts_with("CLIENT_ERROR"));
+    assert_eq!(memcache_line(&mut mc, "incr key3 1\r\n")?, "NOT_FOUND\r\n");
+    assert_eq!(memcache_line(&mut mc, "delete key2\r\n")?, "DELETED\r\n");
+    assert_eq!(memcache_line(&mut mc, "delete key2\r\n")?, "NOT_FOUND\r\n");
+    assert_eq!(memcache_line(&mut mc, "bogus\r\n")?, "ERROR\r\n");
+    assert!(memcache_line(&mut mc, "version\r\n")?.starts_with("VERSION "));
+    // replies to noreply commands are skipped
+    assert_eq!(
+        memcache_line(&mut mc, "set key3 0 0 1 noreply\r\na\r\nversion\r\n")?.get(..8),
+        Some("VERSION ")
+    );
+
+    let reply = memcache_call(&mut mc, "get key1 missing key3\r\n", "END")?;
+    assert_eq!(
+        reply,
+        "VALUE key1 42 6\r\nvalue1\r\nVALUE key3 0 1\r\na\r\nEND\r\n"
+    );
+
+    // cas with the unique of gets
+    let reply = memcache_call(&mut mc, "gets key1\r\n", "END")?;
+    let unique = reply
+        .lines()
+        .next()
+        .unwrap()
+        .rsplit(' ')
+        .next()
+        .unwrap()
+        .to_owned();
+    assert_eq!(
+        memcache_line(&mut mc, &format!("cas key1 1 0 6 {unique}\r\nvalue2\r\n"))?,
+        "STORED\r\n"
+    );
+    assert_eq!(
+        memcache_line(&mut mc, &format!("cas key1 1 0 6 {unique}\r\nvalue3\r\n"))?,
+        "EXISTS\r\n"
+    );
+    assert_eq!(
+        memcache_line(&mut mc, "cas key9 0 0 1 1\r\nx\r\n")?,
+        "NOT_FOUND\r\n"
+    );
+
+    // expired items are gone
+    assert_eq!(
+        memcache_line(&mut mc, "set short 0 1 1\r\nx\r\n")?,
+        "STORED\r\n"
+    );
+    assert_eq!(
+        memcache_line(&mut mc, "set gone 0 -1 1\r\nx\r\n")?,
+        "STORED\r\n"
+    );
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(
+        memcache_call(&mut mc, "get short gone\r\n", "END")?,
+        "END\r\n"
+    );
+    // and swept for native clients too
+    let mut client = KvsClient::connect("127.0.0.1:4021")?;
+    wait_until(|| Ok(client.get("short".to_owned())?.is_none()))?;
+    assert_eq!(client.get("gone".to_owned())?, None);
+
+    // native clients see the values, without the metadata
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    let keys: Vec<String> = client
+        .scan(String::new())?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn client_pool() -> Result<()> {
+    fn shared_between_threads<T: Send + Sync + Clone>(_: &T) {}
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4023", RuntimeConfig::default());
+    let options = PoolOptions {
+        min_connections: 1,
+        max_connections: 2,
+        idle_timeout: Some(Duration::from_millis(200)),
+        checkout_timeout: Duration::from_millis(200),
+        health_check: true,
+    };
+    let pool = KvsClientPool::new(&addr, options)?;
+    shared_between_threads(&pool);
+    assert_eq!((pool.connections(), pool.idle_connections()), (1, 1));
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for j in 0..10 {
+                    pool.get()?.set(format!("key{i}-{j}"), "value".to_owned())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(pool.get()?.scan("key".to_owned())?.len(), 40);
+    assert!(pool.connections() <= 2);
+
+    // checkout waits for a connection, up to the timeout
+    let first = pool.get()?;
+    let second = pool.get()?;
+    assert!(matches!(
+        pool.get().err().unwrap().downcast_ref::<KvsError>(),
+        Some(KvsError::PoolTimeout)
+    ));
+    drop(first);
+    second.discard();
+    assert_eq!((pool.connections(), pool.idle_connections()), (1, 1));
+
+    // idle connections above the minimum are closed
+    let (first, second) = (pool.get()?, pool.get()?);
+    drop((first, second));
+    assert_eq!(pool.idle_connections(), 2);
+    thread::sleep(Duration::from_millis(300));
+    pool.get()?;
+    assert_eq!(pool.connections(), 1);
+    Ok(())
+}
+
+// A client over the limit of connections should be told so, not hung up on
+#[test]
+fn max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = RuntimeConfig {
+        max_connections: Some(1),
+        ..RuntimeConfig::default()
+    };
+    let addr = start_server(temp_dir.path(), "127.0.0.1:4054", config);
+
+    let mut first = KvsClient::connect_addr(&addr)?;
+    let error = KvsClient::connect_addr(&addr)
+        .err()
+        .expect("one connection too many");
+    assert!(matches!(
+        error.downcast_ref::<KvsError>(),
+        Some(KvsError::Server(reason)) if reason == "Too many connections"
+    ));
+    first.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(first);
+    wait_until(|| {
+        Ok(KvsClient::connect_addr(&addr)
+            .and_then(|mut client| client.get("key1".to_owned()))
+            .is_ok())
+    })?;
+    Ok(())
+}
+
+#[test]
+fn client_timeouts_and_reconnect() -> Result<()> {
+    // a server which never answers the handshake
+    let silent = TcpListener::bind("127.0.0.1:4024")?;
+    let start = Instant::now();
+    let client = KvsClient::builder(&"127.0.0.1:4024".parse()?)
+        .read_timeout(Duration::from_millis(200))
+        .build();
+    assert!(client.is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(silent);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4025"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut server = start_server();
+    thread::sleep(Duration::from_secs(1));
+    let policy = RetryPolicy {
+        max_retries: 8,
+        initial_backoff: Duration::from_millis(100),
+        ..RetryPolicy::default()
+    };
+    let mut client = KvsClient::builder(&"127.0.0.1:4025".parse()?)
+        .retry_policy(policy)
+        .build()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    server.kill()?;
+    server.wait()?;
+    // `remove` isn't idempotent, so it isn't retried
+    assert!(client.remove("key1".to_owned()).is_err());
+
+    // `get` waits for the server to come back
+    let mut server = start_server();
+    let value = client.get("key1".to_owned());
+    server.kill()?;
+    assert_eq!(value?, Some("value1".to_owned()));
+    Ok(())
+}
+
+/// Poll `check` until it holds, for up to 10 seconds.
+fn wait_until(mut check: impl FnMut() -> Result<bool>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !check()? {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[test]
+fn replication() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    // left over from before, not on the leader, before, between and after
+    // the pages of its snapshot
+    let mut store = KvStore::open(follower_dir.path().join("kvs"))?;
+    for key in ["a-stale", "page1500-stale", "stale"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    drop(store);
+
+    let mut leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4026"])
+        .current_dir(&leader_dir)
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClient::connect("127.0.0.1:4026")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.mset(
+        (0..2500)
+            .map(|i| (format!("page{i:04}"), i.to_string()))
+            .collect(),
+    )?;
+
+    let mut follower = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4027", "--replica-of", "127.0.0.1:4026"])
+        .current_dir(&follower_dir)
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+    let result = (|| -> Result<()> {
+        let mut replica = KvsClient::connect("127.0.0.1:4027")?;
+        // the last stale key goes at the end of the snapshot
+        wait_until(|| Ok(replica.get("stale".to_owned())?.is_none()))?;
+        assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+        for key in ["a-stale", "page1500-stale", "stale"] {
+            assert_eq!(replica.get(key.to_owned())?, None);
+        }
+        assert_eq!(replica.scan("page".to_owned())?.len(), 2500);
+
+        // writes go to the leader only
+        let err = replica
+            .set("key3".to_owned(), "value3".to_owned())
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KvsError::ReadOnly(_))));
+
+        client.set("key3".to_owned(), "value3".to_owned())?;
+        client.remove("key1".to_owned())?;
+        client.mset(vec![("key4".to_owned(), "value4".to_owned())])?;
+        wait_until(|| Ok(replica.get("key4".to_owned())?.is_some()))?;
+        assert_eq!(replica.get("key1".to_owned())?, None);
+        assert_eq!(replica.get("key3".to_owned())?, Some("value3".to_owned()));
+
+        let status = replica.replication_status()?;
+        assert_eq!(status.leader.as_deref(), Some("127.0.0.1:4026"));
+        assert!(status.connected);
+        assert_eq!(status.lag, 0);
+        assert_eq!(status.applied, client.replication_status()?.seq);
+        let status = client.replication_status()?;
+        assert_eq!(status.leader, None);
+        assert_eq!(status.replicas, 1);
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["replication-status", "--addr", "127.0.0.1:4027"])
+            .assert()
+            .success()
+            .stdout(contains("role\treplica").and(contains("lag\t0")));
+        Ok(())
+    })();
+    follower.kill()?;
+    leader.kill()?;
+    result
+}
+
+/// Address of the node of `nodes` which leads them, once they all follow it.
+fn cluster_leader(nodes: &[&str]) -> Result<String> {
+    let mut leader = None;
+    wait_until(|| {
+        let leaders: Vec<Option<String>> = nodes
+            .iter()
+            .map(|node| {
+                let status = KvsClient::connect(node).ok()?.cluster_status().ok()?;
+                status.leader
+            })
+            .collect();
+        leader = leaders[0].clone();
+        Ok(leader
+            .as_ref()
+            .is_some_and(|leader| nodes.contains(&leader.as_str()))
+            && leaders.iter().all(|other| *other == leader))
+    })?;
+    Ok(leader.unwrap())
+}
+
+fn start_cluster_node(dir: &Path, id: &str, members: &[&str]) -> Result<()> {
+    let mut config = ClusterConfig::new(
+        id,
+        members.iter().map(|member| member.to_string()).collect(),
+        dir.join("raft"),
+    );
+    config.snapshot_threshold = 20;
+    let server = KvsServer::new(KvStore::open(dir.join("kvs"))?).cluster(config)?;
+    let listener = TcpListener::bind(id)?;
+    thread::spawn(move || server.run_with(listener));
+    Ok(())
+}
+
+#[test]
+fn raft_cluster() -> Result<()> {
+    let nodes = ["127.0.0.1:4028", "127.0.0.1:4029", "127.0.0.1:4030"];
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    for (node, dir) in nodes.iter().zip(&dirs) {
+        start_cluster_node(dir.path(), node, &nodes)?;
+    }
+    let leader = cluster_leader(&nodes)?;
+    let follower = *nodes.iter().find(|node| **node != leader).unwrap();
+
+    // a follower refuses, and tells where the leader is
+    let err = KvsClient::connect(follower)?
+        .get("key".to_owned())
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(KvsError::NotLeader(Some(id))) if *id == leader),
+        "{err}"
+    );
+
+    // a built client follows the redirects
+    let mut client = KvsClient::builder(&follower.parse()?).build()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(client.compare_and_set(
+        "key".to_owned(),
+        Some("value".to_owned()),
+        "new".to_owned()
+    )?);
+    assert!(matches!(
+        client
+            .remove("missing".to_owned())
+            .unwrap_err()
+            .downcast_ref(),
+        Some(KvsError::KeyNotFound)
+    ));
+    for i in 0..30 {
+        client.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    let status = client.cluster_status()?;
+    assert_eq!(status.id, leader);
+    assert_eq!(status.role, RaftRole::Leader);
+    assert!(status.snapshot_index > 0);
+
+    // the followers apply the committed writes
+    for node in nodes {
+        wait_until(|| Ok(KvsClient::connect(node)?.cluster_status()?.applied >= status.commit))?;
+    }
+
+    // a new node gets a snapshot, since the log was compacted
+    let joining = "127.0.0.1:4031";
+    start_cluster_node(dirs[3].path(), joining, &nodes)?;
+    client.add_member(joining.to_owned())?;
+    assert_eq!(client.cluster_status()?.members.len(), 4);
+    let head = client.cluster_status()?.commit;
+    wait_until(|| {
+        let status = KvsClient::connect(joining)?.cluster_status()?;
+        Ok(status.applied >= head && status.snapshot_index > 0)
+    })?;
+
+    client.remove_member(follower.to_owned())?;
+    let status = client.cluster_status()?;
+    assert_eq!(status.members.len(), 3);
+    assert!(!status.members.iter().any(|member| member == follower));
+    client.set("after".to_owned(), "removal".to_owned())?;
+    assert_eq!(client.get("after".to_owned())?, Some("removal".to_owned()));
+    Ok(())
+}
+
+/// Server process, killed when dropped even if the test panics.
+struct ServerProcess(std::process::Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn raft_failover() -> Result<()> {
+    let nodes = ["127.0.0.1:4032", "127.0.0.1:4033", "127.0.0.1:4034"];
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Option<ServerProcess>> = nodes
+        .iter()
+        .zip(&dirs)
+        .map(|(node, dir)| {
+            let child = Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", node, "--cluster", &nodes.join(",")])
+                .current_dir(dir)
+                .spawn()
+                .unwrap();
+            Some(ServerProcess(child))
+        })
+        .collect();
+    let leader = cluster_leader(&nodes)?;
+    let mut client = KvsClient::builder(&leader.parse()?).build()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let position = nodes.iter().position(|node| *node == leader).unwrap();
+    servers[position].take();
+    let survivors: Vec<&str> = nodes.into_iter().filter(|node| *node != leader).collect();
+    let new_leader = cluster_leader(&survivors)?;
+
+    let mut client = KvsClient::builder(&survivors[0].parse()?).build()?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.set("key".to_owned(), "other".to_owned())?;
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cluster", "status", "--addr", &new_leader])
+        .assert()
+        .success()
+        .stdout(contains("role\tLeader").and(contains(nodes.join(","))));
+
+    // alone, the leader steps down instead of answering stale reads
+    let follower = survivors.iter().find(|node| **node != new_leader).unwrap();
+    let position = nodes.iter().position(|node| node == follower).unwrap();
+    servers[position].take();
+    let mut client = KvsClient::builder(&new_leader.parse()?).build()?;
+    wait_until(|| Ok(client.get("key".to_owned()).is_err()))?;
+    assert_ne!(client.cluster_status()?.role, RaftRole::Leader);
+    Ok(())
+}
+
+#[test]
+fn sharding() -> Result<()> {
+    let nodes = ["127.0.0.1:4035", "127.0.0.1:4036", "127.0.0.1:4037"];
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    for (node, dir) in nodes.iter().zip(&dirs) {
+        start_server(dir.path(), node, RuntimeConfig::default());
+    }
+    let source = MapSource::Node(nodes[0].parse()?);
+    let two: Vec<String> = nodes[..2].iter().map(|node| node.to_string()).collect();
+    let mut client = ShardedClient::open_or_create(source.clone(), two.clone(), None)?
+        .with_refresh_interval(Duration::from_millis(100));
+    assert_eq!(client.map().nodes(), &two[..]);
+    for i in 0..100 {
+        client.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.scan("key".to_owned())?.len(), 99);
+
+    // each key lives on its owner only
+    let keys_on = |node: &str| -> Result<Vec<String>> {
+        let pairs = KvsClient::connect(node)?.scan("key".to_owned())?;
+        Ok(pairs.into_iter().map(|(key, _)| key).collect())
+    };
+    for node in &nodes[..2] {
+        let keys = keys_on(node)?;
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|key| client.map().owner(key) == *node));
+    }
+
+    // another client keeps writing while the keys move
+    let writer = thread::spawn(move || -> Result<usize> {
+        let mut client =
+            ShardedClient::open(source)?.with_refresh_interval(Duration::from_millis(100));
+        let start = Instant::now();
+        let mut written = 0;
+        while start.elapsed() < Duration::from_secs(2) {
+            client.set(format!("key{written}"), format!("other{written}"))?;
+            written += 1;
+        }
+        Ok(written)
+    });
+    thread::sleep(Duration::from_millis(200));
+    let three: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+    let moved = client.reshard(three.clone())?;
+    assert!(moved > 0);
+    let written = writer.join().unwrap()?;
+    assert!(written > 0);
+
+    assert_eq!(client.map().nodes(), &three[..]);
+    assert_eq!(client.map().previous(), None);
+    for i in 1..written.max(100) {
+        let expected = if i < written {
+            format!("other{i}")
+        } else {
+            format!("value{i}")
+        };
+        assert_eq!(client.get(format!("key{i}"))?, Some(expected));
+    }
+    for node in &nodes {
+        let keys = keys_on(node)?;
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|key| client.map().owner(key) == *node));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["reshard", &two.join(","), "--map-node", nodes[0]])
+        .assert()
+        .success()
+        .stdout(contains("moved\t"));
+    let mut client = ShardedClient::open(MapSource::Node(nodes[0].parse()?))?;
+    assert_eq!(client.map().nodes(), &two[..]);
+    assert_eq!(keys_on(nodes[2])?, Vec::<String>::new());
+    assert_eq!(client.get("key1".to_owned())?, Some("other1".to_owned()));
+    Ok(())
+}
+
+// A key written without a pause should not keep a resharding busy forever
+#[test]
+fn reshard_with_hot_key() -> Result<()> {
+    let nodes = ["127.0.0.1:4056", "127.0.0.1:4057"];
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    for (node, dir) in nodes.iter().zip(&dirs) {
+        start_server(dir.path(), node, RuntimeConfig::default());
+    }
+    let source = MapSource::Node(nodes[0].parse()?);
+    let one = vec![nodes[0].to_owned()];
+    let two: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+    let mut client = ShardedClient::open_or_create(source.clone(), one, None)?
+        .with_refresh_interval(Duration::from_millis(100));
+    // hot keys on both sides of the ring, so that one of them moves
+    let keys: Vec<String> = (0..8).map(|i| format!("hot{i}")).collect();
+    for key in &keys {
+        client.set(key.clone(), "0".to_owned())?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (source, keys, stop) = (source.clone(), keys.clone(), stop.clone());
+        thread::spawn(move || -> Result<u64> {
+            let mut client =
+                ShardedClient::open(source)?.with_refresh_interval(Duration::from_millis(100));
+            let mut written = 0;
+            while !stop.load(Ordering::SeqCst) {
+                written += 1;
+                for key in &keys {
+                    client.set(key.clone(), written.to_string())?;
+                }
+            }
+            Ok(written)
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    // it either keeps up with the writes or gives up, but it returns
+    let result = client.reshard(two.clone());
+    stop.store(true, Ordering::SeqCst);
+    let written = writer.join().unwrap()?;
+    if let Err(e) = result {
+        assert!(e.to_string().contains("kept changing"), "{e}");
+        client.reshard(two.clone())?;
+    }
+
+    assert_eq!(client.map().nodes(), &two[..]);
+    assert_eq!(client.map().previous(), None);
+    for key in &keys {
+        assert_eq!(client.get(key.clone())?, Some(written.to_string()));
+    }
+    Ok(())
+}
+
+#[test]
+fn proxy() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    start_server(dirs[0].path(), "127.0.0.1:4039", RuntimeConfig::default());
+    start_server(dirs[1].path(), "127.0.0.1:4040", RuntimeConfig::default());
+    let replica = KvsServer::new(KvStore::open(dirs[2].path().join("kvs"))?)
+        .replica_of(KvsClient::builder(&"127.0.0.1:4040".parse()?));
+    thread::spawn(move || replica.run("127.0.0.1:4041").unwrap());
+    // 4042 is a replica which is down
+    let _proxy = ServerProcess(
+        Command::cargo_bin("kvs-proxy")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4038", "--shard", "127.0.0.1:4039"])
+            .args(["--shard", "127.0.0.1:4040,127.0.0.1:4041,127.0.0.1:4042"])
+            .args(["--read-from-replicas", "--health-check-interval", "100"])
+            .spawn()?,
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4038")?;
+    assert_eq!(
+        client.capabilities().server.as_ref().unwrap().name,
+        "kvs-proxy"
+    );
+    for i in 0..20 {
+        client.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    client.mset(vec![
+        ("key20".to_owned(), "value20".to_owned()),
+        ("key21".to_owned(), "value21".to_owned()),
+    ])?;
+    client.remove("key21".to_owned())?;
+    assert!(client.compare_and_set(
+        "key0".to_owned(),
+        Some("value0".to_owned()),
+        "new".to_owned()
+    )?);
+    assert!(!client.compare_and_set("key0".to_owned(), None, "other".to_owned())?);
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(KvsError::KeyNotFound)));
+
+    // every key is on one primary
+    let on_first = KvsClient::connect("127.0.0.1:4039")?.scan("key".to_owned())?;
+    let on_second = KvsClient::connect("127.0.0.1:4040")?.scan("key".to_owned())?;
+    assert!(!on_first.is_empty() && !on_second.is_empty());
+    assert_eq!(on_first.len() + on_second.len(), 21);
+    let mut replica = KvsClient::connect("127.0.0.1:4041")?;
+    wait_until(|| Ok(replica.scan("key".to_owned())?.len() == on_second.len()))?;
+
+    // the reads of the second shard go to its replica
+    let (key, value) = &on_second[0];
+    assert_eq!(client.get(key.to_owned())?, Some(value.to_owned()));
+    let keys: Vec<String> = (0..22).map(|i| format!("key{i}")).collect();
+    let values = client.mget(keys)?;
+    assert_eq!(values[0], Some("new".to_owned()));
+    assert_eq!(values[1], Some("value1".to_owned()));
+    assert_eq!(values[21], None);
+    let pairs = client.scan("key".to_owned())?;
+    assert_eq!(pairs.len(), 21);
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    let stats = client.proxy_stats()?;
+    assert_eq!(stats.connections, 1);
+    assert!(stats.errors >= 1);
+    assert_eq!(stats.backends.len(), 4);
+    let backend = |addr: &str| stats.backends.iter().find(|b| b.addr == addr).unwrap();
+    assert!(backend("127.0.0.1:4041").replica && backend("127.0.0.1:4041").healthy);
+    assert!(backend("127.0.0.1:4041").requests >= 3);
+    assert!(!backend("127.0.0.1:4042").healthy);
+    assert_eq!(backend("127.0.0.1:4040").shard, 1);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["proxy-stats", "--addr", "127.0.0.1:4038"])
+        .assert()
+        .success()
+        .stdout(contains(
+            "backend\t127.0.0.1:4042\tshard=1\trole=replica\thealthy=false",
+        ));
+    Ok(())
+}
+
+// The proxy authenticates its clients and applies their ACL, the servers
+// behind it only know the proxy
+#[test]
+fn proxy_authenticates_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server_credentials = temp_dir.path().join("server.toml");
+    fs::write(
+        &server_credentials,
+        format!(
+            "[[tokens]]\nname = \"proxy\"\nhash = \"{}\"\n",
+            hash_secret("token-proxy")
+        ),
+    )?;
+    let config = RuntimeConfig {
+        credentials_file: Some(server_credentials),
+        ..RuntimeConfig::default()
+    };
+    start_server(temp_dir.path(), "127.0.0.1:4052", config);
+
+    let credentials_file = temp_dir.path().join("credentials.toml");
+    let acl_file = temp_dir.path().join("acl.toml");
+    fs::write(
+        &credentials_file,
+        format!(
+            "[[tokens]]\nname = \"team-a\"\nhash = \"{}\"\n\n\
+             [[tokens]]\nname = \"team-b\"\nhash = \"{}\"\n",
+            hash_secret("token-a"),
+            hash_secret("token-b"),
+        ),
+    )?;
+    fs::write(
+        &acl_file,
+        "[[rules]]\nuser = \"team-a\"\nops = [\"get\", \"set\", \"scan\"]\nkeys = \"a/*\"\n\n\
+         [[rules]]\nuser = \"team-b\"\nops = [\"set\", \"scan\"]\nkeys = \"b/*\"\n",
+    )?;
+    let shards = vec![Shard {
+        primary: "127.0.0.1:4052".parse()?,
+        replicas: vec![],
+    }];
+    let template = KvsClient::builder(&"127.0.0.1:4052".parse()?)
+        .credentials(Credentials::Token("token-proxy".to_owned()));
+    // the credentials of the proxy are not for anonymous clients
+    assert!(KvsProxy::with_template(
+        shards.clone(),
+        ProxyOptions::default(),
+        Some(template.clone())
+    )
+    .is_err());
+    let options = ProxyOptions {
+        credentials_file: Some(credentials_file),
+        acl_file: Some(acl_file),
+        ..ProxyOptions::default()
+    };
+    let proxy = KvsProxy::with_template(shards, options, Some(template))?;
+    thread::spawn(move || proxy.run("127.0.0.1:4053").unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let addr: Addr = "127.0.0.1:4053".parse()?;
+    let mut anonymous = KvsClient::connect_addr(&addr)?;
+    assert!(matches!(
+        anonymous.get("a/1".to_owned()).unwrap_err().downcast_ref(),
+        Some(KvsError::AuthRequired)
+    ));
+    assert!(connect(&addr, "token-c").is_err());
+
+    let mut team_a = connect(&addr, "token-a")?;
+    let mut team_b = connect(&addr, "token-b")?;
+    team_a.set("a/1".to_owned(), "value1".to_owned())?;
+    team_b.set("b/1".to_owned(), "value2".to_owned())?;
+    assert_eq!(team_a.get("a/1".to_owned())?, Some("value1".to_owned()));
+    assert!(is_permission_denied(team_b.get("a/1".to_owned())));
+    assert!(is_permission_denied(
+        team_a.set("b/1".to_owned(), "x".to_owned())
+    ));
+    assert_eq!(
+        team_b.scan("".to_owned())?,
+        vec![("b/1".to_owned(), "value2".to_owned())]
+    );
+    Ok(())
+}
+
+#[test]
+fn watch() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(dir.path(), "127.0.0.1:4043", RuntimeConfig::default());
+    let mut client = KvsClient::connect_addr(&addr)?;
+    client.set("config/old".to_owned(), "before".to_owned())?;
+
+    let mut events = KvsClient::connect_addr(&addr)?.watch("config/".to_owned())?;
+    // the watch starts once the server read the request
+    thread::sleep(Duration::from_millis(200));
+    client.set("config/a".to_owned(), "1".to_owned())?;
+    client.set("other".to_owned(), "ignored".to_owned())?;
+    client.mset(vec![
+        ("config/b".to_owned(), "2".to_owned()),
+        ("config/c".to_owned(), "3".to_owned()),
+    ])?;
+    client.remove("config/a".to_owned())?;
+    assert!(!client.compare_and_set("config/b".to_owned(), None, "x".to_owned())?);
+    assert!(client.compare_and_set("config/b".to_owned(), Some("2".to_owned()), "4".to_owned())?);
+
+    let events: Vec<WatchEvent> = events.by_ref().take(5).collect::<Result<_>>()?;
+    let changes: Vec<Change> = events.iter().map(|event| event.change.clone()).collect();
+    let set = |key: &str, value: &str| Change::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    assert_eq!(
+        changes,
+        vec![
+            set("config/a", "1"),
+            set("config/b", "2"),
+            set("config/c", "3"),
+            Change::Remove {
+                key: "config/a".to_owned()
+            },
+            set("config/b", "4"),
+        ]
+    );
+    assert!(events.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+    // `other` took a sequence number
+    assert_eq!(events[1].seq, events[0].seq + 2);
+
+    let mut watcher = ServerProcess(
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["watch", "config/", "--addr", "127.0.0.1:4043"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()?,
+    );
+    thread::sleep(Duration::from_millis(500));
+    client.set("config/d".to_owned(), "5".to_owned())?;
+    client.remove("config/d".to_owned())?;
+    let mut output = BufReader::new(watcher.0.stdout.take().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line)?;
+    assert!(line.ends_with("\tset\tconfig/d\t5\n"), "{line:?}");
+    line.clear();
+    output.read_line(&mut line)?;
+    assert!(line.ends_with("\tremove\tconfig/d\n"), "{line:?}");
+    Ok(())
+}
+
+#[test]
+fn pubsub() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = RuntimeConfig {
+        pubsub_buffer: 4,
+        pubsub_drop: DropPolicy::DropOldest,
+        ..RuntimeConfig::default()
+    };
+    let server = KvsServer::with_config(KvStore::open(dir.path().join("kvs"))?, config)?
+        .with_resp("127.0.0.1:4045".parse::<Addr>()?.bind()?);
+    thread::spawn(move || server.run("127.0.0.1:4044").unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut news = KvsClient::connect("127.0.0.1:4044")?.subscribe(vec!["news".to_owned()])?;
+    let mut all_news =
+        KvsClient::connect("127.0.0.1:4044")?.psubscribe(vec!["news*".to_owned()])?;
+    let mut redis = BufReader::new(TcpStream::connect("127.0.0.1:4045")?);
+    assert_eq!(
+        resp_call(&mut redis, &["SUBSCRIBE", "news"])?,
+        "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+    );
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect("127.0.0.1:4044")?;
+    assert_eq!(client.publish("news".to_owned(), "hello".to_owned())?, 3);
+    assert_eq!(
+        client.publish("news.sport".to_owned(), "goal".to_owned())?,
+        1
+    );
+    assert_eq!(client.publish("other".to_owned(), "nobody".to_owned())?, 0);
+    // messages are not stored
+    assert_eq!(client.scan(String::new())?, vec![]);
+
+    let message = news.next().unwrap()?;
+    assert_eq!(
+        (&message.channel[..], &message.message[..]),
+        ("news", "hello")
+    );
+    assert_eq!(message.pattern, None);
+    let messages: Vec<Message> = all_news.by_ref().take(2).collect::<Result<_>>()?;
+    assert_eq!(messages[0].message, "hello");
+    assert_eq!(messages[1].channel, "news.sport");
+    assert_eq!(messages[1].pattern.as_deref(), Some("news*"));
+    let mut reply = String::new();
+    read_resp(&mut redis, &mut reply)?;
+    assert_eq!(
+        reply,
+        "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n"
+    );
+
+    // a subscriber which doesn't read loses messages once the socket and its
+    // buffer are full, and learns how many
+    let big = "x".repeat(100_000);
+    for i in 0..300 {
+        client.publish("news".to_owned(), format!("{i}{big}"))?;
+    }
+    client.publish("news".to_owned(), "last".to_owned())?;
+    let mut received = 0;
+    let mut dropped = 0;
+    for message in news.by_ref() {
+        let message = message?;
+        received += 1;
+        dropped += message.dropped;
+        if message.message == "last" {
+            break;
+        }
+    }
+    assert!(dropped > 0);
+    assert_eq!(received + dropped, 301);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["publish", "news", "cli", "--addr", "127.0.0.1:4044"])
+        .assert()
+        .success()
+        .stdout("3\n");
+    Ok(())
+}
+
+#[test]
+fn cdc() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = RuntimeConfig {
+        compaction_threshold: Some(4000),
+        cdc_retention: Some(10),
+        ..RuntimeConfig::default()
+    };
+    let addr = start_server(dir.path(), "127.0.0.1:4046", config);
+    let mut client = KvsClient::connect_addr(&addr)?;
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.set("b".to_owned(), "2".to_owned())?;
+    client.remove("a".to_owned())?;
+
+    let set = |seq, key: &str, value: &str| WatchEvent {
+        seq,
+        change: Change::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        },
+    };
+    let mut events = KvsClient::connect_addr(&addr)?.cdc(0)?;
+    let past: Vec<WatchEvent> = events.by_ref().take(3).collect::<Result<_>>()?;
+    assert_eq!(
+        past,
+        vec![
+            set(1, "a", "1"),
+            set(2, "b", "2"),
+            WatchEvent {
+                seq: 3,
+                change: Change::Remove {
+                    key: "a".to_owned()
+                }
+            },
+        ]
+    );
+    client.set("c".to_owned(), "3".to_owned())?;
+    assert_eq!(events.next().unwrap()?, set(4, "c", "3"));
+
+    // resume after the last change seen
+    let mut resumed = KvsClient::connect_addr(&addr)?.cdc(2)?;
+    assert_eq!(resumed.next().unwrap()?.seq, 3);
+    assert_eq!(resumed.next().unwrap()?, set(4, "c", "3"));
+
+    let mut tail = ServerProcess(
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cdc", "--from", "1", "--addr", "127.0.0.1:4046"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()?,
+    );
+    let mut output = BufReader::new(tail.0.stdout.take().unwrap());
+    let lines: Vec<String> = output
+        .by_ref()
+        .lines()
+        .take(3)
+        .collect::<std::io::Result<_>>()?;
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"key":"b","op":"set","seq":2,"value":"2"}"#,
+            r#"{"key":"a","op":"remove","seq":3}"#,
+            r#"{"key":"c","op":"set","seq":4,"value":"3"}"#,
+        ]
+    );
+    drop(tail);
+
+    // compactions keep the last 10 changes only
+    for i in 0..200 {
+        client.set("counter".to_owned(), i.to_string())?;
+    }
+    let mut old = KvsClient::connect_addr(&addr)?.cdc(4)?;
+    match old.next().unwrap() {
+        Err(e) => assert!(matches!(e.downcast_ref(), Some(KvsError::Compacted(_)))),
+        Ok(event) => panic!("Unexpected {event:?}"),
+    }
+    let mut recent = KvsClient::connect_addr(&addr)?.cdc(200)?;
+    assert_eq!(recent.next().unwrap()?, set(201, "counter", "196"));
+    Ok(())
+}
+
+#[test]
+fn online_backup() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let credentials_file = dir.path().join("credentials.toml");
+    let acl_file = dir.path().join("acl.toml");
+    let token_file = dir.path().join("token");
+    fs::write(
+        &credentials_file,
+        format!(
+            "[[tokens]]\nname = \"admin\"\nhash = \"{}\"\n\n\
+             [[tokens]]\nname = \"app\"\nhash = \"{}\"\n",
+            hash_secret("token-admin"),
+            hash_secret("token-app"),
+        ),
+    )?;
+    fs::write(
+        &acl_file,
+        "[[rules]]\nuser = \"admin\"\nops = [\"admin\"]\nkeys = \"*\"\n\n\
+         [[rules]]\nuser = \"*\"\nops = [\"get\", \"set\", \"scan\"]\nkeys = \"*\"\n",
+    )?;
+    fs::write(&token_file, "token-admin")?;
+    let backup_dir = dir.path().join("backups");
+    let config = RuntimeConfig {
+        credentials_file: Some(credentials_file),
+        acl_file: Some(acl_file),
+        backup_dir: Some(backup_dir.clone()),
+        ..RuntimeConfig::default()
+    };
+    let addr = start_server(dir.path(), "127.0.0.1:4047", config);
+    let mut client = connect(&addr, "token-admin")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    // only an admin backs up, and only within the backup directory
+    assert!(matches!(
+        KvsClient::connect_addr(&addr)?
+            .backup("backup".to_owned())
+            .unwrap_err()
+            .downcast_ref::<KvsError>(),
+        Some(KvsError::AuthRequired)
+    ));
+    assert!(is_permission_denied(
+        connect(&addr, "token-app")?.backup("backup".to_owned())
+    ));
+    let outside = dir.path().join("outside");
+    for path in [outside.to_str().unwrap(), "../outside", "a/../../outside"] {
+        let err = client.backup(path.to_owned()).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<KvsError>(),
+                Some(KvsError::InvalidRequest(_))
+            ),
+            "{err}"
+        );
+    }
+    assert!(!outside.exists());
+
+    // writes go on during the backups
+    let writer = {
+        let addr = addr.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut client = connect(&addr, "token-app")?;
+            for i in 0..500 {
+                client.set(format!("other{}", i), i.to_string())?;
+            }
+            Ok(())
+        })
+    };
+    client.backup("backup".to_owned())?;
+    let err = client.backup("backup".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("is not empty"), "{err}");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "backup",
+            "backup.tar",
+            "--addr",
+            "127.0.0.1:4047",
+            "--token-file",
+        ])
+        .arg(&token_file)
+        .assert()
+        .success();
+    writer.join().unwrap()?;
+
+    let restored = dir.path().join("restored");
+    kvs::restore_backup(&backup_dir.join("backup.tar"), &restored)?;
+    for backup in [backup_dir.join("backup"), restored] {
+        let mut store = KvStore::open(backup)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        // a prefix of the writes made meanwhile
+        let others = store.scan("other".to_owned())?.len();
+        for i in 0..others {
+            assert_eq!(store.get(format!("other{}", i))?, Some(i.to_string()));
+        }
+    }
+
+    // a server without authentication makes no backups
+    let open_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = RuntimeConfig {
+        backup_dir: Some(open_dir.path().join("backups")),
+        ..RuntimeConfig::default()
+    };
+    let addr = start_server(open_dir.path(), "127.0.0.1:4055", config);
+    assert!(is_permission_denied(
+        KvsClient::connect_addr(&addr)?.backup("backup".to_owned())
+    ));
+    assert!(!open_dir.path().join("backups").exists());
+    Ok(())
+}
+
+#[test]
+fn export_import() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(dir.path(), "127.0.0.1:4048", RuntimeConfig::default());
+    let mut client = KvsClient::connect_addr(&addr)?;
+    let pairs: Vec<(String, String)> = (0..25)
+        .map(|i| (format!("a/{:02}", i), format!("value,{}", i)))
+        .chain([("b/1".to_owned(), "x".to_owned())])
+        .collect();
+    client.mset(pairs.clone())?;
+
+    let mut export = Vec::new();
+    let count = kvs::export_pairs(
+        &["a/".to_owned(), "a/1".to_owned()],
+        // pages smaller than the store
+        |prefix, start_after| client.scan_page(prefix, start_after, 7),
+        kvs::DumpFormat::Csv,
+        &mut export,
+    )?;
+    assert_eq!(count, 25);
+
+    let other = TempDir::new().expect("unable to create temporary working directory");
+    let other_addr = start_server(other.path(), "127.0.0.1:4049", RuntimeConfig::default());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "import",
+            "--format",
+            "csv",
+            "--batch-size",
+            "10",
+            "--addr",
+            "127.0.0.1:4049",
+        ])
+        .with_stdin()
+        .buffer(export)
+        .assert()
+        .success()
+        .stderr(contains("Imported 25 pairs"));
+    let mut other_client = KvsClient::connect_addr(&other_addr)?;
+    assert_eq!(other_client.scan(String::new())?, pairs[..25]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["export", "--prefix", "b/", "--addr", "127.0.0.1:4048"])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"b/1\",\"value\":\"x\"}\n");
+    Ok(())
+}
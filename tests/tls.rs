@@ -0,0 +1,100 @@
+use kvs::{Addr, KvStore, KvsClient, KvsServer, Result, TlsOptions};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Write a CA, a server certificate and a client certificate signed by the CA
+// into `dir`, and return (server options, client options).
+fn generate_certificates(dir: &Path) -> (TlsOptions, TlsOptions) {
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "kvs test ca");
+    let ca = Certificate::from_params(ca_params).unwrap();
+
+    let mut server_params = CertificateParams::new(vec!["localhost".to_owned()]);
+    server_params
+        .subject_alt_names
+        .push(SanType::IpAddress("127.0.0.1".parse().unwrap()));
+    let server = Certificate::from_params(server_params).unwrap();
+
+    let mut client_params = CertificateParams::new(vec![]);
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "alice");
+    let client = Certificate::from_params(client_params).unwrap();
+
+    let write = |name: &str, content: String| {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        Some(path)
+    };
+    let ca_path = write("ca.pem", ca.serialize_pem().unwrap());
+    let server_options = TlsOptions {
+        cert: write("server.pem", server.serialize_pem_with_signer(&ca).unwrap()),
+        key: write("server.key", server.serialize_private_key_pem()),
+        ca: ca_path.clone(),
+        server_name: None,
+    };
+    let client_options = TlsOptions {
+        cert: write("client.pem", client.serialize_pem_with_signer(&ca).unwrap()),
+        key: write("client.key", client.serialize_private_key_pem()),
+        ca: ca_path,
+        server_name: None,
+    };
+    (server_options, client_options)
+}
+
+fn start_server(dir: &Path, addr: &str, tls: TlsOptions) {
+    let server = KvsServer::new(KvStore::open(dir).unwrap()).with_tls(tls.server_config().unwrap());
+    let addr = addr.to_owned();
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// A client verifying the server certificate should be able to talk over TLS
+#[test]
+fn tls_set_and_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server_tls, mut client_tls) = generate_certificates(temp_dir.path());
+    server_tls.ca = None;
+    client_tls.cert = None;
+    client_tls.key = None;
+    start_server(temp_dir.path(), "127.0.0.1:4010", server_tls);
+
+    let addr: Addr = "127.0.0.1:4010".parse()?;
+    let mut client = KvsClient::connect_tls(&addr, &client_tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A plaintext client cannot even complete the handshake with a TLS server
+    assert!(KvsClient::connect_addr(&addr).is_err());
+    Ok(())
+}
+
+// With mutual TLS, only clients presenting a certificate signed by the CA get through
+#[test]
+fn mutual_tls_requires_client_certificate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (server_tls, client_tls) = generate_certificates(temp_dir.path());
+    start_server(temp_dir.path(), "127.0.0.1:4011", server_tls);
+
+    let addr: Addr = "127.0.0.1:4011".parse()?;
+    let mut client = KvsClient::connect_tls(&addr, &client_tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let anonymous = TlsOptions {
+        cert: None,
+        key: None,
+        ..client_tls
+    };
+    let rejected = KvsClient::connect_tls(&addr, &anonymous)
+        .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(rejected.is_err());
+    Ok(())
+}